@@ -0,0 +1,372 @@
+//! Web bridge: serves the content of a ticket through a web link without downloading it
+//! first. Every request fetches the file, or the part of it the browser asks for, from the
+//! peer behind the ticket and streams it to the browser as it arrives, verified chunk by
+//! chunk. Folders are served as a gallery, like web links of folders. Only the names of
+//! the files are kept in the store.
+
+use super::gallery::{Gallery, GalleryEntry};
+use super::history::HistoryFile;
+use super::limit::{self, RateLimiter};
+use super::mime;
+use super::node::Node;
+use super::p2p;
+use super::sources;
+use super::state::{SendHandle, SendStatus};
+use super::store::ShareTag;
+use super::web::{self, ByteRange, DownloadQuery, WebHistory};
+use anyhow::{bail, ensure};
+use axum::{
+    body::Body,
+    extract::{Path as AxumPath, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use bao_tree::{io::BaoContentItem, ChunkNum};
+use bytes::Bytes;
+use futures::SinkExt;
+use iroh::{endpoint::Connection, EndpointAddr};
+use iroh_blobs::{
+    format::collection::Collection,
+    get::fsm,
+    protocol::{ChunkRanges, GetRequest, ALPN as BlobsAlpn},
+    BlobFormat, Hash,
+};
+use n0_future::StreamExt;
+use ngrok::tunnel::EndpointInfo;
+use std::{collections::HashMap, io, path::PathBuf, sync::Arc};
+use tokio::{runtime::Handle as TokioHandle, sync::mpsc};
+
+/// State for the Axum web server of a bridge.
+#[derive(Clone)]
+struct BridgeState {
+    node: Node,
+    /// The peer behind the ticket, asked for the file on every request.
+    peer: EndpointAddr,
+    /// The names and sizes of the files that can be downloaded, by hash.
+    files: Arc<HashMap<Hash, (String, u64)>>,
+    /// Set if a folder is served.
+    gallery: Option<Gallery>,
+    upload_limit: RateLimiter,
+    history: WebHistory,
+}
+
+/// Publishes a web link to the content shared by `ticket_str` through a fresh ngrok
+/// tunnel: the file itself, or a gallery of a folder. Returns the handle and the name of
+/// the file or folder.
+pub(crate) async fn start(
+    node: Node,
+    ticket_str: &str,
+    progress_sender: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<(SendHandle, String)> {
+    progress_sender.send(SendStatus::Connecting).await?;
+    let ticket = p2p::resolve_ticket(&node, ticket_str.trim()).await?;
+    ensure!(
+        ticket.format() == BlobFormat::HashSeq,
+        "Only tickets of files and folders can be shared as a web link"
+    );
+    let db = node.store();
+    // Protects the names of the files while the bridge runs.
+    let temp_tag = db.tags().temp_tag(ticket.hash_and_format()).await?;
+    let sources = sources::connect(&node, vec![ticket.addr().clone()], false).await?;
+    let (_hash_seq, sizes) = sources::fetch_hash_seq(db, &sources, ticket.hash()).await?;
+    drop(sources);
+    let collection = Collection::load(ticket.hash(), db).await?;
+    let share_tag = ShareTag::session(db, &temp_tag).await?;
+
+    let files: Vec<(String, Hash, u64)> = collection
+        .iter()
+        .zip(sizes.iter().skip(1))
+        .map(|((name, hash), size)| (name.clone(), *hash, *size))
+        .collect();
+    let Some((first_name, first_hash, _)) = files.first().cloned() else {
+        bail!("The ticket shares no files");
+    };
+    // The type of the files is told from their names, as their content is not here.
+    let gallery = (files.len() > 1).then(|| {
+        let entries = files
+            .iter()
+            .map(|(name, hash, size)| GalleryEntry::new(name.clone(), *hash, *size, &[]))
+            .collect();
+        Gallery::new(ticket.hash(), entries)
+    });
+    let name = match &gallery {
+        Some(_) => first_name.split('/').next().unwrap_or_default().to_string(),
+        None => first_name,
+    };
+
+    let upload_limit = RateLimiter::default();
+    let web_history = WebHistory::new(PathBuf::from(&name));
+    let state = BridgeState {
+        node: node.clone(),
+        peer: ticket.addr().clone(),
+        files: Arc::new(
+            files
+                .into_iter()
+                .map(|(name, hash, size)| (hash, (name, size)))
+                .collect(),
+        ),
+        gallery: gallery.clone(),
+        upload_limit: upload_limit.clone(),
+        history: web_history.clone(),
+    };
+    // The same routes as a web link of the content itself, so that `receive` works with both.
+    let app = Router::new()
+        .route("/download/{hash}", get(download_handler))
+        .route("/g/{id}", get(gallery_page_handler))
+        .route("/g/{id}/files", get(gallery_files_handler))
+        .route("/g/{id}/thumb/{hash}", get(thumbnail_handler))
+        .with_state(state);
+
+    progress_sender.send(SendStatus::Connecting).await?;
+    let (shutdown_tx, tun) = web::open_tunnel(app).await?;
+    let url = match &gallery {
+        Some(gallery) => format!("{}/g/{}", tun.url(), gallery.id),
+        None => format!("{}/download/{}", tun.url(), first_hash),
+    };
+    println!(
+        "Web bridge to {} started at: {}",
+        ticket.addr().id.fmt_short(),
+        url
+    );
+    progress_sender
+        .send(SendStatus::ReadyToSend { ticket: url })
+        .await?;
+
+    let handle = SendHandle {
+        shutdown_tx: Some(shutdown_tx),
+        _ngrok_tunnel: Some(tun),
+        _temp_tag: temp_tag,
+        _share_tag: Arc::new(share_tag),
+        _watcher: None,
+        _pointer: None,
+        upload_limit,
+        _upload_registration: None,
+        _node: node,
+        share: None,
+        path: PathBuf::from(&name),
+        fetched: web_history.fetched,
+        tokio_handle,
+    };
+    Ok((handle, name))
+}
+
+/// Connects to the peer behind the ticket, or answers that it cannot be reached.
+async fn connect(state: &BridgeState) -> Result<Connection, Response> {
+    match state
+        .node
+        .endpoint()
+        .connect(state.peer.clone(), BlobsAlpn)
+        .await
+    {
+        Ok(connection) => Ok(connection),
+        Err(e) => {
+            println!(
+                "Web bridge cannot reach {}: {}",
+                state.peer.id.fmt_short(),
+                e
+            );
+            let message = "The device sharing this file cannot be reached right now";
+            Err((StatusCode::BAD_GATEWAY, message).into_response())
+        }
+    }
+}
+
+/// Fetches the `len` bytes from `offset` of the file `hash` over `connection`. The first
+/// item is the start of the file, which tells its type; the bytes asked for follow as
+/// they arrive. Ends with an error if the peer fails or sends less than asked for.
+fn fetch(
+    connection: Connection,
+    hash: Hash,
+    offset: u64,
+    len: u64,
+) -> futures::channel::mpsc::Receiver<io::Result<Bytes>> {
+    let (mut tx, rx) = futures::channel::mpsc::channel(4);
+    tokio::spawn(async move {
+        let result = async {
+            let end = offset + len;
+            let mut ranges =
+                ChunkRanges::from(ChunkNum::full_chunks(offset)..ChunkNum::chunks(end));
+            ranges |= ChunkRanges::from(ChunkNum(0)..ChunkNum(1));
+            let request = GetRequest::builder().root(ranges).build(hash);
+            let connected = fsm::start(connection, request, Default::default())
+                .next()
+                .await?;
+            let fsm::ConnectedNext::StartRoot(root) = connected.next().await? else {
+                bail!("The device sharing the file sent something else");
+            };
+            let (mut content, _size) = root.next().next().await?;
+            let mut head_sent = false;
+            let mut sent = offset;
+            let end_blob = loop {
+                match content.next().await {
+                    fsm::BlobContentNext::More((next, item)) => {
+                        if let BaoContentItem::Leaf(leaf) = item? {
+                            if !head_sent {
+                                let head = leaf.data.len().min(mime::SNIFF_LEN);
+                                tx.send(Ok(leaf.data.slice(..head))).await?;
+                                head_sent = true;
+                            }
+                            let leaf_end = leaf.offset + leaf.data.len() as u64;
+                            let (from, to) = (sent.max(leaf.offset), end.min(leaf_end));
+                            if from < to {
+                                let part =
+                                    (from - leaf.offset) as usize..(to - leaf.offset) as usize;
+                                tx.send(Ok(leaf.data.slice(part))).await?;
+                                sent = to;
+                            }
+                        }
+                        content = next;
+                    }
+                    fsm::BlobContentNext::Done(end_blob) => break end_blob,
+                }
+            };
+            ensure!(
+                sent == end,
+                "The device sharing the file sent less of it than asked for"
+            );
+            if let fsm::EndBlobNext::Closing(closing) = end_blob.next() {
+                closing.next().await?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        // Aborts the response, so the browser does not keep a broken file.
+        if let Err(e) = result {
+            tx.send(Err(io::Error::other(e))).await.ok();
+        }
+    });
+    rx
+}
+
+/// Axum handler that fetches the file, or the part of it a `Range` request asks for, from
+/// the peer and passes it on as it arrives.
+async fn download_handler(
+    State(state): State<BridgeState>,
+    AxumPath(hash_str): AxumPath<String>,
+    Query(query): Query<DownloadQuery>,
+    request_headers: HeaderMap,
+) -> impl IntoResponse {
+    let file = hash_str
+        .parse::<Hash>()
+        .ok()
+        .and_then(|hash| Some((hash, state.files.get(&hash)?.clone())));
+    let Some((hash, (file_name, size))) = file else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+    let range = web::requested_range(&request_headers, size);
+    if range == ByteRange::Unsatisfiable {
+        let headers = mime::download_headers(&file_name, &[], query.inline());
+        return range.response(size, headers, Body::empty());
+    }
+    let (offset, len) = range.span(size);
+    let file = HistoryFile {
+        name: file_name.clone(),
+        size,
+        hash,
+    };
+    let mut download = state.history.download(file, range, len);
+    let connection = match connect(&state).await {
+        Ok(connection) => connection,
+        Err(response) => {
+            if let Some(download) = download.as_mut() {
+                download.fail("The device sharing the file could not be reached");
+            }
+            return response;
+        }
+    };
+    // Shows the peer in the diagnostics while the browser downloads.
+    let active = state.node.active_peers().track(state.peer.id);
+    let mut stream = fetch(connection, hash, offset, len);
+    let head = match stream.next().await {
+        Some(Ok(head)) => head,
+        Some(Err(e)) => {
+            if let Some(download) = download.as_mut() {
+                download.fail(&e);
+            }
+            println!("Web bridge failed to fetch {}: {}", file_name, e);
+            let message = "The device sharing this file did not send it";
+            return (StatusCode::BAD_GATEWAY, message).into_response();
+        }
+        None => Bytes::new(),
+    };
+    let headers = mime::download_headers(&file_name, &head, query.inline());
+    let upload_limit = state.upload_limit.clone();
+    let stream = stream.then(move |chunk| {
+        let _active = &active;
+        if let Some(download) = download.as_mut() {
+            download.add(&chunk);
+        }
+        let upload_limit = upload_limit.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                limit::throttle_upload(&upload_limit, bytes.len() as u64).await;
+            }
+            chunk
+        }
+    });
+    range.response(size, headers, Body::from_stream(stream))
+}
+
+/// Returns the gallery if `id` names it.
+fn gallery(state: &BridgeState, id: &str) -> Option<Gallery> {
+    state
+        .gallery
+        .clone()
+        .filter(|gallery| gallery.id.to_string() == id)
+}
+
+/// Axum handler for the page of a folder.
+async fn gallery_page_handler(
+    State(state): State<BridgeState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    match gallery(&state, &id) {
+        Some(gallery) => gallery.page_response(),
+        None => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
+
+/// Axum handler for the list of files of a folder.
+async fn gallery_files_handler(
+    State(state): State<BridgeState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    match gallery(&state, &id) {
+        Some(gallery) => gallery.files_response(),
+        None => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
+
+/// Axum handler for the thumbnail of an image of a folder, made from the whole image
+/// fetched from the peer.
+async fn thumbnail_handler(
+    State(state): State<BridgeState>,
+    AxumPath((id, hash_str)): AxumPath<(String, String)>,
+) -> impl IntoResponse {
+    let Some(gallery) = gallery(&state, &id) else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+    gallery
+        .thumbnail_response(&hash_str, |hash| async move {
+            let size = state.files.get(&hash).map(|(_, size)| *size);
+            let Some(size) = size else {
+                bail!("Not a file of the folder");
+            };
+            let Ok(connection) = connect(&state).await else {
+                bail!("The device sharing the folder cannot be reached");
+            };
+            let _active = state.node.active_peers().track(state.peer.id);
+            let mut stream = fetch(connection, hash, 0, size);
+            // Skips the start of the file, which is part of the data as well.
+            stream.next().await.transpose()?;
+            let mut data = Vec::with_capacity(size as usize);
+            while let Some(chunk) = stream.next().await {
+                data.extend_from_slice(&chunk?);
+            }
+            Ok(Bytes::from(data))
+        })
+        .await
+}
@@ -0,0 +1,327 @@
+//! Removes what transfers leave on disk when the app crashes: files that were still being
+//! written, and the blob stores older versions kept per transfer in a temporary directory.
+//! Files are overwritten before they are deleted, so that their content cannot be read
+//! back from the disk.
+//!
+//! Every temporary file in use is registered with a lock file in the app data dir. The
+//! lock is held until the file is gone, so only files of processes that ended are swept.
+
+use super::config::data_dir;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+/// Names of the per-transfer stores of older versions, in the temp dir.
+const LEGACY_TEMP_PREFIXES: &[&str] = &["p2p-client-p2p-", "p2p-client-http-"];
+
+/// Names of the stores of downloads of older versions, in the directory received into.
+const LEGACY_RECEIVE_PREFIX: &str = ".p2p-client-recv-";
+
+/// Directories changed more recently may still belong to an older version that is running.
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// What a sweep removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    pub removed: Vec<PathBuf>,
+    /// The size of the removed files.
+    pub bytes: u64,
+}
+
+impl CleanupReport {
+    fn remove(&mut self, path: PathBuf) {
+        match secure_remove(&path) {
+            Ok(bytes) => {
+                self.bytes += bytes;
+                self.removed.push(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => println!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// A registered temporary file, as written to its lock file.
+#[derive(Debug, Serialize, Deserialize)]
+struct TempRecord {
+    pid: u32,
+    path: PathBuf,
+}
+
+/// Keeps a temporary file registered as in use until dropped. Removing the file itself
+/// is left to its owner.
+#[derive(Debug)]
+pub(crate) struct TempRegistration {
+    lock_path: PathBuf,
+    _lock: File,
+}
+
+impl Drop for TempRegistration {
+    fn drop(&mut self) {
+        // The lock is released when the file is closed, after it is gone.
+        let _ = std::fs::remove_file(&self.lock_path);
+    }
+}
+
+fn registry_dir() -> anyhow::Result<PathBuf> {
+    Ok(data_dir()?.join("temp"))
+}
+
+/// Registers the temporary file `path` until the returned guard is dropped.
+pub(crate) fn register_temp(path: &Path) -> anyhow::Result<TempRegistration> {
+    register_temp_in(&registry_dir()?, path)
+}
+
+fn register_temp_in(registry: &Path, path: &Path) -> anyhow::Result<TempRegistration> {
+    create_private_dir(registry)?;
+    let pid = std::process::id();
+    let name = format!("{}-{}", pid, hex::encode(rand::random::<[u8; 8]>()));
+    // Written under another name first, so a sweep never sees a lock file without a lock.
+    let partial = registry.join(format!("{}.tmp", name));
+    let mut lock = File::create(&partial)?;
+    lock.lock()?;
+    let record = TempRecord {
+        pid,
+        path: path.to_path_buf(),
+    };
+    lock.write_all(&serde_json::to_vec(&record)?)?;
+    let lock_path = registry.join(format!("{}.lock", name));
+    std::fs::rename(&partial, &lock_path)?;
+    Ok(TempRegistration {
+        lock_path,
+        _lock: lock,
+    })
+}
+
+/// Removes the registered temporary files whose process has ended.
+fn sweep_registry(registry: &Path, report: &mut CleanupReport) {
+    let lock_files = std::fs::read_dir(registry)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lock"));
+    for lock_path in lock_files {
+        let Ok(mut lock) = File::options().read(true).write(true).open(&lock_path) else {
+            continue;
+        };
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => continue,
+            Err(TryLockError::Error(e)) => {
+                println!("Failed to check {}: {}", lock_path.display(), e);
+                continue;
+            }
+        }
+        let mut json = Vec::new();
+        let record = lock
+            .read_to_end(&mut json)
+            .ok()
+            .and_then(|_| serde_json::from_slice::<TempRecord>(&json).ok());
+        if let Some(record) = record {
+            report.remove(record.path);
+        }
+        let _ = std::fs::remove_file(&lock_path);
+    }
+}
+
+/// Creates `dir` and its parents, readable by the current user only.
+pub(crate) fn create_private_dir(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// Overwrites every file below `path` with zeros and deletes it. Symbolic links are
+/// deleted without touching what they point to. Returns the number of bytes wiped.
+pub(crate) fn secure_remove(path: &Path) -> io::Result<u64> {
+    let mut wiped = 0;
+    for entry in WalkDir::new(path).contents_first(true) {
+        let entry = entry.map_err(io::Error::other)?;
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::remove_dir(entry.path())?;
+            continue;
+        }
+        if file_type.is_file() {
+            wiped += overwrite(entry.path())?;
+        }
+        std::fs::remove_file(entry.path())?;
+    }
+    Ok(wiped)
+}
+
+fn overwrite(path: &Path) -> io::Result<u64> {
+    overwrite_file(&mut File::options().write(true).open(path)?)
+}
+
+/// Overwrites the open `file` with zeros. Returns the number of bytes wiped.
+pub(crate) fn overwrite_file(file: &mut File) -> io::Result<u64> {
+    let len = file.metadata()?.len();
+    file.rewind()?;
+    let zeros = vec![0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()?;
+    Ok(len)
+}
+
+/// Returns the stores of older versions in `temp_dir` and `receive_dir` that have not
+/// been changed for a while.
+fn stale_dirs(temp_dir: &Path, receive_dir: &Path, now: SystemTime) -> Vec<PathBuf> {
+    let in_temp = list_dirs(temp_dir, |name| {
+        LEGACY_TEMP_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    });
+    let in_receive = list_dirs(receive_dir, |name| name.starts_with(LEGACY_RECEIVE_PREFIX));
+    in_temp
+        .chain(in_receive)
+        .filter(|dir| {
+            dir.metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age >= STALE_AFTER)
+        })
+        .collect()
+}
+
+fn list_dirs(dir: &Path, matches: impl Fn(&str) -> bool) -> impl Iterator<Item = PathBuf> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(move |entry| {
+            entry.file_type().is_ok_and(|t| t.is_dir())
+                && matches(&entry.file_name().to_string_lossy())
+        })
+        .map(|entry| entry.path())
+}
+
+/// Securely removes the temporary files of processes that ended, and the stores older
+/// versions left behind in the temp dir and in the current directory.
+pub(crate) fn clean_up() -> anyhow::Result<CleanupReport> {
+    let mut report = CleanupReport::default();
+    sweep_registry(&registry_dir()?, &mut report);
+    let receive_dir = std::env::current_dir().unwrap_or_default();
+    for dir in stale_dirs(&std::env::temp_dir(), &receive_dir, SystemTime::now()) {
+        report.remove(dir);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "p2p-client-test-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ))
+    }
+
+    #[test]
+    fn test_secure_remove_keeps_link_targets() {
+        let dir = test_dir();
+        let store = dir.join("store");
+        std::fs::create_dir_all(store.join("data")).unwrap();
+        std::fs::write(store.join("data").join("blob"), b"secret").unwrap();
+        let outside = dir.join("outside.txt");
+        std::fs::write(&outside, b"keep me").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, store.join("link")).unwrap();
+
+        assert_eq!(secure_remove(&store).unwrap(), 6);
+        assert!(!store.exists());
+        assert_eq!(std::fs::read(&outside).unwrap(), b"keep me");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_dirs() {
+        let dir = test_dir();
+        for name in [
+            "p2p-client-p2p-1",
+            "p2p-client-http-2",
+            ".p2p-client-recv-3",
+            "p2p-client-test-4",
+        ] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        let now = SystemTime::now();
+        assert!(stale_dirs(&dir, &dir, now).is_empty());
+
+        let mut stale = stale_dirs(&dir, &dir, now + STALE_AFTER);
+        stale.sort();
+        let expected: Vec<PathBuf> = [
+            ".p2p-client-recv-3",
+            "p2p-client-http-2",
+            "p2p-client-p2p-1",
+        ]
+        .iter()
+        .map(|name| dir.join(name))
+        .collect();
+        assert_eq!(stale, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sweep_registry_skips_files_in_use() {
+        let dir = test_dir();
+        let registry = dir.join("temp");
+        let (in_use, left_behind) = (dir.join("a.part"), dir.join("b.part"));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&in_use, b"12").unwrap();
+        std::fs::write(&left_behind, b"345").unwrap();
+        let registration = register_temp_in(&registry, &in_use).unwrap();
+        // A process that crashed leaves its lock file behind, but not the lock.
+        let record = TempRecord {
+            pid: 1,
+            path: left_behind.clone(),
+        };
+        std::fs::write(
+            registry.join("1-crashed.lock"),
+            serde_json::to_vec(&record).unwrap(),
+        )
+        .unwrap();
+
+        let mut report = CleanupReport::default();
+        sweep_registry(&registry, &mut report);
+        assert_eq!(report.removed, vec![left_behind.clone()]);
+        assert_eq!(report.bytes, 3);
+        assert!(in_use.exists());
+        assert!(!registry.join("1-crashed.lock").exists());
+
+        drop(registration);
+        assert_eq!(std::fs::read_dir(&registry).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir();
+        create_private_dir(&dir.join("store")).unwrap();
+        let mode = dir.join("store").metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
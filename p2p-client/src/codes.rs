@@ -0,0 +1,195 @@
+use anyhow::{bail, Context};
+use axum::{
+    extract::{Path as AxumPath, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// How long a rendezvous server keeps a code before forgetting it.
+const CODE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Tickets are a few hundred characters; anything much bigger is not a ticket.
+const MAX_TICKET_SIZE: usize = 16 * 1024;
+
+/// Words used to build short codes. Chosen to be easy to spell and say over the phone.
+const WORDS: &[&str] = &[
+    "acid", "acorn", "album", "alpha", "amber", "anchor", "apple", "arrow", "atlas", "autumn",
+    "bacon", "badge", "bamboo", "banana", "basket", "beacon", "berry", "bison", "blanket", "blue",
+    "border", "bottle", "bread", "brick", "bridge", "bronze", "bucket", "butter", "cabin",
+    "cactus", "camel", "candle", "canyon", "carbon", "carpet", "castle", "cedar", "cherry",
+    "circus", "citrus", "clover", "cobalt", "cocoa", "comet", "copper", "coral", "cotton",
+    "crystal", "dagger", "daisy", "delta", "desert", "dolphin", "dragon", "eagle", "echo", "ember",
+    "falcon", "feather", "fiddle", "forest", "fossil", "garden", "garlic", "ginger", "glacier",
+    "granite", "gravel", "guitar", "hammer", "harbor", "hazel", "helmet", "honey", "island",
+    "ivory", "jacket", "jasmine", "jungle", "kettle", "kiwi", "ladder", "lagoon", "lemon", "lilac",
+    "lizard", "lobster", "magnet", "mango", "maple", "marble", "meadow", "melon", "meteor", "mint",
+    "mirror", "monkey", "mustard", "nectar", "noodle", "ocean", "olive", "onion", "orange",
+    "orbit", "otter", "oyster", "paddle", "panda", "papaya", "parrot", "peanut", "pebble",
+    "pepper", "pickle", "pillow", "pirate", "planet", "plum", "pocket", "puzzle", "purple",
+    "quartz", "rabbit", "radar", "raven", "ribbon", "river", "rocket", "saddle", "salmon",
+    "sausage", "silver", "socket", "spider", "spruce", "statue", "sugar", "summit", "tablet",
+    "tango", "teapot", "tiger", "timber", "tomato", "tulip", "tunnel", "turtle", "velvet",
+    "violet", "walnut", "wizard", "yellow", "zebra",
+];
+
+/// Generates a short, human-readable code such as "7-purple-sausage".
+pub fn generate_code() -> String {
+    let number = rand::random_range(1..100u32);
+    let first = WORDS[rand::random_range(0..WORDS.len())];
+    let second = WORDS[rand::random_range(0..WORDS.len())];
+    format!("{}-{}-{}", number, first, second)
+}
+
+/// Returns true if the input looks like a short code rather than a full ticket.
+pub fn is_short_code(input: &str) -> bool {
+    let mut parts = input.trim().split('-');
+    let number = parts.next().unwrap_or_default();
+    let words: Vec<&str> = parts.collect();
+    !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
+        && !words.is_empty()
+        && words
+            .iter()
+            .all(|w| !w.is_empty() && w.chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+/// Normalizes a typed code: surrounding whitespace and case do not matter.
+pub(crate) fn normalize_code(code: &str) -> String {
+    code.trim().to_ascii_lowercase()
+}
+
+/// Registers a code -> ticket mapping on a rendezvous server.
+pub(crate) async fn publish_code(server: &Url, code: &str, ticket: &str) -> anyhow::Result<()> {
+    let response = reqwest::Client::new()
+        .put(code_url(server, code)?)
+        .body(ticket.to_string())
+        .send()
+        .await
+        .context("Rendezvous server is not reachable")?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        reqwest::StatusCode::CONFLICT => bail!("code {} is already taken", code),
+        status => bail!("rendezvous server returned {}", status),
+    }
+}
+
+/// Looks up the ticket behind a code on a rendezvous server.
+pub(crate) async fn lookup_code(server: &Url, code: &str) -> anyhow::Result<Option<String>> {
+    let response = reqwest::Client::new()
+        .get(code_url(server, code)?)
+        .send()
+        .await
+        .context("Rendezvous server is not reachable")?;
+    match response.status() {
+        status if status.is_success() => Ok(Some(response.text().await?.trim().to_string())),
+        reqwest::StatusCode::NOT_FOUND => Ok(None),
+        status => bail!("rendezvous server returned {}", status),
+    }
+}
+
+fn code_url(server: &Url, code: &str) -> anyhow::Result<Url> {
+    let mut url = server.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid rendezvous server URL: {}", server))?
+        .pop_if_empty()
+        .extend(["codes", code]);
+    Ok(url)
+}
+
+/// State of the rendezvous server: code -> (ticket, registration time).
+#[derive(Clone, Default)]
+struct RendezvousState {
+    codes: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+}
+
+impl RendezvousState {
+    fn purge_expired(codes: &mut HashMap<String, (String, Instant)>) {
+        codes.retain(|_, (_, created)| created.elapsed() < CODE_TTL);
+    }
+}
+
+/// Runs a minimal self-hostable rendezvous server that maps short codes to tickets.
+/// `PUT /codes/{code}` registers a ticket, `GET /codes/{code}` resolves it.
+pub async fn run_rendezvous_server(listen: SocketAddr) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/codes/{code}", get(get_code).put(put_code))
+        .with_state(RendezvousState::default());
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    println!(
+        "Rendezvous server listening on http://{}",
+        listener.local_addr()?
+    );
+    axum::serve(listener, app.into_make_service()).await?;
+    Ok(())
+}
+
+/// Axum handler that resolves a code.
+async fn get_code(
+    State(state): State<RendezvousState>,
+    AxumPath(code): AxumPath<String>,
+) -> impl IntoResponse {
+    let mut codes = state.codes.lock().unwrap();
+    RendezvousState::purge_expired(&mut codes);
+    match codes.get(&normalize_code(&code)) {
+        Some((ticket, _)) => (StatusCode::OK, ticket.clone()).into_response(),
+        None => (StatusCode::NOT_FOUND, "Unknown code").into_response(),
+    }
+}
+
+/// Axum handler that registers a code. Existing codes are never overwritten.
+async fn put_code(
+    State(state): State<RendezvousState>,
+    AxumPath(code): AxumPath<String>,
+    ticket: String,
+) -> impl IntoResponse {
+    let code = normalize_code(&code);
+    if !is_short_code(&code) || ticket.is_empty() || ticket.len() > MAX_TICKET_SIZE {
+        return (StatusCode::BAD_REQUEST, "Invalid code or ticket").into_response();
+    }
+    let mut codes = state.codes.lock().unwrap();
+    RendezvousState::purge_expired(&mut codes);
+    if codes.contains_key(&code) {
+        return (StatusCode::CONFLICT, "Code already taken").into_response();
+    }
+    codes.insert(code, (ticket.trim().to_string(), Instant::now()));
+    StatusCode::CREATED.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_code_is_short_code() {
+        for _ in 0..100 {
+            let code = generate_code();
+            assert!(is_short_code(&code), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_is_short_code_rejects_tickets() {
+        assert!(!is_short_code("blobaaxyzabc123"));
+        assert!(!is_short_code("purple-sausage"));
+        assert!(!is_short_code("7-"));
+        assert!(is_short_code(" 7-purple-sausage "));
+    }
+
+    #[test]
+    fn test_code_url_appends_segments() {
+        let server = Url::parse("https://codes.example.com/api/").unwrap();
+        assert_eq!(
+            code_url(&server, "7-purple-sausage").unwrap().as_str(),
+            "https://codes.example.com/api/codes/7-purple-sausage"
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Compression on the wire. Receivers that ask for it fetch whole files over [`ALPN`]
+//! instead of the blobs protocol. The sender reads the file from its store and sends it
+//! in zstd frames, leaving frames that do not get smaller as they are, so photos and
+//! archives cost little. The receiver decompresses the frames into its store, which hashes
+//! them as they arrive, and only keeps the file if it has the hash the collection lists.
+//! Senders that do not know the protocol are asked over the blobs protocol as before.
+
+use super::limit::{self, RateLimiter};
+use super::uploads::Uploads;
+use anyhow::{ensure, Context};
+use bytes::Bytes;
+use futures::SinkExt;
+use iroh::{
+    endpoint::{Connection, RecvStream, SendStream},
+    protocol::{AcceptError, ProtocolHandler},
+    Endpoint,
+};
+use iroh_blobs::{
+    api::{proto::BlobStatus, Store},
+    Hash,
+};
+use std::{future::Future, io};
+use tokio::io::AsyncReadExt;
+
+/// The protocol receivers use to fetch files compressed.
+pub(crate) const ALPN: &[u8] = b"p2p-client/compressed/0";
+
+/// Bytes of the file in every frame but the last.
+const FRAME_SIZE: usize = 256 * 1024;
+
+/// Marks a compressed frame in its header. The other bits are the length of the payload.
+const COMPRESSED: u32 = 1 << 31;
+
+/// Fast enough to keep up with most connections.
+const LEVEL: i32 = 3;
+
+/// Serves files of the store compressed, within the upload limits of their sends.
+#[derive(Debug, Clone)]
+pub(crate) struct Compression {
+    endpoint: Endpoint,
+    store: Store,
+    uploads: Uploads,
+}
+
+impl Compression {
+    pub(crate) fn new(endpoint: Endpoint, store: Store, uploads: Uploads) -> Self {
+        Self {
+            endpoint,
+            store,
+            uploads,
+        }
+    }
+
+    /// Answers one request: the hash of the collection the file belongs to and the hash of
+    /// the file. The answer is the size of the file and its frames, or nothing if no send
+    /// shares the collection, the collection does not list the file or the store does not
+    /// have all of it.
+    async fn serve(
+        &self,
+        connection: &Connection,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) -> anyhow::Result<()> {
+        let mut request = [0u8; 64];
+        recv.read_exact(&mut request).await?;
+        let collection = Hash::from_bytes(request[..32].try_into()?);
+        let hash = Hash::from_bytes(request[32..].try_into()?);
+        let shared = self.uploads.serves(&self.store, collection, hash).await;
+        let size = match self.store.blobs().status(hash).await? {
+            BlobStatus::Complete { size } if shared => size,
+            _ => {
+                send.finish()?;
+                return Ok(());
+            }
+        };
+        let upload = self.uploads.compressed(connection.remote_id()?, collection);
+        send.write_all(&size.to_le_bytes()).await?;
+        let mut reader = self.store.reader(hash);
+        loop {
+            let mut chunk = Vec::with_capacity(FRAME_SIZE);
+            (&mut reader)
+                .take(FRAME_SIZE as u64)
+                .read_to_end(&mut chunk)
+                .await?;
+            if chunk.is_empty() {
+                break;
+            }
+            let frame = tokio::task::spawn_blocking(move || encode_frame(&chunk)).await??;
+            upload.send(frame.len() as u64, &self.endpoint).await;
+            send.write_all(&frame).await?;
+        }
+        send.finish()?;
+        Ok(())
+    }
+}
+
+impl ProtocolHandler for Compression {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        // Every file is asked for on a stream of its own, one after the other.
+        while let Ok((send, recv)) = connection.accept_bi().await {
+            if let Err(e) = self.serve(&connection, send, recv).await {
+                println!("Failed to send a compressed file: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fetches the file `hash` of `collection` compressed over `connection` into `store`,
+/// limited by `download_limit`. `progress` is told how many bytes of the file arrived
+/// after every frame.
+pub(crate) async fn fetch<F>(
+    store: &Store,
+    connection: &Connection,
+    collection: Hash,
+    hash: Hash,
+    download_limit: &RateLimiter,
+    progress: impl Fn(u64) -> F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(collection.as_bytes()).await?;
+    send.write_all(hash.as_bytes()).await?;
+    send.finish()?;
+    let mut size = [0u8; 8];
+    recv.read_exact(&mut size)
+        .await
+        .context("The sender does not have the file")?;
+    let size = u64::from_le_bytes(size);
+
+    // The store reads the file from a channel, as it only takes owned streams.
+    let (mut tx, rx) = futures::channel::mpsc::channel::<io::Result<Bytes>>(4);
+    let import = async { store.add_stream(rx).await.temp_tag().await };
+    let receive = async {
+        let result = async {
+            let mut remaining = size;
+            while remaining > 0 {
+                let mut header = [0u8; 4];
+                recv.read_exact(&mut header)
+                    .await
+                    .context("The download ended early")?;
+                let header = u32::from_le_bytes(header);
+                let len = (header & !COMPRESSED) as usize;
+                ensure!(len <= max_payload(), "The sender sent an invalid frame");
+                let mut payload = vec![0u8; len];
+                recv.read_exact(&mut payload)
+                    .await
+                    .context("The download ended early")?;
+                limit::throttle_download(download_limit, 4 + len as u64).await;
+                let data = decode_frame(header, &payload)?;
+                let len = data.len() as u64;
+                ensure!(
+                    len > 0 && len <= remaining,
+                    "The sender sent more than the file"
+                );
+                remaining -= len;
+                progress(len).await;
+                if tx.send(Ok(Bytes::from(data))).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        // Ends the import. What the store has of a failed download is not tagged and is
+        // removed by its garbage collection.
+        drop(tx);
+        result
+    };
+    let (temp_tag, received) = tokio::join!(import, receive);
+    received?;
+    let temp_tag = temp_tag?;
+    ensure!(
+        temp_tag.hash() == hash,
+        "The file sent does not match its hash"
+    );
+    Ok(())
+}
+
+/// The frame of `chunk`: a header with the length of the payload, then the chunk
+/// compressed, or as it is if it does not get smaller.
+fn encode_frame(chunk: &[u8]) -> io::Result<Vec<u8>> {
+    let compressed = zstd::bulk::compress(chunk, LEVEL)?;
+    let (flag, payload) = if compressed.len() < chunk.len() {
+        (COMPRESSED, compressed.as_slice())
+    } else {
+        (0, chunk)
+    };
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(flag | payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// The bytes of the file in the frame with `header` and `payload`. A frame never holds
+/// more than [`FRAME_SIZE`] bytes of the file, however well they compress.
+fn decode_frame(header: u32, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if header & COMPRESSED == 0 {
+        ensure!(
+            payload.len() <= FRAME_SIZE,
+            "The sender sent an invalid frame"
+        );
+        return Ok(payload.to_vec());
+    }
+    zstd::bulk::decompress(payload, FRAME_SIZE).context("The sender sent an invalid frame")
+}
+
+/// The largest payload a frame can have.
+fn max_payload() -> usize {
+    zstd::zstd_safe::compress_bound(FRAME_SIZE).max(FRAME_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(frame: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let header = u32::from_le_bytes(frame[..4].try_into().unwrap());
+        let len = (header & !COMPRESSED) as usize;
+        assert_eq!(len, frame.len() - 4);
+        decode_frame(header, &frame[4..])
+    }
+
+    #[test]
+    fn test_frames_roundtrip() {
+        let text = "2024-05-01 12:00:00 INFO request served\n".repeat(8000);
+        let text = &text.as_bytes()[..FRAME_SIZE];
+        let frame = encode_frame(text).unwrap();
+        assert!(frame.len() < text.len() / 10);
+        assert_eq!(decode(&frame).unwrap(), text);
+
+        // Data that does not compress is sent as it is.
+        let noise: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        let frame = encode_frame(&noise).unwrap();
+        assert_eq!(frame.len(), 4 + noise.len());
+        assert_eq!(&frame[4..], noise.as_slice());
+        assert_eq!(decode(&frame).unwrap(), noise);
+    }
+
+    #[test]
+    fn test_decode_frame_refuses_oversized_frames() {
+        let big = vec![0u8; FRAME_SIZE + 1];
+        let bomb = zstd::bulk::compress(&big, LEVEL).unwrap();
+        assert!(decode_frame(COMPRESSED | bomb.len() as u32, &bomb).is_err());
+        assert!(decode_frame(big.len() as u32, &big).is_err());
+        assert!(decode_frame(COMPRESSED | 3, b"abc").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::Context;
+use iroh::{endpoint::Builder, Endpoint, RelayMap, RelayMode, RelayUrl};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
+
+/// Environment variable that selects the relay mode when no CLI flag is given.
+pub const RELAY_ENV_VAR: &str = "P2P_CLIENT_RELAY";
+
+/// Environment variable with the URL of the rendezvous server used for short codes.
+pub const RENDEZVOUS_ENV_VAR: &str = "P2P_CLIENT_RENDEZVOUS";
+
+/// Environment variable that overrides where persistent application data is kept.
+pub const DATA_DIR_ENV_VAR: &str = "P2P_CLIENT_DATA_DIR";
+
+/// Environment variable with the garbage collection interval in seconds; `0` disables GC.
+pub const GC_INTERVAL_ENV_VAR: &str = "P2P_CLIENT_GC_INTERVAL";
+
+/// How often blobs that no share references anymore are deleted by default.
+const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Returns the directory for persistent application data, creating nothing.
+pub fn data_dir() -> anyhow::Result<PathBuf> {
+    if let Ok(dir) = std::env::var(DATA_DIR_ENV_VAR) {
+        return Ok(PathBuf::from(dir));
+    }
+    let base = dirs::data_dir().context("Could not determine the application data directory")?;
+    Ok(base.join("p2p-client"))
+}
+
+/// Reads the garbage collection interval from `P2P_CLIENT_GC_INTERVAL`. `None` disables GC.
+pub(crate) fn gc_interval() -> anyhow::Result<Option<Duration>> {
+    match std::env::var(GC_INTERVAL_ENV_VAR) {
+        Ok(value) => {
+            let secs: u64 = value
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid {} value", GC_INTERVAL_ENV_VAR))?;
+            Ok(Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()))
+        }
+        Err(_) => Ok(Some(DEFAULT_GC_INTERVAL)),
+    }
+}
+
+/// Reads the rendezvous server URL from `P2P_CLIENT_RENDEZVOUS`, if set.
+pub fn rendezvous_from_env() -> anyhow::Result<Option<Url>> {
+    match std::env::var(RENDEZVOUS_ENV_VAR) {
+        Ok(value) if !value.trim().is_empty() => {
+            Ok(Some(value.trim().parse().with_context(|| {
+                format!("Invalid {} value", RENDEZVOUS_ENV_VAR)
+            })?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Which relay servers the endpoints of this application may use.
+///
+/// Parsed from `default`, `disabled` or a comma-separated list of relay URLs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RelaySetting {
+    /// n0's public relay servers.
+    #[default]
+    Default,
+    /// Self-hosted relay servers only.
+    Custom(Vec<RelayUrl>),
+    /// No relays and no external discovery: direct connections only.
+    Disabled,
+}
+
+impl RelaySetting {
+    /// Reads the setting from `P2P_CLIENT_RELAY`, falling back to the default.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(RELAY_ENV_VAR) {
+            Ok(value) => value
+                .parse()
+                .with_context(|| format!("Invalid {} value", RELAY_ENV_VAR)),
+            Err(_) => Ok(Self::Default),
+        }
+    }
+
+    /// Returns true if endpoints should try to reach a relay at all.
+    pub fn uses_relay(&self) -> bool {
+        !matches!(self, Self::Disabled)
+    }
+
+    pub(crate) fn relay_mode(&self) -> RelayMode {
+        match self {
+            Self::Default => RelayMode::Default,
+            Self::Custom(urls) => RelayMode::Custom(urls.iter().cloned().collect::<RelayMap>()),
+            Self::Disabled => RelayMode::Disabled,
+        }
+    }
+}
+
+impl FromStr for RelaySetting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "default" => Ok(Self::Default),
+            "disabled" | "none" | "direct" => Ok(Self::Disabled),
+            _ => {
+                let urls = s
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|u| !u.is_empty())
+                    .map(|u| {
+                        u.parse::<RelayUrl>()
+                            .with_context(|| format!("Invalid relay URL: {}", u))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(Self::Custom(urls))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for RelaySetting {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RelaySetting> for String {
+    fn from(relay: RelaySetting) -> Self {
+        relay.to_string()
+    }
+}
+
+impl fmt::Display for RelaySetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Disabled => write!(f, "disabled"),
+            Self::Custom(urls) => {
+                let urls = urls.iter().map(|u| u.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", urls.join(","))
+            }
+        }
+    }
+}
+
+/// Creates an endpoint builder that honours the relay setting.
+/// With relays disabled, n0's DNS/pkarr discovery is dropped as well so no external traffic is made.
+pub(crate) fn endpoint_builder(relay: &RelaySetting) -> Builder {
+    let builder = Endpoint::builder().relay_mode(relay.relay_mode());
+    match relay {
+        RelaySetting::Disabled => builder.clear_discovery(),
+        _ => builder,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_setting_parses_keywords() {
+        assert_eq!(
+            "default".parse::<RelaySetting>().unwrap(),
+            RelaySetting::Default
+        );
+        assert_eq!("".parse::<RelaySetting>().unwrap(), RelaySetting::Default);
+        assert_eq!(
+            "Disabled".parse::<RelaySetting>().unwrap(),
+            RelaySetting::Disabled
+        );
+    }
+
+    #[test]
+    fn test_relay_setting_parses_url_list() {
+        let setting = "https://relay1.example.com, https://relay2.example.com"
+            .parse::<RelaySetting>()
+            .unwrap();
+        match &setting {
+            RelaySetting::Custom(urls) => assert_eq!(urls.len(), 2),
+            other => panic!("unexpected setting: {:?}", other),
+        }
+        assert_eq!(
+            setting.to_string().parse::<RelaySetting>().unwrap(),
+            setting
+        );
+    }
+
+    #[test]
+    fn test_relay_setting_rejects_invalid_url() {
+        assert!("not a url".parse::<RelaySetting>().is_err());
+    }
+}
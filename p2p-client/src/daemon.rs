@@ -0,0 +1,1052 @@
+use super::cleanup;
+use super::config::{data_dir, RelaySetting};
+use super::diagnostics::{self, Diagnostics};
+use super::history::HistoryEntry;
+use super::inbox::InboxOptions;
+use super::limit::{self, BandwidthLimits, RateLimiter};
+use super::node;
+use super::shares::{ShareInfo, ShareMode};
+use super::state::{InboxStatus, ReceiveStatus, SendHandle, SendStatus};
+use super::stats::{PathKind, SourceProgress, TransferProgress, TransferSummary};
+use super::sync::SyncOptions;
+use anyhow::{bail, Context};
+use irpc::{
+    channel::{mpsc, oneshot},
+    rpc::RemoteService,
+    rpc_requests, Client, WithChannels,
+};
+use n0_future::task::AbortOnDropHandle;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{runtime::Handle as TokioHandle, sync::Notify};
+
+/// A transfer managed by the daemon, as shown to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferInfo {
+    pub id: u64,
+    pub kind: TransferKind,
+    /// The file or folder being sent, the directory a download or upload goes to, or the
+    /// name of the file a web bridge serves.
+    pub path: PathBuf,
+    /// The ticket or web link of a send or bridge, the ticket a download comes from, or
+    /// the link of an upload page.
+    pub ticket: Option<String>,
+    /// The persistent share behind a send, if any.
+    pub share: Option<ShareInfo>,
+    /// True if the send is shared again whenever its folder changes.
+    pub watch: bool,
+    /// Upload limit of a send or download limit of a receive, in bytes per second.
+    #[serde(default)]
+    pub limit: Option<u64>,
+    /// Speed and path of the data currently flowing.
+    #[serde(default)]
+    pub progress: Option<TransferProgress>,
+    /// The last finished upload of a send.
+    #[serde(default)]
+    pub summary: Option<TransferSummary>,
+    /// What each peer of a download from several peers contributed.
+    #[serde(default)]
+    pub sources: Vec<SourceProgress>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferKind {
+    Send(ShareMode),
+    Receive,
+    /// A web page others upload files to.
+    Inbox,
+    /// A web link to the content of a ticket, fetched from its sender on demand.
+    Bridge,
+}
+
+/// What to send and how.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendRequest {
+    pub path: PathBuf,
+    pub mode: ShareMode,
+    /// Records the send as a persistent share that survives restarts.
+    pub keep: bool,
+    /// Name of the persistent share. Defaults to the file name.
+    pub name: Option<String>,
+    /// Lifetime of the persistent share in seconds.
+    pub expires_in: Option<u64>,
+    /// Shares the folder again whenever it changes. Only for tickets.
+    pub watch: bool,
+    /// Upload limit in bytes per second.
+    pub limit: Option<u64>,
+}
+
+/// Progress of a send started through the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendUpdate {
+    /// Id of the transfer, to stop it later.
+    pub id: u64,
+    pub status: SendStatus,
+}
+
+/// What to download and where.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiveRequest {
+    pub ticket: String,
+    /// More tickets or node ids of peers with the same content.
+    #[serde(default)]
+    pub sources: Vec<String>,
+    pub target_dir: PathBuf,
+    /// Updates the folder in place instead of refusing to overwrite files.
+    pub sync: Option<SyncOptions>,
+    /// Download limit in bytes per second.
+    pub limit: Option<u64>,
+    /// Keeps sharing the download from this node under a new ticket once it is done.
+    #[serde(default)]
+    pub seed: bool,
+    /// Asks the peers to send the files compressed.
+    #[serde(default)]
+    pub compress: bool,
+}
+
+/// Which ticket to share as a web link.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BridgeRequest {
+    pub ticket: String,
+    /// Upload limit to the browsers in bytes per second.
+    pub limit: Option<u64>,
+}
+
+/// Where uploaded files go and what is accepted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboxRequest {
+    pub target_dir: PathBuf,
+    pub options: InboxOptions,
+    /// Download limit of the uploads in bytes per second.
+    pub limit: Option<u64>,
+}
+
+/// Progress of a file request started through the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboxUpdate {
+    /// Id of the transfer, to stop it later.
+    pub id: u64,
+    pub status: InboxStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ListRequest;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StopRequest {
+    id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LimitsRequest;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SetLimitsRequest {
+    limits: BandwidthLimits,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SetTransferLimitRequest {
+    id: u64,
+    rate: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RelayRequest;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SetRelayRequest {
+    relay: RelaySetting,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DiagnosticsRequest;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ShutdownRequest;
+
+/// The control API of the daemon.
+#[rpc_requests(message = DaemonMessage)]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum DaemonProtocol {
+    #[rpc(tx = oneshot::Sender<Vec<TransferInfo>>)]
+    List(ListRequest),
+    #[rpc(tx = mpsc::Sender<SendUpdate>)]
+    Send(SendRequest),
+    #[rpc(tx = mpsc::Sender<ReceiveStatus>)]
+    Receive(ReceiveRequest),
+    #[rpc(tx = mpsc::Sender<InboxUpdate>)]
+    RequestFiles(InboxRequest),
+    #[rpc(tx = mpsc::Sender<SendUpdate>)]
+    Bridge(BridgeRequest),
+    #[rpc(tx = oneshot::Sender<Result<(), String>>)]
+    Stop(StopRequest),
+    #[rpc(tx = oneshot::Sender<BandwidthLimits>)]
+    Limits(LimitsRequest),
+    #[rpc(tx = oneshot::Sender<Result<(), String>>)]
+    SetLimits(SetLimitsRequest),
+    #[rpc(tx = oneshot::Sender<Result<(), String>>)]
+    SetTransferLimit(SetTransferLimitRequest),
+    #[rpc(tx = oneshot::Sender<RelaySetting>)]
+    Relay(RelayRequest),
+    #[rpc(tx = oneshot::Sender<Result<(), String>>)]
+    SetRelay(SetRelayRequest),
+    #[rpc(tx = oneshot::Sender<Result<Diagnostics, String>>)]
+    Diagnostics(DiagnosticsRequest),
+    #[rpc(tx = oneshot::Sender<()>)]
+    Shutdown(ShutdownRequest),
+}
+
+/// A running send or receive and what keeps it alive.
+struct Transfer {
+    info: TransferInfo,
+    /// The upload limit of a send or the download limit of a receive.
+    limit: RateLimiter,
+    _handle: Option<SendHandle>,
+    _task: Option<AbortOnDropHandle<()>>,
+}
+
+/// Owns all transfers. Runs inside the daemon process, or inside the GUI when no daemon is running.
+#[derive(Clone)]
+struct Daemon {
+    transfers: Arc<Mutex<BTreeMap<u64, Transfer>>>,
+    next_id: Arc<AtomicU64>,
+    /// The relay setting of the node. Requests cannot override it, as the process runs
+    /// a single node; [`Daemon::set_relay`] restarts the node to change it.
+    relay: Arc<Mutex<RelaySetting>>,
+    shutdown: Arc<Notify>,
+    tokio_handle: TokioHandle,
+}
+
+impl Daemon {
+    fn new(relay: RelaySetting, tokio_handle: TokioHandle) -> Self {
+        Self {
+            transfers: Default::default(),
+            next_id: Arc::new(AtomicU64::new(1)),
+            relay: Arc::new(Mutex::new(relay)),
+            shutdown: Default::default(),
+            tokio_handle,
+        }
+    }
+
+    fn relay(&self) -> RelaySetting {
+        self.relay.lock().unwrap().clone()
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Adds a transfer. Its task is spawned after this and handed over with
+    /// [`Daemon::attach`], so the task always finds the transfer it updates or removes.
+    fn insert(&self, info: TransferInfo, limit: RateLimiter, handle: Option<SendHandle>) {
+        let transfer = Transfer {
+            info,
+            limit,
+            _handle: handle,
+            _task: None,
+        };
+        self.transfers
+            .lock()
+            .unwrap()
+            .insert(transfer.info.id, transfer);
+    }
+
+    /// Keeps `task` running for as long as the transfer `id` exists. A transfer that was
+    /// stopped or has ended in the meantime aborts it.
+    fn attach(&self, id: u64, task: AbortOnDropHandle<()>) {
+        if let Some(transfer) = self.transfers.lock().unwrap().get_mut(&id) {
+            transfer._task = Some(task);
+        }
+    }
+
+    fn list(&self) -> Vec<TransferInfo> {
+        let transfers = self.transfers.lock().unwrap();
+        transfers
+            .values()
+            .map(|t| TransferInfo {
+                limit: t.limit.rate(),
+                ..t.info.clone()
+            })
+            .collect()
+    }
+
+    /// Changes the limit of a running transfer.
+    fn set_transfer_limit(&self, id: u64, rate: Option<u64>) -> anyhow::Result<()> {
+        let transfers = self.transfers.lock().unwrap();
+        let Some(transfer) = transfers.get(&id) else {
+            bail!("No transfer with id {}", id);
+        };
+        transfer.limit.set_rate(rate);
+        Ok(())
+    }
+
+    /// Restarts the node with another relay setting. The transfers running on the node
+    /// stop; persistent shares are announced again from the new node.
+    async fn set_relay(&self, relay: RelaySetting) -> anyhow::Result<()> {
+        if self.relay() == relay {
+            return Ok(());
+        }
+        // Upload pages run their own web server and do not use the node.
+        self.transfers
+            .lock()
+            .unwrap()
+            .retain(|_, t| t.info.kind == TransferKind::Inbox);
+        node::restart_shared_node(&relay).await?;
+        println!("Node restarted with relay mode {}.", relay);
+        *self.relay.lock().unwrap() = relay;
+        self.restore_shares().await;
+        Ok(())
+    }
+
+    /// Announces the persistent shares of earlier runs again.
+    async fn restore_shares(&self) {
+        match crate::restore_shares(self.relay(), self.tokio_handle.clone()).await {
+            Ok(handles) => {
+                for handle in handles {
+                    let share = handle.share().cloned();
+                    let Some(share) = share else { continue };
+                    let info = TransferInfo {
+                        id: self.next_id(),
+                        kind: TransferKind::Send(share.mode),
+                        path: share.path.clone(),
+                        ticket: share.ticket.clone(),
+                        watch: share.watch,
+                        share: Some(share),
+                        limit: None,
+                        progress: None,
+                        summary: None,
+                        sources: Vec::new(),
+                    };
+                    self.insert(info, handle.upload_limit().clone(), Some(handle));
+                }
+            }
+            Err(e) => println!("Failed to restore shares: {:#}", e),
+        }
+    }
+
+    async fn run(self, mut rx: tokio::sync::mpsc::Receiver<DaemonMessage>) {
+        let mut expiry_check = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = expiry_check.tick() => {
+                    self.remove_expired_shares().await;
+                    continue;
+                }
+            };
+            match msg {
+                DaemonMessage::List(msg) => {
+                    let WithChannels { tx, .. } = msg;
+                    let list = self.list();
+                    tx.send(list).await.ok();
+                }
+                DaemonMessage::Send(msg) => {
+                    let WithChannels { tx, inner, .. } = msg;
+                    tokio::spawn(self.clone().send(inner, tx));
+                }
+                DaemonMessage::Receive(msg) => {
+                    let WithChannels { tx, inner, .. } = msg;
+                    self.receive(inner, tx);
+                }
+                DaemonMessage::RequestFiles(msg) => {
+                    let WithChannels { tx, inner, .. } = msg;
+                    tokio::spawn(self.clone().request_files(inner, tx));
+                }
+                DaemonMessage::Bridge(msg) => {
+                    let WithChannels { tx, inner, .. } = msg;
+                    tokio::spawn(self.clone().bridge(inner, tx));
+                }
+                DaemonMessage::Stop(msg) => {
+                    let WithChannels { tx, inner, .. } = msg;
+                    let result = self.stop(inner.id).await.map_err(|e| format!("{:#}", e));
+                    tx.send(result).await.ok();
+                }
+                DaemonMessage::Limits(msg) => {
+                    let WithChannels { tx, .. } = msg;
+                    tx.send(limit::global_limits()).await.ok();
+                }
+                DaemonMessage::SetLimits(msg) => {
+                    let WithChannels { tx, inner, .. } = msg;
+                    let result =
+                        limit::set_global_limits(inner.limits).map_err(|e| format!("{:#}", e));
+                    tx.send(result).await.ok();
+                }
+                DaemonMessage::SetTransferLimit(msg) => {
+                    let WithChannels { tx, inner, .. } = msg;
+                    let result = self
+                        .set_transfer_limit(inner.id, inner.rate)
+                        .map_err(|e| format!("{:#}", e));
+                    tx.send(result).await.ok();
+                }
+                DaemonMessage::Relay(msg) => {
+                    let WithChannels { tx, .. } = msg;
+                    tx.send(self.relay()).await.ok();
+                }
+                DaemonMessage::SetRelay(msg) => {
+                    let WithChannels { tx, inner, .. } = msg;
+                    let result = self
+                        .set_relay(inner.relay)
+                        .await
+                        .map_err(|e| format!("{:#}", e));
+                    tx.send(result).await.ok();
+                }
+                DaemonMessage::Diagnostics(msg) => {
+                    let WithChannels { tx, .. } = msg;
+                    // The first network report of a new node takes a moment.
+                    tokio::spawn(async move {
+                        let result = match node::running_node().await {
+                            Some(node) => Ok(diagnostics::diagnose(&node).await),
+                            None => Err("The node is not running".to_string()),
+                        };
+                        tx.send(result).await.ok();
+                    });
+                }
+                DaemonMessage::Shutdown(msg) => {
+                    let WithChannels { tx, .. } = msg;
+                    self.transfers.lock().unwrap().clear();
+                    crate::shutdown().await;
+                    tx.send(()).await.ok();
+                    self.shutdown.notify_waiters();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Starts a send. If the client goes away before the ticket is ready, the send is cancelled.
+    async fn send(self, request: SendRequest, tx: mpsc::Sender<SendUpdate>) {
+        let id = self.next_id();
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel(10);
+        let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel::<()>();
+        let path = request.path.clone();
+        let mode = request.mode;
+        let watch = request.watch;
+        let limit = request.limit;
+        let relay = self.relay();
+        let start = async {
+            let tokio_handle = self.tokio_handle.clone();
+            if request.keep {
+                let name = request.name.clone().unwrap_or_else(|| {
+                    path.file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| path.display().to_string())
+                });
+                crate::create_share(
+                    path.clone(),
+                    name,
+                    mode,
+                    request.expires_in.map(Duration::from_secs),
+                    watch,
+                    relay,
+                    progress_tx,
+                    tokio_handle,
+                )
+                .await
+            } else {
+                match mode {
+                    ShareMode::Ticket => {
+                        crate::send_file(path.clone(), watch, relay, progress_tx, tokio_handle)
+                            .await
+                    }
+                    ShareMode::Web | ShareMode::EncryptedWeb if watch => {
+                        bail!("Only ticket shares can watch a folder")
+                    }
+                    ShareMode::Web | ShareMode::EncryptedWeb => {
+                        crate::start_http_send(
+                            path.clone(),
+                            mode == ShareMode::EncryptedWeb,
+                            relay,
+                            progress_tx,
+                            tokio_handle,
+                        )
+                        .await
+                    }
+                }
+            }
+        };
+        let start = async {
+            tokio::select! {
+                result = start => Some(result),
+                _ = cancel_rx => None,
+            }
+        };
+        // Forwards progress until the ticket is ready; later updates are handled below.
+        let forward = async {
+            while let Some(status) = progress_rx.recv().await {
+                let ticket = match &status {
+                    SendStatus::ReadyToSend { ticket } => Some(ticket.clone()),
+                    _ => None,
+                };
+                if tx.send(SendUpdate { id, status }).await.is_err() {
+                    cancel_tx.send(()).ok();
+                    return None;
+                }
+                if ticket.is_some() {
+                    return ticket;
+                }
+            }
+            None
+        };
+        let (result, ticket) = tokio::join!(start, forward);
+        match result {
+            Some(Ok(handle)) => {
+                handle.upload_limit().set_rate(limit);
+                let info = TransferInfo {
+                    id,
+                    kind: TransferKind::Send(mode),
+                    path,
+                    ticket,
+                    share: handle.share().cloned(),
+                    watch,
+                    limit,
+                    progress: None,
+                    summary: None,
+                    sources: Vec::new(),
+                };
+                self.insert(info, handle.upload_limit().clone(), Some(handle));
+                self.attach(id, self.follow(id, progress_rx, Some(tx)));
+            }
+            Some(Err(e)) => {
+                let status = SendStatus::Error(format!("{:#}", e));
+                tx.send(SendUpdate { id, status }).await.ok();
+            }
+            None => println!("Send {} cancelled by the client.", id),
+        }
+    }
+
+    /// Keeps the ticket of a running send up to date, e.g. when a watched folder changes,
+    /// and passes the updates on for as long as the client listens.
+    fn follow(
+        &self,
+        id: u64,
+        mut progress_rx: tokio::sync::mpsc::Receiver<SendStatus>,
+        mut client: Option<mpsc::Sender<SendUpdate>>,
+    ) -> AbortOnDropHandle<()> {
+        let transfers = self.transfers.clone();
+        let task = tokio::spawn(async move {
+            while let Some(status) = progress_rx.recv().await {
+                if let Some(transfer) = transfers.lock().unwrap().get_mut(&id) {
+                    let info = &mut transfer.info;
+                    match &status {
+                        SendStatus::Updated { ticket } => {
+                            info.ticket = Some(ticket.clone());
+                            if let Some(share) = info.share.as_mut() {
+                                share.ticket = Some(ticket.clone());
+                            }
+                        }
+                        SendStatus::Uploading {
+                            sent, speed, path, ..
+                        } => {
+                            info.progress = Some(TransferProgress {
+                                bytes: *sent,
+                                total: None,
+                                speed: *speed,
+                                eta: None,
+                                path: *path,
+                            });
+                        }
+                        SendStatus::Uploaded { summary, .. } => {
+                            info.progress = None;
+                            info.summary = Some(*summary);
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(tx) = &client {
+                    if tx.send(SendUpdate { id, status }).await.is_err() {
+                        client = None;
+                    }
+                }
+            }
+        });
+        AbortOnDropHandle::new(task)
+    }
+
+    /// Starts a download that keeps running even if the client goes away.
+    fn receive(&self, request: ReceiveRequest, tx: mpsc::Sender<ReceiveStatus>) {
+        let id = self.next_id();
+        let info = TransferInfo {
+            id,
+            kind: TransferKind::Receive,
+            path: request.target_dir.clone(),
+            ticket: Some(request.ticket.clone()),
+            share: None,
+            watch: false,
+            limit: request.limit,
+            progress: None,
+            summary: None,
+            sources: Vec::new(),
+        };
+        let limit = RateLimiter::new(request.limit);
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel(32);
+        let transfers = self.transfers.clone();
+        let download_limit = limit.clone();
+        let daemon = self.clone();
+        let (seed, relay) = (request.seed, self.relay());
+        self.insert(info, limit, None);
+        let task = tokio::spawn(async move {
+            let receive = crate::receive_file_into(
+                request.ticket,
+                request.sources,
+                request.target_dir,
+                request.sync,
+                request.compress,
+                relay.clone(),
+                download_limit,
+                progress_tx,
+            );
+            let forward = async {
+                let mut client_gone = false;
+                while let Some(status) = progress_rx.recv().await {
+                    match &status {
+                        ReceiveStatus::Downloading {
+                            downloaded,
+                            total,
+                            speed,
+                            eta,
+                            path,
+                        } => {
+                            if let Some(transfer) = transfers.lock().unwrap().get_mut(&id) {
+                                transfer.info.progress = Some(TransferProgress {
+                                    bytes: *downloaded,
+                                    total: Some(*total),
+                                    speed: *speed,
+                                    eta: *eta,
+                                    path: *path,
+                                });
+                            }
+                        }
+                        ReceiveStatus::Sources(sources) => {
+                            if let Some(transfer) = transfers.lock().unwrap().get_mut(&id) {
+                                transfer.info.sources = sources.clone();
+                            }
+                        }
+                        _ => {}
+                    }
+                    if !client_gone {
+                        client_gone = tx.send(status).await.is_err();
+                    }
+                }
+            };
+            let (entry, ()) = tokio::join!(receive, forward);
+            // Removing the download ends this task, so the seed is started first.
+            if let Some(entry) = entry.filter(|entry| seed && !entry.is_failed()) {
+                daemon.seed(entry, relay, &tx).await;
+            }
+            transfers.lock().unwrap().remove(&id);
+        });
+        self.attach(id, AbortOnDropHandle::new(task));
+    }
+
+    /// Shares a finished download again as a send of its own and tells the client its ticket.
+    async fn seed(
+        &self,
+        entry: HistoryEntry,
+        relay: RelaySetting,
+        tx: &mpsc::Sender<ReceiveStatus>,
+    ) {
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel(10);
+        let tokio_handle = self.tokio_handle.clone();
+        let handle = match crate::seed_download(&entry, relay, progress_tx, tokio_handle).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                let status = ReceiveStatus::Error(format!("{:#}", e));
+                tx.send(status).await.ok();
+                return;
+            }
+        };
+        // The ticket is ready by the time the send has started.
+        let mut ticket = None;
+        while let Ok(status) = progress_rx.try_recv() {
+            if let SendStatus::ReadyToSend { ticket: ready } = status {
+                ticket = Some(ready);
+            }
+        }
+        let id = self.next_id();
+        let info = TransferInfo {
+            id,
+            kind: TransferKind::Send(ShareMode::Ticket),
+            path: entry.received_path(),
+            ticket: ticket.clone(),
+            share: None,
+            watch: false,
+            limit: None,
+            progress: None,
+            summary: None,
+            sources: Vec::new(),
+        };
+        self.insert(info, handle.upload_limit().clone(), Some(handle));
+        self.attach(id, self.follow(id, progress_rx, None));
+        println!("Sharing the download into {} again.", entry.path.display());
+        let status = ReceiveStatus::Seeding {
+            id,
+            ticket: ticket.unwrap_or_default(),
+        };
+        tx.send(status).await.ok();
+    }
+
+    /// Publishes an upload page that keeps running even if the client goes away.
+    async fn request_files(self, request: InboxRequest, tx: mpsc::Sender<InboxUpdate>) {
+        let id = self.next_id();
+        let limit = RateLimiter::new(request.limit);
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel(32);
+        let started = crate::request_files(
+            request.target_dir.clone(),
+            request.options,
+            limit.clone(),
+            progress_tx,
+            self.tokio_handle.clone(),
+        )
+        .await;
+        let handle = match started {
+            Ok(handle) => handle,
+            Err(e) => {
+                let status = InboxStatus::Error(format!("{:#}", e));
+                tx.send(InboxUpdate { id, status }).await.ok();
+                return;
+            }
+        };
+        let mut link = None;
+        while let Ok(status) = progress_rx.try_recv() {
+            if let InboxStatus::Ready { link: ready } = &status {
+                link = Some(ready.clone());
+            }
+            tx.send(InboxUpdate { id, status }).await.ok();
+        }
+        let info = TransferInfo {
+            id,
+            kind: TransferKind::Inbox,
+            path: request.target_dir,
+            ticket: link,
+            share: None,
+            watch: false,
+            limit: request.limit,
+            progress: None,
+            summary: None,
+            sources: Vec::new(),
+        };
+        self.insert(info, limit, None);
+        let transfers = self.transfers.clone();
+        let task = tokio::spawn(async move {
+            // Stopping the transfer aborts this task, which takes the page offline.
+            let _handle = handle;
+            let mut client = Some(tx);
+            while let Some(status) = progress_rx.recv().await {
+                if let Some(transfer) = transfers.lock().unwrap().get_mut(&id) {
+                    let info = &mut transfer.info;
+                    match &status {
+                        InboxStatus::Receiving {
+                            received, speed, ..
+                        } => {
+                            info.progress = Some(TransferProgress {
+                                bytes: *received,
+                                total: None,
+                                speed: *speed,
+                                eta: None,
+                                path: PathKind::Web,
+                            });
+                        }
+                        InboxStatus::Received { .. } | InboxStatus::Rejected(_) => {
+                            info.progress = None;
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(tx) = &client {
+                    if tx.send(InboxUpdate { id, status }).await.is_err() {
+                        client = None;
+                    }
+                }
+            }
+        });
+        self.attach(id, AbortOnDropHandle::new(task));
+    }
+
+    /// Publishes a web link to the content of a ticket that keeps running even if the
+    /// client goes away.
+    async fn bridge(self, request: BridgeRequest, tx: mpsc::Sender<SendUpdate>) {
+        let id = self.next_id();
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::channel(10);
+        let tokio_handle = self.tokio_handle.clone();
+        let started =
+            crate::bridge_ticket(request.ticket, self.relay(), progress_tx, tokio_handle).await;
+        let (handle, name) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                let status = SendStatus::Error(format!("{:#}", e));
+                tx.send(SendUpdate { id, status }).await.ok();
+                return;
+            }
+        };
+        // The link is ready by the time the bridge has started.
+        let mut link = None;
+        while let Ok(status) = progress_rx.try_recv() {
+            if let SendStatus::ReadyToSend { ticket } = &status {
+                link = Some(ticket.clone());
+            }
+            tx.send(SendUpdate { id, status }).await.ok();
+        }
+        handle.upload_limit().set_rate(request.limit);
+        let info = TransferInfo {
+            id,
+            kind: TransferKind::Bridge,
+            path: PathBuf::from(name),
+            ticket: link,
+            share: None,
+            watch: false,
+            limit: request.limit,
+            progress: None,
+            summary: None,
+            sources: Vec::new(),
+        };
+        self.insert(info, handle.upload_limit().clone(), Some(handle));
+        self.attach(id, self.follow(id, progress_rx, Some(tx)));
+    }
+
+    /// Stops and removes persistent shares whose expiry time has passed.
+    async fn remove_expired_shares(&self) {
+        let expired = self
+            .list()
+            .into_iter()
+            .filter(|t| t.share.as_ref().is_some_and(|s| s.is_expired()));
+        for transfer in expired {
+            println!("Share {} has expired.", transfer.id);
+            if let Err(e) = self.stop(transfer.id).await {
+                println!("Failed to remove expired share {}: {:#}", transfer.id, e);
+            }
+        }
+    }
+
+    /// Stops a transfer. A persistent share is removed as well.
+    async fn stop(&self, id: u64) -> anyhow::Result<()> {
+        let transfer = self.transfers.lock().unwrap().remove(&id);
+        let Some(transfer) = transfer else {
+            bail!("No transfer with id {}", id);
+        };
+        if let Some(share) = &transfer.info.share {
+            crate::remove_share(&share.id).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Spawns the daemon actor in this process and returns a client talking to it directly.
+fn spawn_local(daemon: Daemon) -> Client<DaemonProtocol> {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(daemon.run(rx));
+    Client::local(tx)
+}
+
+fn ipc_dir() -> anyhow::Result<PathBuf> {
+    Ok(data_dir()?.join("ipc"))
+}
+
+/// Creates the directory holding the sockets, readable by the current user only.
+fn create_ipc_dir() -> anyhow::Result<PathBuf> {
+    let dir = ipc_dir()?;
+    cleanup::create_private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// Runs the daemon until it is told to shut down or receives Ctrl-C.
+/// It serves the control API on a socket in the app data dir.
+#[cfg(unix)]
+pub async fn run_daemon(relay: RelaySetting) -> anyhow::Result<()> {
+    if DaemonClient::connect().await.is_ok() {
+        bail!("A daemon is already running");
+    }
+    let dir = create_ipc_dir()?;
+    if let Err(e) = limit::load_global_limits() {
+        println!("Failed to load bandwidth limits: {:#}", e);
+    }
+    let daemon = Daemon::new(relay, TokioHandle::current());
+    daemon.restore_shares().await;
+    let shutdown = daemon.shutdown.clone();
+    let client = spawn_local(daemon);
+    let local = client.as_local().context("daemon client is not local")?;
+
+    let socket_path = dir.join("daemon.sock");
+    let (endpoint, cert) = super::ipc::server_endpoint(&socket_path)?;
+    std::fs::write(dir.join("daemon.cert"), cert)?;
+    let handler = DaemonProtocol::remote_handler(local);
+    let _server =
+        AbortOnDropHandle::new(tokio::spawn(irpc::rpc::listen(endpoint.clone(), handler)));
+    println!("Daemon listening on {}", socket_path.display());
+
+    let notified = shutdown.notified();
+    tokio::select! {
+        _ = notified => {}
+        _ = tokio::signal::ctrl_c() => {
+            client.rpc(ShutdownRequest).await?;
+        }
+    }
+    // Let the reply to a shutdown request reach the client before the connections are closed.
+    tokio::time::timeout(Duration::from_secs(1), endpoint.wait_idle())
+        .await
+        .ok();
+    endpoint.close(0u32.into(), b"daemon shut down");
+    println!("Daemon stopped.");
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn run_daemon(_relay: RelaySetting) -> anyhow::Result<()> {
+    bail!("The daemon needs Unix domain sockets, which this platform does not support")
+}
+
+/// A connection to the daemon, or to a daemon embedded in this process.
+#[derive(Clone)]
+pub struct DaemonClient {
+    client: Client<DaemonProtocol>,
+}
+
+impl DaemonClient {
+    /// Connects to the daemon running in the background.
+    #[cfg(unix)]
+    pub async fn connect() -> anyhow::Result<Self> {
+        let dir = ipc_dir()?;
+        let server_path = dir.join("daemon.sock");
+        anyhow::ensure!(server_path.exists(), "No daemon is running");
+        let cert = std::fs::read(dir.join("daemon.cert")).context("No daemon is running")?;
+        let id: [u8; 8] = rand::random();
+        let client_path = dir.join(format!("client-{}.sock", hex::encode(id)));
+        let (endpoint, addr) = super::ipc::client_endpoint(&client_path, &server_path, &cert)?;
+        let this = Self {
+            client: Client::quinn(endpoint, addr),
+        };
+        // A socket file left behind by a crashed daemon does not answer.
+        tokio::time::timeout(Duration::from_secs(2), this.list())
+            .await
+            .map_err(|_| anyhow::anyhow!("No daemon is running"))??;
+        Ok(this)
+    }
+
+    #[cfg(not(unix))]
+    pub async fn connect() -> anyhow::Result<Self> {
+        bail!("The daemon is not supported on this platform")
+    }
+
+    /// Starts a daemon inside this process, so transfers work without a background daemon.
+    /// They stop when [`DaemonClient::shutdown`] is called or the process exits.
+    pub async fn embedded(relay: RelaySetting) -> Self {
+        if let Err(e) = limit::load_global_limits() {
+            println!("Failed to load bandwidth limits: {:#}", e);
+        }
+        let daemon = Daemon::new(relay, TokioHandle::current());
+        daemon.restore_shares().await;
+        Self {
+            client: spawn_local(daemon),
+        }
+    }
+
+    /// Returns true if this talks to a daemon in another process.
+    pub fn is_remote(&self) -> bool {
+        self.client.as_local().is_none()
+    }
+
+    /// Lists all running transfers.
+    pub async fn list(&self) -> anyhow::Result<Vec<TransferInfo>> {
+        Ok(self.client.rpc(ListRequest).await?)
+    }
+
+    /// Starts a send. Progress ends with the ticket or an error.
+    pub async fn send(&self, request: SendRequest) -> anyhow::Result<mpsc::Receiver<SendUpdate>> {
+        Ok(self.client.server_streaming(request, 16).await?)
+    }
+
+    /// Starts a download. With `seed`, a [`ReceiveStatus::Seeding`] follows the
+    /// [`ReceiveStatus::Done`] once the download is shared again.
+    pub async fn receive(
+        &self,
+        request: ReceiveRequest,
+    ) -> anyhow::Result<mpsc::Receiver<ReceiveStatus>> {
+        Ok(self.client.server_streaming(request, 32).await?)
+    }
+
+    /// Publishes an upload page. Progress starts with the link and goes on with every
+    /// upload for as long as the receiver is kept.
+    pub async fn request_files(
+        &self,
+        request: InboxRequest,
+    ) -> anyhow::Result<mpsc::Receiver<InboxUpdate>> {
+        Ok(self.client.server_streaming(request, 32).await?)
+    }
+
+    /// Publishes a web link to the content of a ticket. Progress ends with the link; the
+    /// bridge keeps running when the receiver is dropped.
+    pub async fn bridge(
+        &self,
+        request: BridgeRequest,
+    ) -> anyhow::Result<mpsc::Receiver<SendUpdate>> {
+        Ok(self.client.server_streaming(request, 16).await?)
+    }
+
+    /// Stops a transfer. Stopping a persistent share removes it.
+    pub async fn stop(&self, id: u64) -> anyhow::Result<()> {
+        self.client
+            .rpc(StopRequest { id })
+            .await?
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Returns the global bandwidth limits.
+    pub async fn limits(&self) -> anyhow::Result<BandwidthLimits> {
+        Ok(self.client.rpc(LimitsRequest).await?)
+    }
+
+    /// Changes the global bandwidth limits. They apply to running transfers right away
+    /// and are kept for the next start.
+    pub async fn set_limits(&self, limits: BandwidthLimits) -> anyhow::Result<()> {
+        self.client
+            .rpc(SetLimitsRequest { limits })
+            .await?
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Changes the limit of a running transfer, in bytes per second.
+    pub async fn set_transfer_limit(&self, id: u64, rate: Option<u64>) -> anyhow::Result<()> {
+        self.client
+            .rpc(SetTransferLimitRequest { id, rate })
+            .await?
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Returns the relay setting the daemon's node runs with.
+    pub async fn relay(&self) -> anyhow::Result<RelaySetting> {
+        Ok(self.client.rpc(RelayRequest).await?)
+    }
+
+    /// Restarts the daemon's node with another relay setting. Running sends, downloads and
+    /// bridges stop; persistent shares are announced again.
+    pub async fn set_relay(&self, relay: RelaySetting) -> anyhow::Result<()> {
+        self.client
+            .rpc(SetRelayRequest { relay })
+            .await?
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Reports the connectivity of the daemon's node.
+    pub async fn diagnostics(&self) -> anyhow::Result<Diagnostics> {
+        self.client
+            .rpc(DiagnosticsRequest)
+            .await?
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// Stops all transfers and the daemon.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        Ok(self.client.rpc(ShutdownRequest).await?)
+    }
+}
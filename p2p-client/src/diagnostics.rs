@@ -0,0 +1,224 @@
+//! Connection diagnostics: how the node can be reached and how it talks to the peers of
+//! running transfers.
+
+use super::node::Node;
+use super::stats::PathKind;
+use iroh::{endpoint::ConnectionType, EndpointId, RelayUrl, Watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How long to wait for the first network report of a node that just started.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the network in front of the node does to its UDP traffic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NatKind {
+    /// No UDP packets got through, so all traffic goes through the relay.
+    NoUdp,
+    /// The public address is the same for every server; direct connections usually work.
+    Easy,
+    /// The public address changes with the server; direct connections often fail.
+    Hard,
+    /// No relay answered, or not enough probes have finished yet.
+    #[default]
+    Unknown,
+}
+
+impl NatKind {
+    /// The NAT probes go through the relays, so nothing is known until one answered.
+    fn from_report(reached_relay: bool, has_udp: bool, mapping_varies: Option<bool>) -> Self {
+        match (reached_relay, has_udp, mapping_varies) {
+            (false, _, _) => NatKind::Unknown,
+            (true, false, _) => NatKind::NoUdp,
+            (true, true, Some(false)) => NatKind::Easy,
+            (true, true, Some(true)) => NatKind::Hard,
+            (true, true, None) => NatKind::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for NatKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NatKind::NoUdp => "UDP blocked, relay only",
+            NatKind::Easy => "easy, direct connections should work",
+            NatKind::Hard => "hard, direct connections may fail",
+            NatKind::Unknown => "unknown",
+        })
+    }
+}
+
+/// The connection to the peer of a running transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerConnection {
+    pub peer: EndpointId,
+    pub path: PathKind,
+    /// The peer's UDP address on a direct path.
+    pub remote_addr: Option<SocketAddr>,
+    /// The relay carrying the traffic on a relayed or mixed path.
+    pub relay: Option<RelayUrl>,
+    pub latency: Option<Duration>,
+}
+
+/// A snapshot of the node's connectivity.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostics {
+    pub node_id: EndpointId,
+    /// The local sockets the endpoint is bound to.
+    pub bound_sockets: Vec<SocketAddr>,
+    /// The addresses other nodes can try to reach this one at directly.
+    pub direct_addresses: Vec<SocketAddr>,
+    /// The addresses the relays saw this node's traffic come from.
+    pub public_ipv4: Option<SocketAddr>,
+    pub public_ipv6: Option<SocketAddr>,
+    pub nat: NatKind,
+    pub home_relay: Option<RelayUrl>,
+    /// The fastest probe to the home relay.
+    pub relay_latency: Option<Duration>,
+    /// True if HTTP traffic seems to be intercepted, e.g. by a hotel login page.
+    pub captive_portal: Option<bool>,
+    /// Connections of the transfers currently running.
+    pub connections: Vec<PeerConnection>,
+}
+
+/// The peers the node is transferring data with, counted by transfer.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ActivePeers {
+    counts: Arc<Mutex<HashMap<EndpointId, usize>>>,
+}
+
+impl ActivePeers {
+    /// Counts `peer` as active until the returned guard is dropped.
+    pub(crate) fn track(&self, peer: EndpointId) -> ActivePeer {
+        *self.counts.lock().unwrap().entry(peer).or_default() += 1;
+        ActivePeer {
+            peers: self.clone(),
+            peer,
+        }
+    }
+
+    fn list(&self) -> Vec<EndpointId> {
+        self.counts.lock().unwrap().keys().copied().collect()
+    }
+}
+
+/// Keeps a peer in [`ActivePeers`].
+#[derive(Debug)]
+pub(crate) struct ActivePeer {
+    peers: ActivePeers,
+    peer: EndpointId,
+}
+
+impl Drop for ActivePeer {
+    fn drop(&mut self) {
+        let mut counts = self.peers.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Looks at the connectivity of `node`. A node that just started is given a few
+/// seconds to finish its first network report.
+pub(crate) async fn diagnose(node: &Node) -> Diagnostics {
+    let endpoint = node.endpoint();
+    let mut report = endpoint.net_report();
+    let report = tokio::time::timeout(REPORT_TIMEOUT, report.initialized())
+        .await
+        .ok();
+    let addr = endpoint.addr();
+    let home_relay = addr.relay_urls().next().cloned();
+    let relay_latency = report.as_ref().and_then(|report| {
+        report
+            .relay_latency
+            .iter()
+            .filter(|(_, url, _)| Some(*url) == home_relay.as_ref())
+            .map(|(_, _, latency)| latency)
+            .min()
+    });
+
+    let mut connections: Vec<PeerConnection> = node
+        .active_peers()
+        .list()
+        .into_iter()
+        .map(|peer| {
+            let conn_type = endpoint.conn_type(peer).map(|mut watcher| watcher.get());
+            let (remote_addr, relay) = match conn_type {
+                Some(ConnectionType::Direct(addr)) => (Some(addr), None),
+                Some(ConnectionType::Relay(url)) => (None, Some(url)),
+                Some(ConnectionType::Mixed(addr, url)) => (Some(addr), Some(url)),
+                _ => (None, None),
+            };
+            PeerConnection {
+                peer,
+                path: PathKind::of(endpoint, peer),
+                remote_addr,
+                relay,
+                latency: endpoint.latency(peer),
+            }
+        })
+        .collect();
+    connections.sort_by_key(|c| c.peer);
+
+    Diagnostics {
+        node_id: endpoint.id(),
+        bound_sockets: endpoint.bound_sockets(),
+        direct_addresses: addr.ip_addrs().copied().collect(),
+        public_ipv4: report
+            .as_ref()
+            .and_then(|r| r.global_v4)
+            .map(SocketAddr::V4),
+        public_ipv6: report
+            .as_ref()
+            .and_then(|r| r.global_v6)
+            .map(SocketAddr::V6),
+        nat: report
+            .as_ref()
+            .map(|r| {
+                let reached_relay = r.relay_latency.iter().next().is_some();
+                NatKind::from_report(reached_relay, r.has_udp(), r.mapping_varies_by_dest())
+            })
+            .unwrap_or_default(),
+        home_relay,
+        relay_latency,
+        captive_portal: report.as_ref().and_then(|r| r.captive_portal),
+        connections,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nat_kind() {
+        assert_eq!(NatKind::from_report(true, false, None), NatKind::NoUdp);
+        assert_eq!(NatKind::from_report(true, true, Some(false)), NatKind::Easy);
+        assert_eq!(NatKind::from_report(true, true, Some(true)), NatKind::Hard);
+        assert_eq!(NatKind::from_report(true, true, None), NatKind::Unknown);
+        // Offline, failed probes say nothing about UDP.
+        assert_eq!(NatKind::from_report(false, false, None), NatKind::Unknown);
+    }
+
+    #[test]
+    fn test_active_peers() {
+        let peers = ActivePeers::default();
+        let peer = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let first = peers.track(peer);
+        let second = peers.track(peer);
+        assert_eq!(peers.list(), vec![peer]);
+        drop(first);
+        assert_eq!(peers.list(), vec![peer]);
+        drop(second);
+        assert!(peers.list().is_empty());
+    }
+}
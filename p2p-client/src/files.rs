@@ -1,4 +1,5 @@
 use super::state::{ReceiveStatus, SendStatus};
+use super::store::HashCache;
 use anyhow::{bail, Context};
 use iroh_blobs::{
     api::{
@@ -6,7 +7,7 @@ use iroh_blobs::{
         Store, TempTag,
     },
     format::collection::Collection,
-    BlobFormat,
+    BlobFormat, Hash, HashAndFormat,
 };
 use n0_future::StreamExt;
 use std::path::{Component, Path, PathBuf};
@@ -15,6 +16,7 @@ use walkdir::WalkDir;
 
 /// Walks the given path, imports all files into the Iroh store, and creates a "collection".
 /// A collection is a single hash that represents a group of files.
+/// Files that are unchanged since an earlier import and still in the store are not hashed again.
 pub(crate) async fn import(
     path: &Path,
     db: &Store,
@@ -39,6 +41,7 @@ pub(crate) async fn import(
         .iter()
         .map(|(_, p)| p.metadata().map(|m| m.len()).unwrap_or(0))
         .sum();
+    let mut cache = HashCache::load()?;
     let mut names_and_tags = Vec::new();
     let mut done_files = 0;
     let mut done_size = 0;
@@ -51,8 +54,19 @@ pub(crate) async fn import(
                 done_size,
             })
             .await?;
+        let metadata = path.metadata()?;
+        if let Some(hash) = cache.lookup(&path, &metadata) {
+            // Protect the blob before checking for it, so GC can not remove it in between.
+            let temp_tag = db.tags().temp_tag(HashAndFormat::raw(hash)).await?;
+            if db.blobs().has(hash).await? {
+                done_files += 1;
+                done_size += metadata.len();
+                names_and_tags.push((name, temp_tag, metadata.len()));
+                continue;
+            }
+        }
         let import = db.add_path_with_opts(AddPathOptions {
-            path,
+            path: path.clone(),
             mode: ImportMode::TryReference,
             format: BlobFormat::Raw,
         });
@@ -70,10 +84,14 @@ pub(crate) async fn import(
                 _ => {}
             }
         };
+        cache.insert(path, &metadata, temp_tag.hash());
         done_files += 1;
         done_size += item_size;
         names_and_tags.push((name, temp_tag, item_size));
     }
+    if let Err(e) = cache.save() {
+        println!("Failed to save hash cache: {}", e);
+    }
     names_and_tags.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
     let size = names_and_tags.iter().map(|(_, _, size)| *size).sum::<u64>();
     let (collection, tags) = names_and_tags
@@ -85,13 +103,13 @@ pub(crate) async fn import(
     Ok((temp_tag, size, collection))
 }
 
-/// Exports files from an Iroh collection to the local filesystem.
+/// Exports files from an Iroh collection into the `root` directory.
 pub(crate) async fn export(
     db: &Store,
     collection: Collection,
+    root: &Path,
     progress: mpsc::Sender<ReceiveStatus>,
 ) -> anyhow::Result<()> {
-    let root = std::env::current_dir()?;
     let total_files = collection.len() as u64;
     for (i, (name, hash)) in collection.iter().enumerate() {
         progress
@@ -100,7 +118,7 @@ pub(crate) async fn export(
                 done_files: i as u64,
             })
             .await?;
-        let target = get_export_path(&root, name)?;
+        let target = get_export_path(root, name)?;
         if target.exists() {
             bail!(
                 "target {} already exists. Please remove it and try again.",
@@ -110,27 +128,34 @@ pub(crate) async fn export(
         if let Some(parent) = target.parent() {
             tokio::fs::create_dir_all(parent).await?;
         }
-        let mut stream = db
-            .export_with_opts(ExportOptions {
-                hash: *hash,
-                target,
-                mode: ExportMode::Copy,
-            })
-            .stream()
-            .await;
-        while let Some(item) = stream.next().await {
-            if let ExportProgressItem::Error(cause) = item {
-                bail!("error exporting {}: {}", name, cause);
-            }
+        export_blob(db, *hash, target)
+            .await
+            .with_context(|| format!("error exporting {}", name))?;
+    }
+    Ok(())
+}
+
+/// Copies a single blob out of the store into the file `target`.
+pub(crate) async fn export_blob(db: &Store, hash: Hash, target: PathBuf) -> anyhow::Result<()> {
+    let mut stream = db
+        .export_with_opts(ExportOptions {
+            hash,
+            target,
+            mode: ExportMode::Copy,
+        })
+        .stream()
+        .await;
+    while let Some(item) = stream.next().await {
+        if let ExportProgressItem::Error(cause) = item {
+            bail!(cause.to_string());
         }
     }
-    progress.send(ReceiveStatus::Done).await?;
     Ok(())
 }
 
 /// Safely constructs a valid export path from a root directory and a relative file name.
 /// Prevents path traversal attacks (e.g., names like "../../../../etc/passwd").
-fn get_export_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
+pub(crate) fn get_export_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
     let mut path = root.to_path_buf();
     for part in name.split('/') {
         anyhow::ensure!(
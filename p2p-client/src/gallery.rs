@@ -0,0 +1,275 @@
+//! Galleries of folders shared as a web link. The page shows a grid of thumbnails for the
+//! images and plays videos and audio in the browser, which fetches them with range
+//! requests, so recipients can browse a shoot without downloading all of it. Every other
+//! file is listed with a download link. Thumbnails are made when first asked for and kept
+//! for as long as the link is up.
+
+use super::mime;
+use anyhow::{bail, Context};
+use axum::{
+    body::Bytes,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use iroh_blobs::{
+    api::{proto::BlobStatus, Store},
+    format::collection::Collection,
+    Hash,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::Cursor,
+    sync::{Arc, Mutex},
+};
+use tokio::{io::AsyncReadExt, sync::Semaphore};
+
+/// The page that shows the gallery.
+pub(crate) const PAGE: &str = include_str!("gallery.html");
+
+/// The longest side of a thumbnail, in pixels.
+const THUMBNAIL_SIZE: u32 = 320;
+
+/// Images larger than this are not decoded for a thumbnail; the page shows their name.
+const MAX_THUMBNAIL_SOURCE: u64 = 64 * 1024 * 1024;
+
+/// How many thumbnails are made at once, as decoding a photo takes a lot of memory.
+const THUMBNAIL_WORKERS: usize = 2;
+
+/// How the page shows a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MediaKind {
+    Image,
+    Video,
+    Audio,
+    Other,
+}
+
+impl MediaKind {
+    fn of(content_type: &str) -> Self {
+        if !mime::is_previewable(content_type) {
+            return MediaKind::Other;
+        }
+        match content_type.split('/').next() {
+            Some("image") => MediaKind::Image,
+            Some("video") => MediaKind::Video,
+            Some("audio") => MediaKind::Audio,
+            _ => MediaKind::Other,
+        }
+    }
+}
+
+/// A file of the gallery, as the page lists it.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct GalleryEntry {
+    pub name: String,
+    pub hash: Hash,
+    pub size: u64,
+    pub kind: MediaKind,
+    /// True if the server can make a thumbnail of the image.
+    pub thumbnail: bool,
+}
+
+impl GalleryEntry {
+    /// Describes the file `name` of `size` bytes, telling its type from its name and
+    /// `head`, its first bytes if known.
+    pub(crate) fn new(name: String, hash: Hash, size: u64, head: &[u8]) -> Self {
+        let content_type = mime::content_type(&name, head);
+        let kind = MediaKind::of(content_type);
+        Self {
+            name,
+            hash,
+            size,
+            kind,
+            thumbnail: kind == MediaKind::Image
+                && has_decoder(content_type)
+                && size <= MAX_THUMBNAIL_SOURCE,
+        }
+    }
+}
+
+/// A folder served as a gallery.
+#[derive(Clone)]
+pub(crate) struct Gallery {
+    /// The hash of the collection, which names the gallery in the link.
+    pub id: Hash,
+    pub entries: Arc<Vec<GalleryEntry>>,
+    thumbnails: Arc<Mutex<HashMap<Hash, Bytes>>>,
+    workers: Arc<Semaphore>,
+}
+
+impl Gallery {
+    /// A gallery of `entries`, named by `id`, the hash of their collection.
+    pub(crate) fn new(id: Hash, entries: Vec<GalleryEntry>) -> Self {
+        Self {
+            id,
+            entries: Arc::new(entries),
+            thumbnails: Default::default(),
+            workers: Arc::new(Semaphore::new(THUMBNAIL_WORKERS)),
+        }
+    }
+
+    /// Lists the files of `collection`, whose hash is `id`, telling their type from their
+    /// names and first bytes.
+    pub(crate) async fn load(
+        db: &Store,
+        id: Hash,
+        collection: &Collection,
+    ) -> anyhow::Result<Self> {
+        let mut entries = Vec::with_capacity(collection.len());
+        for (name, hash) in collection.iter() {
+            let size = match db.blobs().status(*hash).await? {
+                BlobStatus::Complete { size } => size,
+                _ => bail!("{} is no longer in the store", name),
+            };
+            let mut head = Vec::new();
+            db.reader(*hash)
+                .take(mime::SNIFF_LEN as u64)
+                .read_to_end(&mut head)
+                .await?;
+            entries.push(GalleryEntry::new(name.clone(), *hash, size, &head));
+        }
+        Ok(Self::new(id, entries))
+    }
+
+    /// A JPEG thumbnail of the image `hash`, or `None` if the gallery has no thumbnail of it.
+    /// `data` reads the image if the thumbnail has not been made yet.
+    pub(crate) async fn thumbnail(
+        &self,
+        hash: Hash,
+        data: impl Future<Output = anyhow::Result<Bytes>>,
+    ) -> anyhow::Result<Option<Bytes>> {
+        if !self
+            .entries
+            .iter()
+            .any(|entry| entry.hash == hash && entry.thumbnail)
+        {
+            return Ok(None);
+        }
+        if let Some(thumbnail) = self.cached(hash) {
+            return Ok(Some(thumbnail));
+        }
+        let _permit = self.workers.acquire().await?;
+        // Another request may have made it in the meantime.
+        if let Some(thumbnail) = self.cached(hash) {
+            return Ok(Some(thumbnail));
+        }
+        let data = data.await?;
+        let thumbnail = tokio::task::spawn_blocking(move || make_thumbnail(&data)).await??;
+        let thumbnail = Bytes::from(thumbnail);
+        self.thumbnails
+            .lock()
+            .unwrap()
+            .insert(hash, thumbnail.clone());
+        Ok(Some(thumbnail))
+    }
+
+    fn cached(&self, hash: Hash) -> Option<Bytes> {
+        self.thumbnails.lock().unwrap().get(&hash).cloned()
+    }
+
+    /// The answer to a request for the page of the gallery.
+    pub(crate) fn page_response(&self) -> Response {
+        (
+            [
+                (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+                (header::REFERRER_POLICY, "no-referrer"),
+            ],
+            PAGE,
+        )
+            .into_response()
+    }
+
+    /// The answer to a request for the list of files of the gallery.
+    pub(crate) fn files_response(&self) -> Response {
+        Json(self.entries.as_ref().clone()).into_response()
+    }
+
+    /// The answer to a request for the thumbnail of the image `hash_str`, which `data`
+    /// reads if the thumbnail has not been made yet.
+    pub(crate) async fn thumbnail_response<F>(
+        &self,
+        hash_str: &str,
+        data: impl FnOnce(Hash) -> F,
+    ) -> Response
+    where
+        F: Future<Output = anyhow::Result<Bytes>>,
+    {
+        let hash = match hash_str.parse::<Hash>() {
+            Ok(h) => h,
+            Err(_) => return (StatusCode::BAD_REQUEST, "Invalid hash format").into_response(),
+        };
+        match self.thumbnail(hash, data(hash)).await {
+            Ok(Some(thumbnail)) => (
+                [
+                    (header::CONTENT_TYPE, "image/jpeg"),
+                    // The image behind the hash never changes.
+                    (header::CACHE_CONTROL, "max-age=86400, immutable"),
+                ],
+                thumbnail,
+            )
+                .into_response(),
+            Ok(None) => (StatusCode::NOT_FOUND, "Not found").into_response(),
+            Err(e) => {
+                println!("Failed to make a thumbnail of {}: {}", hash.fmt_short(), e);
+                (StatusCode::INTERNAL_SERVER_ERROR, "No thumbnail").into_response()
+            }
+        }
+    }
+}
+
+/// True if thumbnails can be made of images of `content_type`.
+fn has_decoder(content_type: &str) -> bool {
+    matches!(
+        content_type,
+        "image/png" | "image/jpeg" | "image/gif" | "image/bmp" | "image/webp"
+    )
+}
+
+/// Scales the image `data` down to a JPEG of at most [`THUMBNAIL_SIZE`] pixels on each
+/// side, turned upright as its EXIF orientation says.
+fn make_thumbnail(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()
+        .context("Unknown image format")?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder).context("Failed to decode image")?;
+    image.apply_orientation(orientation);
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+    let mut jpeg = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut jpeg, ImageFormat::Jpeg)?;
+    Ok(jpeg.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbaImage};
+
+    #[test]
+    fn test_media_kind() {
+        assert_eq!(MediaKind::of("image/jpeg"), MediaKind::Image);
+        assert_eq!(MediaKind::of("video/mp4"), MediaKind::Video);
+        assert_eq!(MediaKind::of("audio/flac"), MediaKind::Audio);
+        assert_eq!(MediaKind::of("image/svg+xml"), MediaKind::Other);
+        assert_eq!(MediaKind::of("application/pdf"), MediaKind::Other);
+    }
+
+    #[test]
+    fn test_make_thumbnail() {
+        let image = RgbaImage::from_pixel(1000, 500, image::Rgba([10, 200, 30, 128]));
+        let mut png = Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+
+        let thumbnail = make_thumbnail(png.get_ref()).unwrap();
+        let thumbnail = image::load_from_memory_with_format(&thumbnail, ImageFormat::Jpeg).unwrap();
+        assert_eq!(thumbnail.dimensions(), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+
+        assert!(make_thumbnail(b"not an image").is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! The transfer history: every finished send and receive is appended as one JSON line
+//! to `history.jsonl` in the app data dir.
+
+use super::config::data_dir;
+use super::pointer::PointerTicket;
+use super::stats::TransferSummary;
+use super::sync::SyncOptions;
+use iroh::EndpointId;
+use iroh_blobs::{
+    api::{proto::BlobStatus, Store},
+    format::collection::Collection,
+    ticket::BlobTicket,
+    Hash,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Serializes access to the history file within this process.
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryKind {
+    Send,
+    Receive,
+}
+
+/// One file of a transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryFile {
+    pub name: String,
+    pub size: u64,
+    pub hash: Hash,
+}
+
+/// How a transfer ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferOutcome {
+    Completed(TransferSummary),
+    Failed(String),
+    /// A send that was stopped before anyone fetched it.
+    Cancelled,
+}
+
+/// A finished send to one receiver, or a finished receive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When the transfer ended, in seconds since the Unix epoch.
+    pub time: u64,
+    pub kind: HistoryKind,
+    /// Node id of the other side. Unknown for web links.
+    pub peer: Option<EndpointId>,
+    /// The file or folder that was sent, or the folder received into.
+    pub path: PathBuf,
+    /// The ticket or web link of the transfer.
+    pub ticket: Option<String>,
+    /// Hash of the collection, or of the file behind a web link.
+    pub hash: Option<Hash>,
+    #[serde(default)]
+    pub files: Vec<HistoryFile>,
+    /// The sync options of a receive, so that it can be retried the same way.
+    #[serde(default)]
+    pub sync: Option<SyncOptions>,
+    pub outcome: TransferOutcome,
+}
+
+impl HistoryEntry {
+    pub(crate) fn new(kind: HistoryKind, path: PathBuf, outcome: TransferOutcome) -> Self {
+        Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            kind,
+            peer: None,
+            path,
+            ticket: None,
+            hash: None,
+            files: Vec::new(),
+            sync: None,
+            outcome,
+        }
+    }
+
+    /// The size of all files of the transfer.
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self.outcome, TransferOutcome::Failed(_))
+    }
+
+    /// The file or folder a receive created in the folder it received into.
+    pub fn received_path(&self) -> PathBuf {
+        match self.files.first() {
+            Some(file) => self
+                .path
+                .join(file.name.split('/').next().unwrap_or_default()),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// Tells whether anyone fetched a send. Shared between the send and what serves it, so
+/// that a send nobody fetched can be recorded as [`TransferOutcome::Cancelled`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FetchFlag(Arc<AtomicBool>);
+
+impl FetchFlag {
+    pub(crate) fn set(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+fn history_file() -> anyhow::Result<PathBuf> {
+    Ok(data_dir()?.join("history.jsonl"))
+}
+
+fn append_to(path: &Path, entry: &HistoryEntry) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Reads the history. Lines that cannot be parsed, e.g. the last one after a crash
+/// while it was written, are skipped.
+fn read_from(path: &Path) -> anyhow::Result<Vec<HistoryEntry>> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(data
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Appends `entry` to the history. A history that cannot be written does not fail
+/// the transfer, so errors are only reported.
+pub(crate) fn record(entry: &HistoryEntry) {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    if let Err(e) = history_file().and_then(|path| append_to(&path, entry)) {
+        println!("Failed to record the transfer in the history: {}", e);
+    }
+}
+
+/// Returns the recorded transfers, oldest first.
+pub fn history() -> anyhow::Result<Vec<HistoryEntry>> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    read_from(&history_file()?)
+}
+
+/// Deletes all recorded transfers.
+pub fn clear_history() -> anyhow::Result<()> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    match std::fs::remove_file(history_file()?) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The files of `collection`, with the sizes of those that are in `store`.
+pub(crate) async fn collection_files(store: &Store, collection: &Collection) -> Vec<HistoryFile> {
+    let mut files = Vec::new();
+    for (name, hash) in collection.iter() {
+        let size = match store.blobs().status(*hash).await {
+            Ok(BlobStatus::Complete { size }) => size,
+            Ok(BlobStatus::Partial { size }) => size.unwrap_or_default(),
+            _ => 0,
+        };
+        files.push(HistoryFile {
+            name: name.clone(),
+            size,
+            hash: *hash,
+        });
+    }
+    files
+}
+
+/// The sender and content of a ticket, as far as they are known without contacting
+/// the sender.
+pub(crate) fn ticket_origin(ticket: &str) -> (Option<EndpointId>, Option<Hash>) {
+    if let Ok(ticket) = BlobTicket::from_str(ticket) {
+        (Some(ticket.addr().id), Some(ticket.hash()))
+    } else if let Ok(ticket) = PointerTicket::from_str(ticket) {
+        (Some(ticket.sender()), None)
+    } else {
+        (None, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::PathKind;
+    use std::time::Duration;
+
+    #[test]
+    fn test_history_file_roundtrip() {
+        let dir = std::env::temp_dir().join(format!(
+            "p2p-client-test-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let path = dir.join("history.jsonl");
+        assert!(read_from(&path).unwrap().is_empty());
+
+        let summary = TransferSummary {
+            bytes: 1000,
+            duration: Duration::from_secs(2),
+            path: PathKind::Direct,
+        };
+        let mut sent = HistoryEntry::new(
+            HistoryKind::Send,
+            PathBuf::from("/photos"),
+            TransferOutcome::Completed(summary),
+        );
+        sent.files.push(HistoryFile {
+            name: "a.jpg".to_string(),
+            size: 1000,
+            hash: Hash::new(b"a"),
+        });
+        let failed = HistoryEntry::new(
+            HistoryKind::Receive,
+            PathBuf::from("/downloads"),
+            TransferOutcome::Failed("gone".to_string()),
+        );
+        append_to(&path, &sent).unwrap();
+        append_to(&path, &failed).unwrap();
+        // A line cut short by a crash does not hide the others.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"time\":")
+            .unwrap();
+
+        let entries = read_from(&path).unwrap();
+        assert_eq!(entries, vec![sent, failed]);
+        assert_eq!(entries[0].total_size(), 1000);
+        assert!(entries[1].is_failed());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_received_path() {
+        let mut entry = HistoryEntry::new(
+            HistoryKind::Receive,
+            PathBuf::from("/downloads"),
+            TransferOutcome::Failed("gone".to_string()),
+        );
+        assert_eq!(entry.received_path(), PathBuf::from("/downloads"));
+        for name in ["photos/2024/a.jpg", "photos/b.jpg"] {
+            entry.files.push(HistoryFile {
+                name: name.to_string(),
+                size: 1,
+                hash: Hash::new(name),
+            });
+        }
+        assert_eq!(entry.received_path(), PathBuf::from("/downloads/photos"));
+    }
+}
@@ -0,0 +1,446 @@
+//! File requests: a web page behind an ngrok tunnel where anyone with the link can
+//! upload files to this device. Uploads stream into the blob store, so they are hashed
+//! as they arrive, and are then exported into the folder the files were requested into.
+
+use super::files::get_export_path;
+use super::history::{self, HistoryEntry, HistoryFile, HistoryKind, TransferOutcome};
+use super::limit::{self, RateLimiter};
+use super::state::InboxStatus;
+use super::stats::{PathKind, Throughput};
+use super::store;
+use super::web;
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Multipart, Path as AxumPath, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use bytesize::ByteSize;
+use futures::SinkExt;
+use iroh_blobs::api::Store;
+use ngrok::forwarder::Forwarder;
+use ngrok::tunnel::{EndpointInfo, HttpTunnel, TunnelCloser};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{runtime::Handle as TokioHandle, sync::mpsc};
+
+/// The page that uploads files from the browser.
+const PAGE: &str = include_str!("inbox.html");
+
+/// How long a wrong password holds up the answer, to slow down guessing.
+const WRONG_PASSWORD_DELAY: Duration = Duration::from_secs(1);
+
+/// What uploads a file request accepts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InboxOptions {
+    /// The largest file accepted, in bytes.
+    pub max_file_size: Option<u64>,
+    /// How many bytes are accepted over all uploads.
+    pub max_total_size: Option<u64>,
+    /// Required to upload, if set.
+    pub password: Option<String>,
+}
+
+/// A handle to a running file request. Dropping it takes the page offline.
+pub struct InboxHandle {
+    shutdown_tx: Option<tokio::sync::oneshot::Sender<()>>,
+    tunnel: Option<Forwarder<HttpTunnel>>,
+    tokio_handle: TokioHandle,
+}
+
+impl Drop for InboxHandle {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            let _ = tx.send(());
+        }
+        if let Some(mut tunnel) = self.tunnel.take() {
+            self.tokio_handle.spawn(async move {
+                let _ = tunnel.close().await;
+                println!("Ngrok tunnel closed.");
+            });
+        }
+        println!("File request stopped.");
+    }
+}
+
+/// What the page asks before uploading.
+#[derive(Debug, Serialize)]
+struct PageInfo {
+    password: bool,
+    max_file_size: Option<u64>,
+    /// What is left of the total size limit.
+    remaining: Option<u64>,
+}
+
+#[derive(Clone)]
+struct InboxState {
+    /// Names the request in the link, so that the bare tunnel address is not enough.
+    id: String,
+    db: Store,
+    target_dir: PathBuf,
+    options: Arc<InboxOptions>,
+    /// Bytes taken on against `max_total_size`, including uploads still running.
+    accepted: Arc<AtomicU64>,
+    download_limit: RateLimiter,
+    progress: mpsc::Sender<InboxStatus>,
+}
+
+/// Why an upload was refused, answered to the browser.
+type Rejection = (StatusCode, String);
+
+/// Publishes an upload page through ngrok. Uploaded files are saved into `target_dir`.
+pub(crate) async fn start(
+    target_dir: PathBuf,
+    options: InboxOptions,
+    download_limit: RateLimiter,
+    progress: mpsc::Sender<InboxStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<InboxHandle> {
+    progress.send(InboxStatus::Connecting).await?;
+    anyhow::ensure!(
+        target_dir.is_dir(),
+        "{} is not a folder",
+        target_dir.display()
+    );
+    let state = InboxState {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        db: store::shared_store().await?,
+        target_dir,
+        options: Arc::new(options),
+        accepted: Default::default(),
+        download_limit,
+        progress: progress.clone(),
+    };
+    let id = state.id.clone();
+    let app = Router::new()
+        .route("/r/{id}", get(page_handler))
+        .route("/r/{id}/info", get(info_handler))
+        .route("/r/{id}/upload", post(upload_handler))
+        // The size limits are checked while the files stream in.
+        .layer(DefaultBodyLimit::disable())
+        .with_state(state);
+
+    let (shutdown_tx, tun) = web::open_tunnel(app).await?;
+    let link = format!("{}/r/{}", tun.url(), id);
+    println!("Upload page started at: {}", link);
+    progress.send(InboxStatus::Ready { link }).await?;
+    Ok(InboxHandle {
+        shutdown_tx: Some(shutdown_tx),
+        tunnel: Some(tun),
+        tokio_handle,
+    })
+}
+
+async fn page_handler(
+    State(state): State<InboxState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    if id != state.id {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    (
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        PAGE,
+    )
+        .into_response()
+}
+
+async fn info_handler(
+    State(state): State<InboxState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    if id != state.id {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    let accepted = state.accepted.load(Ordering::Relaxed);
+    Json(PageInfo {
+        password: state.options.password.is_some(),
+        max_file_size: state.options.max_file_size,
+        remaining: state
+            .options
+            .max_total_size
+            .map(|max| max.saturating_sub(accepted)),
+    })
+    .into_response()
+}
+
+/// Axum handler for a form with an optional `password` field followed by the files.
+async fn upload_handler(
+    State(state): State<InboxState>,
+    AxumPath(id): AxumPath<String>,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    if id != state.id {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    let mut throughput = Throughput::new();
+    let mut received = 0;
+    let mut files = Vec::new();
+    let result = receive_files(
+        &state,
+        &mut multipart,
+        &mut throughput,
+        &mut received,
+        &mut files,
+    )
+    .await;
+    // Files saved before a later one was refused are kept.
+    if !files.is_empty() {
+        let summary = throughput.summary(received, PathKind::Web);
+        let mut entry = HistoryEntry::new(
+            HistoryKind::Receive,
+            state.target_dir.clone(),
+            TransferOutcome::Completed(summary),
+        );
+        entry.files = files;
+        history::record(&entry);
+    }
+    match result {
+        Ok(()) => (StatusCode::OK, "Uploaded").into_response(),
+        Err((status, message)) => {
+            println!("Upload refused: {}", message);
+            let rejected = InboxStatus::Rejected(message.clone());
+            state.progress.send(rejected).await.ok();
+            (status, message).into_response()
+        }
+    }
+}
+
+async fn receive_files(
+    state: &InboxState,
+    multipart: &mut Multipart,
+    throughput: &mut Throughput,
+    received: &mut u64,
+    files: &mut Vec<HistoryFile>,
+) -> Result<(), Rejection> {
+    let mut authorized = state.options.password.is_none();
+    while let Some(mut field) = multipart.next_field().await.map_err(bad_request)? {
+        if field.name() == Some("password") {
+            let given = field.text().await.map_err(bad_request)?;
+            authorized = password_matches(state.options.password.as_deref(), &given);
+            continue;
+        }
+        if !authorized {
+            tokio::time::sleep(WRONG_PASSWORD_DELAY).await;
+            return Err((StatusCode::UNAUTHORIZED, "Wrong password".to_string()));
+        }
+        let name = field
+            .file_name()
+            .and_then(upload_file_name)
+            .ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    "A file has no valid name".to_string(),
+                )
+            })?;
+
+        // The store reads the file from a channel, as it only takes owned streams.
+        let (mut tx, rx) = futures::channel::mpsc::channel::<io::Result<Bytes>>(4);
+        let import = async {
+            state
+                .db
+                .add_stream(rx)
+                .await
+                .temp_tag()
+                .await
+                .map_err(internal_error)
+        };
+        let mut size = 0;
+        let stream_in = async {
+            let result = async {
+                while let Some(chunk) = field.chunk().await.map_err(bad_request)? {
+                    let len = chunk.len() as u64;
+                    accept(state, &name, size + len, len)?;
+                    size += len;
+                    limit::throttle_download(&state.download_limit, len).await;
+                    *received += len;
+                    if throughput.update(*received) {
+                        let status = InboxStatus::Receiving {
+                            name: name.clone(),
+                            received: *received,
+                            speed: throughput.speed(),
+                        };
+                        state.progress.send(status).await.ok();
+                    }
+                    if tx.send(Ok(chunk)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            .await;
+            // Ends the import. What the store has of a refused file is not tagged and is
+            // removed by its garbage collection; the store never finishes an import that
+            // is sent an error.
+            drop(tx);
+            result
+        };
+        let (temp_tag, streamed) = tokio::join!(import, stream_in);
+        let temp_tag = match (streamed, temp_tag) {
+            (Ok(()), Ok(temp_tag)) => temp_tag,
+            (Err(rejection), _) | (_, Err(rejection)) => {
+                state.accepted.fetch_sub(size, Ordering::Relaxed);
+                return Err(rejection);
+            }
+        };
+
+        // A file that is not saved does not count against the limits.
+        let target = reserve_path(&state.target_dir, &name).map_err(|e| {
+            state.accepted.fetch_sub(size, Ordering::Relaxed);
+            internal_error(e)
+        })?;
+        let exported = state
+            .db
+            .blobs()
+            .export(temp_tag.hash(), &target)
+            .finish()
+            .await;
+        if let Err(e) = exported {
+            let _ = std::fs::remove_file(&target);
+            state.accepted.fetch_sub(size, Ordering::Relaxed);
+            return Err(internal_error(e));
+        }
+        println!("Received {} ({})", target.display(), ByteSize(size));
+        let status = InboxStatus::Received {
+            name: name.clone(),
+            size,
+            path: target,
+        };
+        state.progress.send(status).await.ok();
+        files.push(HistoryFile {
+            name,
+            size,
+            hash: temp_tag.hash(),
+        });
+    }
+    Ok(())
+}
+
+/// Takes `len` more bytes of the file `name` on against the size limits, which makes
+/// it `size` bytes long. Nothing is taken on if a limit is exceeded.
+fn accept(state: &InboxState, name: &str, size: u64, len: u64) -> Result<(), Rejection> {
+    if let Some(max) = state.options.max_file_size.filter(|max| size > *max) {
+        let message = format!("{} is larger than {}", name, ByteSize(max).display().si());
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, message));
+    }
+    let accepted = state.accepted.fetch_add(len, Ordering::Relaxed) + len;
+    if let Some(max) = state.options.max_total_size.filter(|max| accepted > *max) {
+        state.accepted.fetch_sub(len, Ordering::Relaxed);
+        let message = format!(
+            "Uploads are limited to {} in total",
+            ByteSize(max).display().si()
+        );
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, message));
+    }
+    Ok(())
+}
+
+fn bad_request(e: impl std::fmt::Display) -> Rejection {
+    (StatusCode::BAD_REQUEST, e.to_string())
+}
+
+fn internal_error(e: impl std::fmt::Display) -> Rejection {
+    println!("Failed to save an upload: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "The file could not be saved".to_string(),
+    )
+}
+
+/// Compares the hashes of the passwords, which takes the same time wherever they differ.
+fn password_matches(expected: Option<&str>, given: &str) -> bool {
+    expected
+        .is_none_or(|expected| blake3::hash(expected.as_bytes()) == blake3::hash(given.as_bytes()))
+}
+
+/// The name to save an uploaded file under: the last part of what the browser sent,
+/// as some send the whole path.
+fn upload_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    (!name.is_empty() && name != "." && name != "..").then(|| name.to_string())
+}
+
+/// Creates an empty file for `name` in `dir` under a name that is not taken yet, adding
+/// " (1)", " (2)" and so on before the extension, and returns its path. Creating the file
+/// reserves the name, so files of the same name uploaded at once do not overwrite each other.
+fn reserve_path(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    for n in 0.. {
+        let candidate = match (n, extension) {
+            (0, _) => name.to_string(),
+            (n, Some(extension)) => format!("{} ({}).{}", stem, n, extension),
+            (n, None) => format!("{} ({})", stem, n),
+        };
+        let path = get_export_path(dir, &candidate)?;
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_file_name() {
+        assert_eq!(upload_file_name("photo.jpg").as_deref(), Some("photo.jpg"));
+        assert_eq!(
+            upload_file_name("C:\\Users\\me\\photo.jpg").as_deref(),
+            Some("photo.jpg")
+        );
+        assert_eq!(
+            upload_file_name("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(upload_file_name(".."), None);
+        assert_eq!(upload_file_name("dir/"), None);
+    }
+
+    #[test]
+    fn test_reserve_path() {
+        let dir = std::env::temp_dir().join(format!(
+            "p2p-client-test-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(reserve_path(&dir, "a.txt").unwrap(), dir.join("a.txt"));
+        assert!(dir.join("a.txt").exists());
+        std::fs::write(dir.join("a (1).txt"), b"").unwrap();
+        assert_eq!(reserve_path(&dir, "a.txt").unwrap(), dir.join("a (2).txt"));
+        assert_eq!(reserve_path(&dir, "a.txt").unwrap(), dir.join("a (3).txt"));
+        std::fs::write(dir.join(".env"), b"").unwrap();
+        assert_eq!(reserve_path(&dir, ".env").unwrap(), dir.join(".env (1)"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_password_matches() {
+        assert!(password_matches(None, ""));
+        assert!(password_matches(Some("secret"), "secret"));
+        assert!(!password_matches(Some("secret"), "Secret"));
+    }
+}
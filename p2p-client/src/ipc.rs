@@ -0,0 +1,184 @@
+//! Local transport for the daemon's irpc API.
+//!
+//! irpc speaks QUIC, so the daemon API runs a quinn endpoint on top of a Unix datagram
+//! socket instead of UDP. Only processes that may write to the socket file can reach the
+//! daemon, and nothing is exposed on the network.
+
+use quinn::{
+    udp::{RecvMeta, Transmit},
+    AsyncUdpSocket, Endpoint, EndpointConfig, TokioRuntime, UdpSender, UdpSenderHelper,
+    UdpSenderHelperSocket,
+};
+use std::os::unix::fs::PermissionsExt;
+use std::{
+    collections::HashMap,
+    fmt, io,
+    io::IoSliceMut,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+use tokio::{io::ReadBuf, net::UnixDatagram};
+
+/// Fake address under which the peer a client socket was created for is known to quinn.
+const SERVER_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+
+/// Maps socket file paths of peers to the made-up IP addresses quinn sees.
+#[derive(Debug, Default)]
+struct PeerMap {
+    by_path: HashMap<PathBuf, SocketAddr>,
+    by_addr: HashMap<SocketAddr, PathBuf>,
+}
+
+impl PeerMap {
+    fn addr_for(&mut self, path: &Path) -> SocketAddr {
+        if let Some(addr) = self.by_path.get(path) {
+            return *addr;
+        }
+        let port = u16::try_from(self.by_addr.len() + 2).unwrap_or(u16::MAX);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        self.by_path.insert(path.to_path_buf(), addr);
+        self.by_addr.insert(addr, path.to_path_buf());
+        addr
+    }
+}
+
+/// A Unix datagram socket that quinn can use in place of a UDP socket.
+#[derive(Clone)]
+struct UnixSocket {
+    io: Arc<UnixDatagram>,
+    peers: Arc<Mutex<PeerMap>>,
+    /// Removed on drop of the last clone.
+    path: Arc<SocketFile>,
+}
+
+impl fmt::Debug for UnixSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnixSocket")
+            .field("path", &self.path.0)
+            .finish()
+    }
+}
+
+/// Deletes the socket file when the socket is gone.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl UnixSocket {
+    fn bind(path: &Path) -> io::Result<Self> {
+        let _ = std::fs::remove_file(path);
+        let io = UnixDatagram::bind(path)?;
+        Ok(Self {
+            io: Arc::new(io),
+            peers: Default::default(),
+            path: Arc::new(SocketFile(path.to_path_buf())),
+        })
+    }
+}
+
+impl UdpSenderHelperSocket for UnixSocket {
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        let path = self
+            .peers
+            .lock()
+            .unwrap()
+            .by_addr
+            .get(&transmit.destination)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown peer"))?;
+        self.io.try_send_to(transmit.contents, path)?;
+        Ok(())
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        1
+    }
+}
+
+impl AsyncUdpSocket for UnixSocket {
+    fn create_sender(&self) -> Pin<Box<dyn UdpSender>> {
+        Box::pin(UdpSenderHelper::new(self.clone(), |socket: &UnixSocket| {
+            let io = socket.io.clone();
+            async move { io.writable().await }
+        }))
+    }
+
+    fn poll_recv(
+        &mut self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut buf = ReadBuf::new(&mut bufs[0]);
+            let from = ready!(self.io.poll_recv_from(cx, &mut buf))?;
+            let len = buf.filled().len();
+            // Unnamed sockets can not be answered; drop the datagram like a lossy network would.
+            let Some(path) = from.as_pathname() else {
+                continue;
+            };
+            meta[0] = RecvMeta {
+                addr: self.peers.lock().unwrap().addr_for(path),
+                len,
+                stride: len,
+                ecn: None,
+                dst_ip: None,
+            };
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+    }
+
+    fn may_fragment(&self) -> bool {
+        false
+    }
+}
+
+/// Binds a server endpoint on the socket file at `path`.
+/// Returns the endpoint and its self-signed certificate in DER format.
+pub(crate) fn server_endpoint(path: &Path) -> anyhow::Result<(Endpoint, Vec<u8>)> {
+    let (server_config, cert) = irpc::util::configure_server()?;
+    let socket = UnixSocket::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    let endpoint = Endpoint::new_with_abstract_socket(
+        EndpointConfig::default(),
+        Some(server_config),
+        Box::new(socket),
+        Arc::new(TokioRuntime),
+    )?;
+    Ok((endpoint, cert))
+}
+
+/// Binds a client endpoint on its own socket file at `path` that talks to the server
+/// at `server_path` and trusts only `server_cert`.
+/// Returns the endpoint and the address to connect to.
+pub(crate) fn client_endpoint(
+    path: &Path,
+    server_path: &Path,
+    server_cert: &[u8],
+) -> anyhow::Result<(Endpoint, SocketAddr)> {
+    let socket = UnixSocket::bind(path)?;
+    {
+        let mut peers = socket.peers.lock().unwrap();
+        peers.by_path.insert(server_path.to_path_buf(), SERVER_ADDR);
+        peers.by_addr.insert(SERVER_ADDR, server_path.to_path_buf());
+    }
+    let mut endpoint = Endpoint::new_with_abstract_socket(
+        EndpointConfig::default(),
+        None,
+        Box::new(socket),
+        Arc::new(TokioRuntime),
+    )?;
+    endpoint.set_default_client_config(irpc::util::configure_client(&[server_cert])?);
+    Ok((endpoint, SERVER_ADDR))
+}
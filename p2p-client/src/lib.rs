@@ -1,70 +1,377 @@
 #![allow(clippy::large_enum_variant)]
+mod bridge;
+mod cleanup;
+mod codes;
+mod compress;
+mod config;
+mod daemon;
+mod diagnostics;
 mod files;
+mod gallery;
+mod history;
+mod inbox;
+#[cfg(unix)]
+mod ipc;
+mod limit;
+mod mime;
+mod nearby;
+mod node;
 mod p2p;
+mod pointer;
+mod qr;
+mod sealed;
+mod shares;
+mod sources;
 mod state;
+mod stats;
+mod store;
+mod sync;
+mod uploads;
+mod watch;
 mod web;
 
-pub use state::{ReceiveStatus, SendHandle, SendStatus};
+pub use cleanup::CleanupReport;
+pub use codes::{generate_code, is_short_code, run_rendezvous_server};
+pub use config::{
+    data_dir, rendezvous_from_env, RelaySetting, DATA_DIR_ENV_VAR, GC_INTERVAL_ENV_VAR,
+    RELAY_ENV_VAR, RENDEZVOUS_ENV_VAR,
+};
+pub use daemon::{
+    run_daemon, BridgeRequest, DaemonClient, InboxRequest, InboxUpdate, ReceiveRequest,
+    SendRequest, SendUpdate, TransferInfo, TransferKind,
+};
+pub use diagnostics::{Diagnostics, NatKind, PeerConnection};
+pub use history::{
+    clear_history, history, HistoryEntry, HistoryFile, HistoryKind, TransferOutcome,
+};
+pub use inbox::{InboxHandle, InboxOptions};
+pub use limit::{global_limits, parse_rate, set_global_limits, BandwidthLimits, RateLimiter};
+pub use nearby::{default_device_name, NearbyEvent, NearbyHandle, NearbyPeer};
+pub use qr::{decode_qr_file, decode_qr_rgba, encode_qr, QrMatrix};
+pub use shares::{ShareInfo, ShareMode};
+pub use state::{InboxStatus, ReceiveStatus, SendHandle, SendStatus};
+pub use stats::{PathKind, SourceProgress, TransferProgress, TransferSummary};
+pub use sync::{SyncOptions, SyncPlan};
 
-use iroh_blobs::ticket::BlobTicket;
 use std::path::PathBuf;
-use std::str::FromStr;
+use std::time::Duration;
 use tokio::{runtime::Handle as TokioHandle, sync::mpsc};
+use url::Url;
 
 /// Public entry point for starting a P2P (ticket-based) send operation.
+/// With `watch`, a folder is shared again whenever it changes and every new ticket
+/// is reported as [`SendStatus::Updated`].
 pub async fn send_file(
     path: PathBuf,
+    watch: bool,
+    relay: RelaySetting,
     progress_sender: mpsc::Sender<SendStatus>,
     tokio_handle: TokioHandle,
 ) -> anyhow::Result<SendHandle> {
-    p2p::send_internal(path, progress_sender, tokio_handle).await
+    let node = node::shared_node(&relay).await?;
+    p2p::send_internal(node, path, watch, progress_sender, tokio_handle).await
+}
+
+/// Shares a finished download again from this node. Only downloads from tickets can be
+/// shared, as web links do not go through the store.
+pub(crate) async fn seed_download(
+    entry: &HistoryEntry,
+    relay: RelaySetting,
+    progress_sender: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<SendHandle> {
+    let hash = match (&entry.ticket, entry.hash) {
+        (Some(ticket), Some(hash)) if !is_web_link(ticket) => hash,
+        _ => anyhow::bail!("Only downloads from tickets can be shared again"),
+    };
+    let node = node::shared_node(&relay).await?;
+    p2p::seed(
+        node,
+        hash,
+        entry.received_path(),
+        progress_sender,
+        tokio_handle,
+    )
+    .await
+}
+
+/// Removes what crashed transfers left on disk: temporary files no running process uses,
+/// and the stores of older versions. Also runs whenever the store is opened.
+pub fn clean_up() -> anyhow::Result<CleanupReport> {
+    cleanup::clean_up()
 }
 
 /// Public entry point for starting an HTTP (web link) send operation.
+/// With `encrypted`, the file can only be read with the key in the link's fragment.
 pub async fn start_http_send(
     path: PathBuf,
+    encrypted: bool,
+    relay: RelaySetting,
     progress_sender: mpsc::Sender<SendStatus>,
     tokio_handle: TokioHandle,
 ) -> anyhow::Result<SendHandle> {
+    let node = node::shared_node(&relay).await?;
     // Викликаємо функцію з модуля web
-    web::start_http_send_internal(path, progress_sender, tokio_handle).await
+    web::start_http_send_internal(node, path, encrypted, progress_sender, tokio_handle).await
+}
+
+/// Public entry point for bridging a ticket to the web: publishes a web link to the file
+/// of `ticket`, which is fetched from the sender whenever the link is opened.
+/// Returns the handle and the name of the file.
+pub async fn bridge_ticket(
+    ticket: String,
+    relay: RelaySetting,
+    progress_sender: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<(SendHandle, String)> {
+    let node = node::shared_node(&relay).await?;
+    bridge::start(node, &ticket, progress_sender, tokio_handle).await
+}
+
+/// Public entry point for requesting files: publishes a web page where anyone with the
+/// link can upload files into `target_dir`, within the limits of `options`.
+/// Uploads are limited by `download_limit` and the global download limit.
+pub async fn request_files(
+    target_dir: PathBuf,
+    options: InboxOptions,
+    download_limit: RateLimiter,
+    progress_sender: mpsc::Sender<InboxStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<InboxHandle> {
+    inbox::start(
+        target_dir,
+        options,
+        download_limit,
+        progress_sender,
+        tokio_handle,
+    )
+    .await
+}
+
+/// Public entry point for creating a persistent share that is announced again
+/// after a restart until it expires or is removed.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_share(
+    path: PathBuf,
+    name: String,
+    mode: ShareMode,
+    expires_in: Option<Duration>,
+    watch: bool,
+    relay: RelaySetting,
+    progress_sender: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<SendHandle> {
+    let node = node::shared_node(&relay).await?;
+    shares::create_share_internal(
+        node,
+        path,
+        name,
+        mode,
+        expires_in,
+        watch,
+        progress_sender,
+        tokio_handle,
+    )
+    .await
+}
+
+/// Reports how the node can be reached and how it is connected to the peers of
+/// running transfers.
+pub async fn diagnose(relay: RelaySetting) -> anyhow::Result<Diagnostics> {
+    let node = node::shared_node(&relay).await?;
+    Ok(diagnostics::diagnose(&node).await)
+}
+
+/// Announces all persistent shares recorded by earlier runs. Call this once on startup.
+pub async fn restore_shares(
+    relay: RelaySetting,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<Vec<SendHandle>> {
+    let node = node::shared_node(&relay).await?;
+    shares::restore_shares_internal(node, tokio_handle).await
+}
+
+/// Returns the persistent shares recorded in the local database.
+pub fn list_shares() -> anyhow::Result<Vec<ShareInfo>> {
+    shares::list()
+}
+
+/// Removes a persistent share. Dropping its `SendHandle` stops serving it.
+pub async fn remove_share(id: &str) -> anyhow::Result<()> {
+    let store = store::shared_store().await?;
+    shares::remove_share_internal(&store, id).await
+}
+
+/// Stops the node shared by all transfers and closes the blob store. Call this before the process exits.
+pub async fn shutdown() {
+    node::shutdown_shared_node().await;
+    store::shutdown_shared_store().await;
+}
+
+/// Public entry point for advertising this device on the LAN and discovering nearby peers.
+pub async fn start_nearby(
+    device_name: String,
+    relay: RelaySetting,
+    events: mpsc::Sender<NearbyEvent>,
+) -> anyhow::Result<NearbyHandle> {
+    let node = node::shared_node(&relay).await?;
+    nearby::start_nearby_internal(node, device_name, events).await
+}
+
+/// Creates a short code for a ticket. The code is announced to nearby devices and,
+/// if a rendezvous server is configured, registered there as well.
+pub async fn create_short_code(
+    ticket: String,
+    rendezvous: Option<Url>,
+    nearby: Option<&NearbyHandle>,
+) -> anyhow::Result<String> {
+    anyhow::ensure!(
+        rendezvous.is_some() || nearby.is_some(),
+        "No rendezvous server configured and local discovery is not running"
+    );
+    let mut attempts = 0;
+    let code = loop {
+        let code = codes::generate_code();
+        match &rendezvous {
+            Some(server) => match codes::publish_code(server, &code, &ticket).await {
+                Ok(()) => break code,
+                Err(e) if attempts < 5 => {
+                    println!("Failed to register code {}: {}", code, e);
+                    attempts += 1;
+                }
+                Err(e) => return Err(e),
+            },
+            None => break code,
+        }
+    };
+    if let Some(nearby) = nearby {
+        nearby.register_code(code.clone(), ticket);
+    }
+    Ok(code)
+}
+
+/// Resolves a short code to the full ticket, asking nearby devices first and the
+/// rendezvous server second.
+pub async fn resolve_short_code(
+    code: &str,
+    rendezvous: Option<Url>,
+    nearby: Option<&NearbyHandle>,
+) -> anyhow::Result<String> {
+    let code = codes::normalize_code(code);
+    if let Some(nearby) = nearby {
+        if let Some(ticket) = nearby.lookup_code(&code).await {
+            return Ok(ticket);
+        }
+    }
+    if let Some(server) = rendezvous {
+        if let Some(ticket) = codes::lookup_code(&server, &code).await? {
+            return Ok(ticket);
+        }
+    }
+    anyhow::bail!("Unknown code: {}", code)
 }
 
 /// Public entry point for receiving a file using a ticket.
-pub async fn receive_file(ticket_str: String, progress_sender: mpsc::Sender<ReceiveStatus>) {
-    let dir_name = match BlobTicket::from_str(&ticket_str) {
-        Ok(ticket) => format!(".p2p-client-recv-{}", ticket.hash().to_hex()),
+pub async fn receive_file(
+    ticket_str: String,
+    relay: RelaySetting,
+    progress_sender: mpsc::Sender<ReceiveStatus>,
+) {
+    match std::env::current_dir() {
+        Ok(target_dir) => {
+            receive_file_into(
+                ticket_str,
+                Vec::new(),
+                target_dir,
+                None,
+                false,
+                relay,
+                RateLimiter::default(),
+                progress_sender,
+            )
+            .await;
+        }
         Err(e) => {
             progress_sender
                 .send(ReceiveStatus::Error(e.to_string()))
                 .await
                 .ok();
-            return;
         }
-    };
-    let data_dir = match std::env::current_dir() {
-        Ok(dir) => dir.join(dir_name),
+    }
+}
+
+fn is_web_link(ticket: &str) -> bool {
+    ticket.starts_with("http://") || ticket.starts_with("https://")
+}
+
+/// Receives the content of a ticket or web link into `target_dir`.
+/// `sources` are more tickets or node ids of peers with the same content to download from.
+/// With `sync`, existing files are updated instead of refusing to overwrite them.
+/// With `compress`, peers are asked to send the files compressed.
+/// The download is limited by `download_limit` and the global download limit.
+/// The outcome is recorded in the transfer history and returned, unless it was a dry run.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn receive_file_into(
+    ticket_str: String,
+    sources: Vec<String>,
+    target_dir: PathBuf,
+    sync: Option<SyncOptions>,
+    compress: bool,
+    relay: RelaySetting,
+    download_limit: RateLimiter,
+    progress_sender: mpsc::Sender<ReceiveStatus>,
+) -> Option<HistoryEntry> {
+    let result = async {
+        if is_web_link(&ticket_str) {
+            anyhow::ensure!(
+                sources.is_empty(),
+                "Web links cannot be downloaded from other peers"
+            );
+            return web::receive_web_link(
+                &ticket_str,
+                &target_dir,
+                sync,
+                download_limit,
+                progress_sender.clone(),
+            )
+            .await;
+        }
+        let node = node::shared_node(&relay).await?;
+        p2p::receive_logic(
+            node,
+            &ticket_str,
+            &sources,
+            &target_dir,
+            sync,
+            compress,
+            download_limit,
+            progress_sender.clone(),
+        )
+        .await
+    }
+    .await;
+
+    let mut entry = match result {
+        Ok(entry) => entry,
         Err(e) => {
             progress_sender
                 .send(ReceiveStatus::Error(e.to_string()))
                 .await
                 .ok();
-            return;
+            let mut entry = HistoryEntry::new(
+                HistoryKind::Receive,
+                target_dir,
+                TransferOutcome::Failed(e.to_string()),
+            );
+            (entry.peer, entry.hash) = history::ticket_origin(&ticket_str);
+            entry
         }
     };
-
-    let result =
-        async { p2p::receive_logic(&ticket_str, &data_dir, progress_sender.clone()).await }.await;
-
-    println!("Cleaning up temporary receive directory...");
-    if let Err(e) = tokio::fs::remove_dir_all(&data_dir).await {
-        println!("Failed to clean up temp dir {:?}: {}", data_dir, e);
+    if sync.is_some_and(|options| options.dry_run) {
+        return None;
     }
-
-    if let Err(e) = result {
-        progress_sender
-            .send(ReceiveStatus::Error(e.to_string()))
-            .await
-            .ok();
-    }
-}
\ No newline at end of file
+    entry.ticket = Some(ticket_str);
+    entry.sync = sync;
+    history::record(&entry);
+    Some(entry)
+}
@@ -0,0 +1,201 @@
+//! Bandwidth limits. Every byte a transfer moves passes through the global limiter of
+//! its direction and through the limiter of the transfer itself; all of them can be
+//! changed while transfers are running.
+
+use super::config::data_dir;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The global upload and download limiters of this process.
+static GLOBAL: LazyLock<(RateLimiter, RateLimiter)> = LazyLock::new(Default::default);
+
+/// Upload and download rates in bytes per second. `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+/// A token bucket that holds up to one second worth of bytes, so short bursts are
+/// allowed while the average stays at the configured rate.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    /// Bytes that may be sent right away. Negative while transfers wait for their turn.
+    available: f64,
+    refilled: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            available: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.available = (self.available + elapsed * rate as f64).min(rate as f64);
+        }
+        self.refilled = now;
+    }
+
+    /// Takes `bytes` from the bucket and returns how long the caller has to wait for them.
+    fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        let Some(rate) = self.rate else {
+            return Duration::ZERO;
+        };
+        self.available -= bytes as f64;
+        if self.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.available / rate.max(1) as f64)
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let this = Self::default();
+        this.set_rate(rate);
+        this
+    }
+
+    /// The current rate in bytes per second, `None` if unlimited.
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the rate. Transfers that are running pick it up with their next chunk.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = rate.filter(|rate| *rate > 0);
+        bucket.available = match bucket.rate {
+            Some(rate) => bucket.available.min(rate as f64),
+            None => 0.0,
+        };
+    }
+
+    /// Waits until `bytes` may be transferred.
+    pub(crate) async fn consume(&self, bytes: u64) {
+        let wait = self.bucket.lock().unwrap().take(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parses a rate like "2 MB", "500KiB/s" or "off". Returns `None` for unlimited.
+pub fn parse_rate(text: &str) -> anyhow::Result<Option<u64>> {
+    let text = text.trim();
+    let text = text.strip_suffix("/s").unwrap_or(text).trim();
+    if text.is_empty() || text.eq_ignore_ascii_case("off") || text.eq_ignore_ascii_case("unlimited")
+    {
+        return Ok(None);
+    }
+    let rate = text
+        .parse::<bytesize::ByteSize>()
+        .map_err(|e| anyhow::anyhow!("Invalid rate {:?}: {}", text, e))?;
+    Ok(Some(rate.as_u64()).filter(|rate| *rate > 0))
+}
+
+/// Returns the global limits of this process.
+pub fn global_limits() -> BandwidthLimits {
+    BandwidthLimits {
+        upload: GLOBAL.0.rate(),
+        download: GLOBAL.1.rate(),
+    }
+}
+
+/// Changes the global limits and records them for the next start.
+pub fn set_global_limits(limits: BandwidthLimits) -> anyhow::Result<()> {
+    GLOBAL.0.set_rate(limits.upload);
+    GLOBAL.1.set_rate(limits.download);
+    let path = data_dir()?.join("limits.json");
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(&limits)?)?;
+    Ok(())
+}
+
+/// Applies the global limits recorded by an earlier run.
+pub(crate) fn load_global_limits() -> anyhow::Result<()> {
+    let path = data_dir()?.join("limits.json");
+    let limits: BandwidthLimits = match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    GLOBAL.0.set_rate(limits.upload);
+    GLOBAL.1.set_rate(limits.download);
+    Ok(())
+}
+
+/// Waits until `bytes` may be sent by a transfer limited by `limit`.
+pub(crate) async fn throttle_upload(limit: &RateLimiter, bytes: u64) {
+    GLOBAL.0.consume(bytes).await;
+    limit.consume(bytes).await;
+}
+
+/// Waits until `bytes` may be received by a transfer limited by `limit`.
+pub(crate) async fn throttle_download(limit: &RateLimiter, bytes: u64) {
+    GLOBAL.1.consume(bytes).await;
+    limit.consume(bytes).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            rate: Some(1000),
+            available: 0.0,
+            refilled: start,
+        };
+        // Half a second fills the bucket with 500 bytes.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(500, later), Duration::ZERO);
+        // The next 1000 bytes have to wait a full second.
+        assert_eq!(bucket.take(1000, later), Duration::from_secs(1));
+        // The bucket never holds more than one second worth of bytes.
+        bucket.available = 0.0;
+        assert_eq!(
+            bucket.take(1000, later + Duration::from_secs(10)),
+            Duration::ZERO
+        );
+        assert!(bucket.take(1, later + Duration::from_secs(10)) > Duration::ZERO);
+
+        bucket.rate = None;
+        assert_eq!(bucket.take(u64::MAX, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("2MB").unwrap(), Some(2_000_000));
+        assert_eq!(parse_rate(" 512 KiB/s ").unwrap(), Some(512 * 1024));
+        assert_eq!(parse_rate("1000").unwrap(), Some(1000));
+        assert_eq!(parse_rate("").unwrap(), None);
+        assert_eq!(parse_rate("off").unwrap(), None);
+        assert_eq!(parse_rate("0").unwrap(), None);
+        assert!(parse_rate("fast").is_err());
+    }
+}
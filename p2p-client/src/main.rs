@@ -1,16 +1,178 @@
+use anyhow::Context as _;
+use clap::{Parser, Subcommand};
 use eframe::{egui, App, Frame};
 use egui::{
-    Align, Button, CentralPanel, Color32, Context, Frame as EguiFrame, Layout, ProgressBar,
-    RichText, Stroke,
+    Align, Button, CentralPanel, Color32, Context, Frame as EguiFrame, Layout, ProgressBar, Rect,
+    RichText, Sense, Stroke, Ui, Vec2,
+};
+use iroh::EndpointId;
+use p2p_client::{
+    clean_up, clear_history, create_short_code, decode_qr_file, decode_qr_rgba,
+    default_device_name, diagnose, encode_qr, history, is_short_code, parse_rate,
+    rendezvous_from_env, resolve_short_code, run_daemon, run_rendezvous_server, start_nearby,
+    BandwidthLimits, BridgeRequest, DaemonClient, Diagnostics, HistoryEntry, HistoryKind,
+    InboxOptions, InboxRequest, InboxStatus, NearbyEvent, NearbyHandle, NearbyPeer, PathKind,
+    QrMatrix, ReceiveRequest, ReceiveStatus, RelaySetting, SendRequest, SendStatus, ShareInfo,
+    ShareMode, SourceProgress, SyncOptions, SyncPlan, TransferInfo, TransferKind, TransferOutcome,
+    TransferProgress, TransferSummary, RELAY_ENV_VAR,
 };
-use p2p_client::{receive_file, send_file, start_http_send, ReceiveStatus, SendHandle, SendStatus};
 use rfd::FileDialog;
 use rustls::crypto::CryptoProvider;
+use std::collections::HashMap;
 use std::error::Error;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc;
+use url::Url;
+
+/// Command-line options of the desktop application.
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Relay servers to use: "default", "disabled", or a comma-separated list of relay URLs.
+    /// Overrides the P2P_CLIENT_RELAY environment variable.
+    #[arg(long)]
+    relay: Option<RelaySetting>,
+    /// URL of the rendezvous server used to resolve short codes.
+    /// Overrides the P2P_CLIENT_RENDEZVOUS environment variable.
+    #[arg(long)]
+    rendezvous: Option<Url>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a rendezvous server that maps short codes to tickets instead of the GUI.
+    RendezvousServer {
+        /// Address to listen on.
+        #[arg(long, default_value = "0.0.0.0:8080")]
+        listen: SocketAddr,
+    },
+    /// Run the background daemon that hosts all transfers. The GUI and the commands
+    /// below talk to it, so shares keep running when the window is closed.
+    Daemon,
+    /// Stop the background daemon and all of its transfers.
+    StopDaemon,
+    /// Send a file or folder through the daemon and print its ticket.
+    Send {
+        path: PathBuf,
+        /// Share a web link instead of a ticket.
+        #[arg(long)]
+        web: bool,
+        /// Keep sharing after restarts until removed.
+        #[arg(long)]
+        keep: bool,
+        /// Name of the persistent share.
+        #[arg(long, requires = "keep")]
+        name: Option<String>,
+        /// Remove the persistent share after this many seconds.
+        #[arg(long, requires = "keep")]
+        expires: Option<u64>,
+        /// End-to-end encrypt the web link. The key is in the link's fragment, so the tunnel
+        /// provider cannot read the file.
+        #[arg(long, requires = "web")]
+        encrypt: bool,
+        /// Share the folder again whenever it changes and print every new ticket.
+        #[arg(long, conflicts_with = "web")]
+        watch: bool,
+        /// Upload limit of this send per second, e.g. "2MB".
+        #[arg(long)]
+        limit: Option<String>,
+    },
+    /// Download a ticket or short code through the daemon.
+    Receive {
+        ticket: String,
+        /// Another ticket or node id of a peer with the same content to download from at
+        /// the same time. May be repeated.
+        #[arg(long = "source")]
+        sources: Vec<String>,
+        /// Directory to download into. Defaults to the current directory.
+        #[arg(long)]
+        dir: Option<PathBuf>,
+        /// Update existing files instead of refusing to overwrite them.
+        /// Only new and changed files are downloaded.
+        #[arg(long)]
+        sync: bool,
+        /// With --sync, delete local files the sender no longer has.
+        #[arg(long, requires = "sync")]
+        delete: bool,
+        /// With --sync, only show what would change.
+        #[arg(long, requires = "sync")]
+        dry_run: bool,
+        /// Download limit per second, e.g. "2MB".
+        #[arg(long)]
+        limit: Option<String>,
+        /// Keep sharing the download from this device afterwards and print its new ticket.
+        #[arg(long, conflicts_with = "dry_run")]
+        seed: bool,
+        /// Ask the sender to compress the files. Speeds up text, logs and other
+        /// compressible files on slow connections.
+        #[arg(long)]
+        compress: bool,
+    },
+    /// Share the file behind a ticket or short code as a web link and print the link. The
+    /// file is fetched from its sender whenever the link is opened.
+    Bridge {
+        ticket: String,
+        /// Upload limit to the browsers per second, e.g. "2MB".
+        #[arg(long)]
+        limit: Option<String>,
+    },
+    /// Publish a web page where others can upload files into a folder, and print its link.
+    Request {
+        /// Directory to save the uploads in. Defaults to the current directory.
+        #[arg(long)]
+        dir: Option<PathBuf>,
+        /// Largest file accepted, e.g. "500MB".
+        #[arg(long)]
+        max_file_size: Option<bytesize::ByteSize>,
+        /// How much is accepted over all uploads together, e.g. "2GB".
+        #[arg(long)]
+        max_total_size: Option<bytesize::ByteSize>,
+        /// Password uploaders have to enter.
+        #[arg(long)]
+        password: Option<String>,
+        /// Download limit of the uploads per second, e.g. "2MB".
+        #[arg(long)]
+        limit: Option<String>,
+    },
+    /// List the daemon's transfers.
+    List,
+    /// Check how this device can be reached and how running transfers are connected.
+    Doctor,
+    /// Show the relay mode of the daemon, or restart its node with another one. The
+    /// sends, downloads and bridges running on the node stop.
+    Relay { mode: Option<RelaySetting> },
+    /// Show the finished transfers, oldest first.
+    History {
+        /// Delete the history instead.
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Remove files that crashed transfers left behind and report the space reclaimed.
+    Cleanup,
+    /// Stop a transfer of the daemon. Stopping a persistent share removes it.
+    Stop { id: u64 },
+    /// Show or change the bandwidth limits. Rates are per second, e.g. "2MB" or "off".
+    Limit {
+        /// Global upload limit.
+        #[arg(long, conflicts_with = "id")]
+        upload: Option<String>,
+        /// Global download limit.
+        #[arg(long, conflicts_with = "id")]
+        download: Option<String>,
+        /// Change the limit of this transfer instead.
+        #[arg(long, requires = "rate")]
+        id: Option<u64>,
+        /// New limit of the transfer given by --id.
+        #[arg(long, requires = "id")]
+        rate: Option<String>,
+    },
+}
 
 struct MyApp {
     // --- UI State ---
@@ -20,6 +182,14 @@ struct MyApp {
     progress_value: f32,            // Progress bar value (0.0 to 1.0).
     is_drag_hover: bool,            // True if a file is being dragged over the window.
     is_web_send_active: bool,       // True if a web (ngrok) transfer is active.
+    qr_code: Option<(String, QrMatrix)>, // QR code currently shown in the status area and its text.
+
+    // --- Settings ---
+    relay: RelaySetting,            // Relay mode the daemon's node runs with.
+    relay_input: String,            // Text field for editing the relay mode.
+    relay_rx: Option<mpsc::Receiver<anyhow::Result<RelaySetting>>>, // Receives the relay mode once the node has restarted.
+    rendezvous: Option<Url>,        // Rendezvous server for short codes, if any.
+    rendezvous_input: String,       // Text field for editing the rendezvous server.
 
     // --- Async Communication ---
     send_progress_rx: Option<mpsc::Receiver<SendStatus>>, // Receives status updates for sending.
@@ -27,54 +197,488 @@ struct MyApp {
     tokio_rt: Arc<Runtime>, // The Tokio runtime to execute async tasks.
 
     // --- Transfer Management ---
-    send_handle_rx: Option<mpsc::Receiver<anyhow::Result<SendHandle>>>, // Receives the handle to manage a send operation.
-    send_handle: Option<SendHandle>, // Holds the handle for the *currently starting* send operation.
-    active_sends: Vec<(String, SendHandle, SendType)>, // List of active background transfers.
-}
+    daemon: Option<DaemonClient>,   // The daemon running all transfers, in the background or in-process.
+    daemon_rx: Option<mpsc::Receiver<(DaemonClient, BandwidthLimits, RelaySetting)>>, // Receives the daemon client, its limits and its relay mode once connected.
+    transfers: Vec<TransferInfo>,   // Transfers of the daemon, refreshed periodically.
+    transfers_rx: Option<mpsc::Receiver<anyhow::Result<Vec<TransferInfo>>>>, // Receives the refreshed transfer list.
+    last_refresh: Instant,          // When the transfer list was last requested.
+    short_codes: HashMap<String, String>, // Short codes created for active tickets: ticket -> code.
+    short_code_rx: Option<mpsc::Receiver<(String, anyhow::Result<String>)>>, // Receives newly created short codes.
+
+    // --- Persistent Shares ---
+    keep_share: bool,               // True if the next send should survive restarts.
+    watch_folder: bool,             // True if the next send is shared again whenever it changes.
+    encrypt_web: bool,              // True if the next web link is end-to-end encrypted.
+    share_expiry: usize,            // Index into SHARE_EXPIRY_CHOICES for the next persistent share.
+
+    // --- Sync Receive ---
+    sync_receive: bool,             // True if "Get" updates an existing folder in place.
+    sync_delete: bool,              // True if a sync also deletes files the sender no longer has.
+    sync_preview: Option<(PathBuf, Option<SyncPlan>)>, // Folder of the sync being previewed and its differences once known.
+
+    // --- Seeding ---
+    seed_receive: bool,             // True if downloads are shared again from this device once done.
+    receive_seeding: bool,          // True if the running download is going to be shared again.
+
+    // --- Compression ---
+    compress_receive: bool,         // True if downloads ask the sender to compress the files.
+
+    // --- Web Bridge ---
+    bridge_progress_rx: Option<mpsc::Receiver<SendStatus>>, // Receives status updates of a ticket being shared as a web link.
+
+    // --- File Requests ---
+    inbox_password: String,         // Password uploaders must enter; empty for none.
+    inbox_max_size: String,         // Text field for the largest file accepted, e.g. "500 MB".
+    inbox_progress_rx: Option<mpsc::Receiver<InboxStatus>>, // Receives status updates of the file request.
+
+    // --- Bandwidth Limits ---
+    upload_limit_input: String,     // Text field for the global upload limit, e.g. "2 MB".
+    download_limit_input: String,   // Text field for the global download limit.
+    limit_inputs: HashMap<u64, String>, // Text fields for the limits of running transfers, by transfer id.
+
+    // --- History ---
+    history: Vec<HistoryEntry>,     // Finished transfers, oldest first.
+    history_rx: Option<mpsc::Receiver<anyhow::Result<Vec<HistoryEntry>>>>, // Receives the reloaded history.
+    last_history_refresh: Option<Instant>, // When the history was last loaded, if it was.
 
-// Differentiates between a P2P ticket transfer and a web link transfer.
-#[derive(Clone, Copy, PartialEq)]
-enum SendType {
-    P2P,
-    Web,
+    // --- Diagnostics ---
+    diagnostics: Option<anyhow::Result<Diagnostics>>, // The last connectivity report of the daemon's node.
+    diagnostics_rx: Option<mpsc::Receiver<anyhow::Result<Diagnostics>>>, // Receives a new report.
+    last_diagnostics_refresh: Option<Instant>, // When the report was last requested, if it was.
+
+    // --- Nearby Devices ---
+    nearby_handle_rx: Option<mpsc::Receiver<anyhow::Result<NearbyHandle>>>, // Receives the handle of the nearby service.
+    nearby: Option<Arc<NearbyHandle>>, // The running nearby devices service.
+    nearby_rx: Option<mpsc::Receiver<NearbyEvent>>, // Receives discovery events and incoming offers.
+    nearby_peers: Vec<NearbyPeer>, // Devices currently visible on the LAN.
+    incoming_offers: Vec<(String, String)>, // Tickets offered by nearby devices: (from, ticket).
 }
 
+/// Expiry options offered for persistent shares: (label, lifetime).
+const SHARE_EXPIRY_CHOICES: &[(&str, Option<Duration>)] = &[
+    ("Never expires", None),
+    ("1 hour", Some(Duration::from_secs(60 * 60))),
+    ("1 day", Some(Duration::from_secs(24 * 60 * 60))),
+    ("1 week", Some(Duration::from_secs(7 * 24 * 60 * 60))),
+];
+
 impl MyApp {
-    fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        Self {
+    fn new(
+        _cc: &eframe::CreationContext<'_>,
+        relay: RelaySetting,
+        rendezvous: Option<Url>,
+    ) -> Self {
+        let mut app = Self {
             ticket_input: String::new(),
             path_to_send: None,
             status_message: "Ready to work".to_string(),
             send_progress_rx: None,
             receive_progress_rx: None,
             tokio_rt: Arc::new(Runtime::new().expect("Failed to create Tokio runtime")),
-            send_handle_rx: None,
-            send_handle: None,
-            active_sends: Vec::new(),
+            daemon: None,
+            daemon_rx: None,
+            transfers: Vec::new(),
+            transfers_rx: None,
+            last_refresh: Instant::now(),
+            short_codes: HashMap::new(),
+            short_code_rx: None,
+            keep_share: false,
+            watch_folder: false,
+            encrypt_web: false,
+            share_expiry: 0,
             progress_value: 0.0,
             is_drag_hover: false,
             is_web_send_active: false,
+            qr_code: None,
+            relay_input: relay.to_string(),
+            relay,
+            relay_rx: None,
+            rendezvous_input: rendezvous.as_ref().map(Url::to_string).unwrap_or_default(),
+            rendezvous,
+            nearby_handle_rx: None,
+            nearby: None,
+            nearby_rx: None,
+            nearby_peers: Vec::new(),
+            incoming_offers: Vec::new(),
+            sync_receive: false,
+            upload_limit_input: String::new(),
+            download_limit_input: String::new(),
+            limit_inputs: HashMap::new(),
+            sync_delete: false,
+            sync_preview: None,
+            seed_receive: false,
+            receive_seeding: false,
+            compress_receive: false,
+            bridge_progress_rx: None,
+            inbox_password: String::new(),
+            inbox_max_size: String::new(),
+            inbox_progress_rx: None,
+            history: Vec::new(),
+            history_rx: None,
+            last_history_refresh: None,
+            diagnostics: None,
+            diagnostics_rx: None,
+            last_diagnostics_refresh: None,
+        };
+        // Nearby devices start once the daemon is connected.
+        app.connect_daemon();
+        app
+    }
+
+    // Connects to the background daemon, or runs one inside the app if none is running.
+    fn connect_daemon(&mut self) {
+        let (tx, rx) = mpsc::channel(1);
+        self.daemon_rx = Some(rx);
+        let relay = self.relay.clone();
+        self.tokio_rt.spawn(async move {
+            let daemon = match DaemonClient::connect().await {
+                Ok(daemon) => daemon,
+                Err(_) => DaemonClient::embedded(relay.clone()).await,
+            };
+            let limits = daemon.limits().await.unwrap_or_default();
+            let relay = daemon.relay().await.unwrap_or(relay);
+            let _ = tx.send((daemon, limits, relay)).await;
+        });
+    }
+
+    // Asks the daemon for its transfers in the background.
+    fn refresh_transfers(&mut self) {
+        let Some(daemon) = self.daemon.clone() else {
+            return;
+        };
+        let (tx, rx) = mpsc::channel(1);
+        self.transfers_rx = Some(rx);
+        self.last_refresh = Instant::now();
+        self.tokio_rt.spawn(async move {
+            let _ = tx.send(daemon.list().await).await;
+        });
+    }
+
+    // Loads the transfer history in the background.
+    fn refresh_history(&mut self) {
+        let (tx, rx) = mpsc::channel(1);
+        self.history_rx = Some(rx);
+        self.last_history_refresh = Some(Instant::now());
+        let loading = self.tokio_rt.spawn_blocking(history);
+        self.tokio_rt.spawn(async move {
+            let result = loading.await.unwrap_or_else(|e| Err(e.into()));
+            let _ = tx.send(result).await;
+        });
+    }
+
+    // Asks the daemon for a connectivity report in the background.
+    fn refresh_diagnostics(&mut self) {
+        let Some(daemon) = self.daemon.clone() else {
+            return;
+        };
+        let (tx, rx) = mpsc::channel(1);
+        self.diagnostics_rx = Some(rx);
+        self.last_diagnostics_refresh = Some(Instant::now());
+        self.tokio_rt.spawn(async move {
+            let _ = tx.send(daemon.diagnostics().await).await;
+        });
+    }
+
+    // Downloads a failed receive from the history again, into the same folder.
+    fn retry_receive(&mut self, entry: &HistoryEntry) {
+        let Some(ticket) = entry.ticket.clone() else {
+            return;
+        };
+        self.ticket_input = ticket;
+        self.start_receive_into(entry.path.clone(), entry.sync);
+    }
+
+    // Stops a transfer of the daemon; a persistent share is removed.
+    fn stop_transfer(&mut self, id: u64) {
+        let Some(daemon) = self.daemon.clone() else {
+            return;
+        };
+        self.tokio_rt.spawn(async move {
+            if let Err(e) = daemon.stop(id).await {
+                println!("Failed to stop transfer {}: {}", id, e);
+            }
+        });
+        self.transfers.retain(|t| t.id != id);
+    }
+
+    // Changes the limit of a running transfer; `input` is a rate like "2 MB" or "off".
+    fn set_transfer_limit(&mut self, id: u64, input: &str) {
+        let Some(daemon) = self.daemon.clone() else {
+            return;
+        };
+        let rate = match parse_rate(input) {
+            Ok(rate) => rate,
+            Err(e) => {
+                self.status_message = format!("Error: {:#}", e);
+                return;
+            }
+        };
+        self.status_message = match rate {
+            Some(_) => format!("Transfer limited to {}.", format_rate(rate)),
+            None => "Transfer no longer limited.".to_string(),
+        };
+        self.tokio_rt.spawn(async move {
+            if let Err(e) = daemon.set_transfer_limit(id, rate).await {
+                println!("Failed to limit transfer {}: {}", id, e);
+            }
+        });
+        self.last_refresh = Instant::now() - Duration::from_secs(60);
+    }
+
+    // Applies the global bandwidth limits from the settings.
+    fn apply_global_limits(&mut self) {
+        let Some(daemon) = self.daemon.clone() else {
+            return;
+        };
+        let limits = match (
+            parse_rate(&self.upload_limit_input),
+            parse_rate(&self.download_limit_input),
+        ) {
+            (Ok(upload), Ok(download)) => BandwidthLimits { upload, download },
+            (Err(e), _) | (_, Err(e)) => {
+                self.status_message = format!("Error: {:#}", e);
+                return;
+            }
+        };
+        self.upload_limit_input = format_limit_input(limits.upload);
+        self.download_limit_input = format_limit_input(limits.download);
+        self.status_message = format!(
+            "Upload limited to {}, download to {}.",
+            format_rate(limits.upload),
+            format_rate(limits.download)
+        );
+        self.tokio_rt.spawn(async move {
+            if let Err(e) = daemon.set_limits(limits).await {
+                println!("Failed to set bandwidth limits: {}", e);
+            }
+        });
+    }
+
+    // Restarts the daemon's node with another relay mode. Running transfers on it stop.
+    fn apply_relay(&mut self, relay: RelaySetting) {
+        let Some(daemon) = self.daemon.clone() else {
+            self.status_message = "Still starting up, try again in a moment.".to_string();
+            return;
+        };
+        self.status_message = format!("Restarting the node with relay mode {}...", relay);
+        let (tx, rx) = mpsc::channel(1);
+        self.relay_rx = Some(rx);
+        self.tokio_rt.spawn(async move {
+            let result = daemon.set_relay(relay.clone()).await.map(|()| relay);
+            let _ = tx.send(result).await;
+        });
+    }
+
+    // Starts sending the selected path as a ticket or web link, as a persistent share if requested.
+    fn start_send(&mut self, mode: ShareMode) {
+        let (Some(path), Some(daemon)) = (self.path_to_send.clone(), self.daemon.clone()) else {
+            return;
+        };
+        let request = SendRequest {
+            path,
+            mode,
+            keep: self.keep_share,
+            watch: self.watch_folder,
+            name: None,
+            expires_in: SHARE_EXPIRY_CHOICES[self.share_expiry]
+                .1
+                .map(|d| d.as_secs()),
+            limit: None,
+        };
+        let (progress_tx, progress_rx) = mpsc::channel(10);
+        self.send_progress_rx = Some(progress_rx);
+        self.tokio_rt.spawn(async move {
+            let mut updates = match daemon.send(request).await {
+                Ok(updates) => updates,
+                Err(e) => {
+                    let _ = progress_tx.send(SendStatus::Error(e.to_string())).await;
+                    return;
+                }
+            };
+            // Dropping the updates (e.g. on "Cancel sending") cancels a send that is not ready yet.
+            while let Ok(Some(update)) = updates.recv().await {
+                if progress_tx.send(update.status).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Shares the content of the ticket field as a web link that fetches it from the sender.
+    fn start_bridge(&mut self) {
+        let Some(daemon) = self.daemon.clone() else {
+            self.status_message = "Still starting up, try again in a moment.".to_string();
+            return;
+        };
+        let input = self.ticket_input.trim().to_string();
+        let rendezvous = self.rendezvous.clone();
+        let nearby = self.nearby.clone();
+        let (tx, rx) = mpsc::channel(10);
+        self.bridge_progress_rx = Some(rx);
+        self.tokio_rt.spawn(async move {
+            let ticket = if is_short_code(&input) {
+                match resolve_short_code(&input, rendezvous, nearby.as_deref()).await {
+                    Ok(ticket) => ticket,
+                    Err(e) => {
+                        let _ = tx.send(SendStatus::Error(e.to_string())).await;
+                        return;
+                    }
+                }
+            } else {
+                input
+            };
+            let request = BridgeRequest {
+                ticket,
+                limit: None,
+            };
+            match daemon.bridge(request).await {
+                Ok(mut updates) => {
+                    while let Ok(Some(update)) = updates.recv().await {
+                        if tx.send(update.status).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(SendStatus::Error(e.to_string())).await;
+                }
+            }
+        });
+    }
+
+    // Publishes a web page that saves uploads into a folder the user picks.
+    fn start_inbox(&mut self) {
+        let Some(daemon) = self.daemon.clone() else {
+            self.status_message = "Still starting up, try again in a moment.".to_string();
+            return;
+        };
+        let max_file_size = match parse_rate(&self.inbox_max_size) {
+            Ok(size) => size,
+            Err(e) => {
+                self.status_message = format!("Error: {:#}", e);
+                return;
+            }
+        };
+        let Some(target_dir) = FileDialog::new().pick_folder() else {
+            return;
+        };
+        let request = InboxRequest {
+            target_dir,
+            options: InboxOptions {
+                max_file_size,
+                max_total_size: None,
+                password: Some(self.inbox_password.clone()).filter(|p| !p.is_empty()),
+            },
+            limit: None,
+        };
+        let (progress_tx, progress_rx) = mpsc::channel(32);
+        self.inbox_progress_rx = Some(progress_rx);
+        self.tokio_rt.spawn(async move {
+            let mut updates = match daemon.request_files(request).await {
+                Ok(updates) => updates,
+                Err(e) => {
+                    let _ = progress_tx.send(InboxStatus::Error(e.to_string())).await;
+                    return;
+                }
+            };
+            while let Ok(Some(update)) = updates.recv().await {
+                if progress_tx.send(update.status).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // (Re)starts the nearby devices service on the node of the embedded daemon.
+    // A background daemon owns the node and the store, so this process cannot run
+    // a node of its own next to it.
+    fn restart_nearby(&mut self) {
+        self.nearby = None;
+        self.nearby_peers.clear();
+        self.nearby_rx = None;
+        self.nearby_handle_rx = None;
+        if self.daemon.as_ref().is_none_or(DaemonClient::is_remote) {
+            return;
         }
+        let (nearby_tx, nearby_rx) = mpsc::channel(32);
+        let (handle_tx, handle_rx) = mpsc::channel(1);
+        self.nearby_rx = Some(nearby_rx);
+        self.nearby_handle_rx = Some(handle_rx);
+        let relay = self.relay.clone();
+        self.tokio_rt.spawn(async move {
+            let handle_result = start_nearby(default_device_name(), relay, nearby_tx).await;
+            let _ = handle_tx.send(handle_result).await;
+        });
     }
 
     fn update_web_send_status(&mut self) {
-        self.is_web_send_active = self
-            .active_sends
-            .iter()
-            .any(|(_, _, send_type)| *send_type == SendType::Web);
+        self.is_web_send_active = self.transfers.iter().any(|t| {
+            matches!(
+                t.kind,
+                TransferKind::Send(ShareMode::Web | ShareMode::EncryptedWeb)
+                    | TransferKind::Inbox
+                    | TransferKind::Bridge
+            )
+        });
     }
 
     fn handle_progress_updates(&mut self) {
-        if let Some(ref mut rx) = self.send_handle_rx {
-            if let Ok(handle_result) = rx.try_recv() {
-                match handle_result {
-                    Ok(handle) => self.send_handle = Some(handle),
-                    Err(e) => {
-                        self.status_message = format!("Error during startup: {}", e);
-                        self.send_handle = None;
+        if let Some(ref mut rx) = self.daemon_rx {
+            if let Ok((daemon, limits, relay)) = rx.try_recv() {
+                self.upload_limit_input = format_limit_input(limits.upload);
+                self.download_limit_input = format_limit_input(limits.download);
+                self.daemon = Some(daemon);
+                self.daemon_rx = None;
+                // A daemon that was already running keeps its own relay mode.
+                self.relay_input = relay.to_string();
+                self.relay = relay;
+                // Start advertising on the LAN right away so other devices can see us.
+                self.restart_nearby();
+                self.refresh_transfers();
+            }
+        }
+        if let Some(ref mut rx) = self.relay_rx {
+            if let Ok(result) = rx.try_recv() {
+                match result {
+                    Ok(relay) => {
+                        self.status_message = format!("Relay mode set to {}.", relay);
+                        self.relay_input = relay.to_string();
+                        self.relay = relay;
+                        self.restart_nearby();
                     }
+                    Err(e) => self.status_message = format!("Error: {:#}", e),
+                }
+                self.relay_rx = None;
+            }
+        }
+        if let Some(ref mut rx) = self.transfers_rx {
+            if let Ok(result) = rx.try_recv() {
+                match result {
+                    Ok(transfers) => {
+                        self.transfers = transfers;
+                        self.update_web_send_status();
+                    }
+                    Err(e) => println!("Failed to list transfers: {}", e),
+                }
+                self.transfers_rx = None;
+            }
+        }
+        if self.transfers_rx.is_none() && self.last_refresh.elapsed() > Duration::from_secs(1) {
+            self.refresh_transfers();
+        }
+        if let Some(ref mut rx) = self.history_rx {
+            if let Ok(result) = rx.try_recv() {
+                match result {
+                    Ok(history) => self.history = history,
+                    Err(e) => println!("Failed to load the history: {}", e),
                 }
-                self.send_handle_rx = None;
+                self.history_rx = None;
+            }
+        }
+        if let Some(ref mut rx) = self.diagnostics_rx {
+            if let Ok(result) = rx.try_recv() {
+                self.diagnostics = Some(result);
+                self.diagnostics_rx = None;
             }
         }
 
@@ -100,19 +704,19 @@ impl MyApp {
                     }
                     SendStatus::ReadyToSend { ticket } => {
                         self.status_message = format!("Done! Click to copy:\n{}", ticket);
+                        self.show_qr_for(&ticket);
                         self.progress_value = 0.0;
-                        if let Some(handle) = self.send_handle.take() {
-                            let send_type = if ticket.starts_with("http") {
-                                SendType::Web
-                            } else {
-                                SendType::P2P
-                            };
-                            self.active_sends.push((ticket.clone(), handle, send_type));
-                            self.update_web_send_status();
-                        }
                         self.send_progress_rx = None;
                         self.path_to_send = None;
+                        self.refresh_transfers();
+                    }
+                    SendStatus::Updated { ticket } => {
+                        self.status_message = format!("Done! Click to copy:\n{}", ticket);
+                        self.show_qr_for(&ticket);
+                        self.refresh_transfers();
                     }
+                    // Uploads are shown with the transfer they belong to.
+                    SendStatus::Uploading { .. } | SendStatus::Uploaded { .. } => {}
                     SendStatus::Error(e) => {
                         self.status_message = format!("Error: {}", e);
                         self.reset_send_state();
@@ -125,6 +729,107 @@ impl MyApp {
             }
         }
 
+        // Process status updates of the web bridge; they end once its link is ready.
+        if let Some(ref mut rx) = self.bridge_progress_rx {
+            if let Ok(status) = rx.try_recv() {
+                match status {
+                    SendStatus::ReadyToSend { ticket } => {
+                        self.status_message = format!("Done! Click to copy:\n{}", ticket);
+                        self.show_qr_for(&ticket);
+                        self.bridge_progress_rx = None;
+                        self.refresh_transfers();
+                    }
+                    SendStatus::Error(e) => {
+                        self.status_message = format!("Error: {}", e);
+                        self.bridge_progress_rx = None;
+                    }
+                    _ => self.status_message = "Connecting to the sender...".to_string(),
+                }
+            }
+        }
+
+        // Process status updates of the file request; it reports uploads until it is stopped.
+        if let Some(ref mut rx) = self.inbox_progress_rx {
+            match rx.try_recv() {
+                Ok(InboxStatus::Connecting) => {
+                    self.status_message = "Connection...".to_string();
+                }
+                Ok(InboxStatus::Ready { link }) => {
+                    self.status_message = format!("Done! Click to copy:\n{}", link);
+                    self.show_qr_for(&link);
+                    self.refresh_transfers();
+                }
+                Ok(InboxStatus::Receiving {
+                    name,
+                    received,
+                    speed,
+                }) => {
+                    self.status_message = format!(
+                        "Receiving {}: {} ({})",
+                        name,
+                        bytesize::ByteSize(received),
+                        describe_speed(speed, None, PathKind::Web)
+                    );
+                }
+                Ok(InboxStatus::Received { name, size, path }) => {
+                    self.status_message = format!(
+                        "Received {} ({}) as {}",
+                        name,
+                        bytesize::ByteSize(size),
+                        path.display()
+                    );
+                }
+                Ok(InboxStatus::Rejected(reason)) => {
+                    self.status_message = format!("Refused an upload: {}", reason);
+                }
+                Ok(InboxStatus::Error(e)) => {
+                    self.status_message = format!("Error: {}", e);
+                    self.inbox_progress_rx = None;
+                }
+                Err(mpsc::error::TryRecvError::Disconnected) => self.inbox_progress_rx = None,
+                Err(mpsc::error::TryRecvError::Empty) => {}
+            }
+        }
+
+        // Process events from the nearby devices service.
+        if let Some(ref mut rx) = self.nearby_handle_rx {
+            if let Ok(handle_result) = rx.try_recv() {
+                match handle_result {
+                    Ok(handle) => self.nearby = Some(Arc::new(handle)),
+                    Err(e) => println!("Nearby devices unavailable: {}", e),
+                }
+                self.nearby_handle_rx = None;
+            }
+        }
+        if let Some(ref mut rx) = self.nearby_rx {
+            while let Ok(event) = rx.try_recv() {
+                match event {
+                    NearbyEvent::Discovered(peer) => {
+                        self.nearby_peers.retain(|p| p.id != peer.id);
+                        self.nearby_peers.push(peer);
+                    }
+                    NearbyEvent::Expired(id) => self.nearby_peers.retain(|p| p.id != id),
+                    NearbyEvent::Offer { from, ticket } => {
+                        self.status_message = format!("{} wants to send you files.", from);
+                        self.incoming_offers.push((from, ticket));
+                    }
+                }
+            }
+        }
+
+        if let Some(ref mut rx) = self.short_code_rx {
+            if let Ok((ticket, code_result)) = rx.try_recv() {
+                match code_result {
+                    Ok(code) => {
+                        self.status_message = format!("Short code: {}", code);
+                        self.short_codes.insert(ticket, code);
+                    }
+                    Err(e) => self.status_message = format!("Failed to create short code: {}", e),
+                }
+                self.short_code_rx = None;
+            }
+        }
+
         // Process status updates for the receiving operation.
         if let Some(ref mut rx) = self.receive_progress_rx {
             if let Ok(status) = rx.try_recv() {
@@ -143,11 +848,24 @@ impl MyApp {
                             bytesize::ByteSize(total_size)
                         );
                     }
-                    ReceiveStatus::Downloading { downloaded, total } => {
+                    ReceiveStatus::SyncPlan(plan) => {
+                        self.status_message = describe_sync_plan(&plan, self.sync_delete);
+                        if let Some((_, preview @ None)) = &mut self.sync_preview {
+                            *preview = Some(plan);
+                        }
+                    }
+                    ReceiveStatus::Downloading {
+                        downloaded,
+                        total,
+                        speed,
+                        eta,
+                        path,
+                    } => {
                         self.status_message = format!(
-                            "Download: {} / {}",
+                            "Download: {} / {} ({})",
                             bytesize::ByteSize(downloaded),
-                            bytesize::ByteSize(total)
+                            bytesize::ByteSize(total),
+                            describe_speed(speed, eta, path)
                         );
                         self.progress_value = if total > 0 {
                             downloaded as f32 / total as f32
@@ -155,32 +873,156 @@ impl MyApp {
                             0.0
                         };
                     }
+                    // Shown with the transfer in the list of transfers.
+                    ReceiveStatus::Sources(_) => {}
                     ReceiveStatus::Exporting {
                         done_files,
                         total_files,
                     } => {
                         self.status_message = format!("Download: {} / {}", done_files, total_files);
                     }
-                    ReceiveStatus::Done => {
-                        self.status_message = "Download complete!".to_string();
-                        self.receive_progress_rx = None;
+                    ReceiveStatus::Done(summary) => {
+                        if self.sync_preview.is_none() {
+                            self.status_message =
+                                format!("Download complete: {}", describe_summary(&summary));
+                        }
+                        // A seeded download reports its new ticket after this.
+                        if !self.receive_seeding {
+                            self.receive_progress_rx = None;
+                        }
                         self.progress_value = 0.0;
                     }
+                    ReceiveStatus::Seeding { id, .. } => {
+                        self.status_message =
+                            format!("Download complete, shared again as transfer {}.", id);
+                        self.receive_progress_rx = None;
+                    }
                     ReceiveStatus::Error(e) => {
                         self.status_message = format!("Download error: {}", e);
                         self.receive_progress_rx = None;
                         self.progress_value = 0.0;
+                        self.sync_preview = None;
+                    }
+                }
+            }
+        }
+    }
+
+    // Starts downloading whatever ticket (or short code) is currently in the ticket field.
+    fn start_receive(&mut self) {
+        match std::env::current_dir() {
+            Ok(dir) => self.start_receive_into(dir, None),
+            Err(e) => self.status_message = format!("Download error: {}", e),
+        }
+    }
+
+    // Compares the content of the ticket field with a folder and shows what a sync would change.
+    fn preview_sync(&mut self) {
+        let Some(dir) = FileDialog::new().pick_folder() else {
+            return;
+        };
+        self.sync_preview = Some((dir.clone(), None));
+        let options = SyncOptions {
+            delete: self.sync_delete,
+            dry_run: true,
+        };
+        self.start_receive_into(dir, Some(options));
+    }
+
+    // Downloads the content of the ticket field into `target_dir`.
+    fn start_receive_into(&mut self, target_dir: PathBuf, sync: Option<SyncOptions>) {
+        let Some(daemon) = self.daemon.clone() else {
+            self.status_message = "Still starting up, try again in a moment.".to_string();
+            return;
+        };
+        self.status_message = "Starting download...".to_string();
+        let rt = self.tokio_rt.clone();
+        // More tickets or node ids after the first are other peers to download from.
+        let mut words = self.ticket_input.split_whitespace().map(str::to_string);
+        let input = words.next().unwrap_or_default();
+        let sources: Vec<String> = words.collect();
+        let rendezvous = self.rendezvous.clone();
+        let nearby = self.nearby.clone();
+        let seed = self.seed_receive && !sync.is_some_and(|options| options.dry_run);
+        self.receive_seeding = seed;
+        let compress = self.compress_receive;
+        let (tx, rx) = mpsc::channel(32);
+        self.receive_progress_rx = Some(rx);
+        rt.spawn(async move {
+            let ticket = if is_short_code(&input) {
+                match resolve_short_code(&input, rendezvous, nearby.as_deref()).await {
+                    Ok(ticket) => ticket,
+                    Err(e) => {
+                        let _ = tx.send(ReceiveStatus::Error(e.to_string())).await;
+                        return;
                     }
                 }
+            } else {
+                input
+            };
+            let request = ReceiveRequest {
+                ticket,
+                sources,
+                target_dir,
+                sync,
+                limit: None,
+                seed,
+                compress,
+            };
+            match daemon.receive(request).await {
+                Ok(mut updates) => {
+                    while let Ok(Some(status)) = updates.recv().await {
+                        if tx.send(status).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(ReceiveStatus::Error(e.to_string())).await;
+                }
             }
+        });
+    }
+
+    // Creates a short code for an active ticket transfer in the background.
+    fn request_short_code(&mut self, ticket: String) {
+        self.status_message = "Creating short code...".to_string();
+        let rendezvous = self.rendezvous.clone();
+        let nearby = self.nearby.clone();
+        let (tx, rx) = mpsc::channel(1);
+        self.short_code_rx = Some(rx);
+        self.tokio_rt.spawn(async move {
+            let code_result =
+                create_short_code(ticket.clone(), rendezvous, nearby.as_deref()).await;
+            let _ = tx.send((ticket, code_result)).await;
+        });
+    }
+
+    // Shows a QR code for a ticket, web link or short code in the status area.
+    fn show_qr_for(&mut self, text: &str) {
+        match encode_qr(text) {
+            Ok(matrix) => self.qr_code = Some((text.to_string(), matrix)),
+            Err(e) => {
+                println!("Failed to create QR code: {}", e);
+                self.qr_code = None;
+            }
+        }
+    }
+
+    // Puts a ticket decoded from a QR code into the ticket field.
+    fn use_scanned_ticket(&mut self, scanned: anyhow::Result<String>) {
+        match scanned {
+            Ok(ticket) => {
+                self.ticket_input = ticket;
+                self.status_message = "Ticket read from QR code.".to_string();
+            }
+            Err(e) => self.status_message = format!("Error: {:#}", e),
         }
     }
 
     // Resets the state related to sending a file.
     fn reset_send_state(&mut self) {
         self.send_progress_rx = None;
-        self.send_handle = None;
-        self.send_handle_rx = None;
         self.progress_value = 0.0;
         self.path_to_send = None;
     }
@@ -269,22 +1111,10 @@ impl App for MyApp {
                                     );
                                     ui.add_space(8.0);
 
-                                    if self.send_handle.is_none() && self.send_handle_rx.is_none() {
+                                    if self.send_progress_rx.is_none() {
                                         ui.with_layout(Layout::top_down(Align::Center), |ui| {
                                             if ui.button("Send (ticket)").clicked() {
-                                                let path = self.path_to_send.clone().unwrap();
-                                                let (progress_tx, progress_rx) = mpsc::channel(10);
-                                                let (handle_tx, handle_rx) = mpsc::channel(1);
-                                                self.send_progress_rx = Some(progress_rx);
-                                                self.send_handle_rx = Some(handle_rx);
-                                                let rt = self.tokio_rt.clone();
-                                                let tokio_handle = rt.handle().clone();
-                                                rt.spawn(async move {
-                                                    let handle_result =
-                                                        send_file(path, progress_tx, tokio_handle)
-                                                            .await;
-                                                    let _ = handle_tx.send(handle_result).await;
-                                                });
+                                                self.start_send(ShareMode::Ticket);
                                             }
 
                                             let web_button_enabled = !self.is_web_send_active;
@@ -302,33 +1132,60 @@ impl App for MyApp {
                                                 .on_disabled_hover_text(web_button_tooltip)
                                                 .clicked()
                                             {
-                                                let path = self.path_to_send.clone().unwrap();
-                                                let (progress_tx, progress_rx) = mpsc::channel(10);
-                                                let (handle_tx, handle_rx) = mpsc::channel(1);
-                                                self.send_progress_rx = Some(progress_rx);
-                                                self.send_handle_rx = Some(handle_rx);
-                                                let rt = self.tokio_rt.clone();
-                                                let tokio_handle = rt.handle().clone();
-                                                rt.spawn(async move {
-                                                    let handle_result = start_http_send(
-                                                        path,
-                                                        progress_tx,
-                                                        tokio_handle,
-                                                    )
-                                                    .await;
-                                                    let _ = handle_tx.send(handle_result).await;
+                                                self.start_send(if self.encrypt_web {
+                                                    ShareMode::EncryptedWeb
+                                                } else {
+                                                    ShareMode::Web
                                                 });
                                             }
                                             if ui.button("Cancel").clicked() {
                                                 self.path_to_send = None;
                                             }
+                                            ui.horizontal(|ui| {
+                                                ui.checkbox(
+                                                    &mut self.keep_share,
+                                                    "Keep sharing after restart",
+                                                )
+                                                .on_hover_text(
+                                                    "Tickets stay the same; web links get a new address after a restart.",
+                                                );
+                                                egui::ComboBox::from_id_salt("share_expiry")
+                                                    .selected_text(
+                                                        SHARE_EXPIRY_CHOICES[self.share_expiry].0,
+                                                    )
+                                                    .show_ui(ui, |ui| {
+                                                        for (i, (label, _)) in
+                                                            SHARE_EXPIRY_CHOICES.iter().enumerate()
+                                                        {
+                                                            ui.selectable_value(
+                                                                &mut self.share_expiry,
+                                                                i,
+                                                                *label,
+                                                            );
+                                                        }
+                                                    });
+                                            });
+                                            ui.checkbox(
+                                                &mut self.watch_folder,
+                                                "Share changes automatically",
+                                            )
+                                            .on_hover_text(
+                                                "Watches the folder and shares every new version",
+                                            );
+                                            ui.checkbox(
+                                                &mut self.encrypt_web,
+                                                "Encrypt web link",
+                                            )
+                                            .on_hover_text(
+                                                "The key is part of the link, so the tunnel provider cannot read the file",
+                                            );
                                         });
                                     }
 
-                                    if let Some(_handle) = &self.send_handle {
-                                        if ui.button("Cancel sending").clicked() {
-                                            self.reset_send_state();
-                                        }
+                                    if self.send_progress_rx.is_some()
+                                        && ui.button("Cancel sending").clicked()
+                                    {
+                                        self.reset_send_state();
                                     }
                                     ui.add_space(10.0);
                                 }
@@ -351,7 +1208,7 @@ impl App for MyApp {
             ui.label(egui::RichText::new("ticket:").size(15.0));
             let text_edit_widget = egui::TextEdit::multiline(&mut self.ticket_input)
                 .font(egui::FontId::proportional(15.0))
-                .hint_text("Insert your ticket here...");
+                .hint_text("Insert your ticket or short code here...");
 
             let desired_width = ui.available_width();
             let desired_height = 80.0;
@@ -362,16 +1219,184 @@ impl App for MyApp {
             let button_width = 60.0;
             let button_height = 40.0;
 
-            if ui
-                .add_sized([button_width, button_height], get_button)
-                .clicked()
-            {
-                self.status_message = "Starting download...".to_string();
-                let rt = self.tokio_rt.clone();
-                let ticket = self.ticket_input.clone();
-                let (tx, rx) = mpsc::channel(32);
-                self.receive_progress_rx = Some(rx);
-                rt.spawn(async move { receive_file(ticket, tx).await });
+            ui.horizontal(|ui| {
+                if ui
+                    .add_sized([button_width, button_height], get_button)
+                    .clicked()
+                {
+                    if self.sync_receive {
+                        self.preview_sync();
+                    } else {
+                        self.start_receive();
+                    }
+                }
+                let bridge_enabled = !self.is_web_send_active && self.bridge_progress_rx.is_none();
+                if ui
+                    .add_enabled(bridge_enabled, Button::new("Share as web link"))
+                    .on_hover_text("Publishes a web link that fetches the file from the sender whenever it is opened")
+                    .on_disabled_hover_text("Only one web link can be active at a time")
+                    .clicked()
+                {
+                    self.start_bridge();
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.sync_receive, "Sync into an existing folder")
+                    .on_hover_text("Only new and changed files are downloaded");
+                if self.sync_receive {
+                    ui.checkbox(
+                        &mut self.sync_delete,
+                        "Delete files the sender no longer has",
+                    );
+                }
+            });
+            ui.checkbox(&mut self.seed_receive, "Share again after download")
+                .on_hover_text("Others can then download from this device instead of the sender");
+            ui.checkbox(&mut self.compress_receive, "Compress on the way")
+                .on_hover_text("Asks the sender to compress the files, which speeds up text and logs on slow connections");
+            ui.horizontal(|ui| {
+                let request_enabled = !self.is_web_send_active && self.inbox_progress_rx.is_none();
+                if ui
+                    .add_enabled(request_enabled, Button::new("Request files..."))
+                    .on_hover_text("Publishes a web page where anyone with the link can upload files into a folder")
+                    .on_disabled_hover_text("Only one web link can be active at a time")
+                    .clicked()
+                {
+                    self.start_inbox();
+                }
+                ui.label("Password:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.inbox_password)
+                        .password(true)
+                        .hint_text("none")
+                        .desired_width(100.0),
+                );
+                ui.label("Max file size:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.inbox_max_size)
+                        .hint_text("no limit")
+                        .desired_width(80.0),
+                );
+            });
+            let mut apply_sync = None;
+            let mut discard_sync = false;
+            if let Some((dir, Some(plan))) = &self.sync_preview {
+                ui.label(format!("Sync into {}:", dir.display()));
+                ui.collapsing(describe_sync_plan(plan, self.sync_delete), |ui| {
+                    for name in &plan.added {
+                        ui.label(format!("+ {}", name));
+                    }
+                    for name in &plan.changed {
+                        ui.label(format!("~ {}", name));
+                    }
+                    for name in &plan.removed {
+                        ui.label(format!("- {}", name));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Apply sync").clicked() {
+                        apply_sync = Some(dir.clone());
+                    }
+                    discard_sync = ui.button("Discard").clicked();
+                });
+            }
+            if let Some(dir) = apply_sync {
+                self.sync_preview = None;
+                let options = SyncOptions {
+                    delete: self.sync_delete,
+                    dry_run: false,
+                };
+                self.start_receive_into(dir, Some(options));
+            }
+            if discard_sync {
+                self.sync_preview = None;
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Scan QR from image...").clicked() {
+                    if let Some(path) = FileDialog::new()
+                        .add_filter("Images", &["png", "jpg", "jpeg", "bmp", "gif", "webp"])
+                        .pick_file()
+                    {
+                        self.use_scanned_ticket(decode_qr_file(&path));
+                    }
+                }
+                if ui.button("Scan QR from clipboard").clicked() {
+                    let scanned = arboard::Clipboard::new()
+                        .and_then(|mut clipboard| clipboard.get_image())
+                        .map_err(anyhow::Error::from)
+                        .and_then(|img| decode_qr_rgba(img.width, img.height, &img.bytes));
+                    self.use_scanned_ticket(scanned);
+                }
+            });
+
+            ui.separator();
+            ui.with_layout(Layout::top_down(Align::Center), |ui| {
+                ui.strong(RichText::new("Nearby devices").size(30.0));
+            });
+            match &self.nearby {
+                Some(nearby) => {
+                    ui.label(format!("Visible as \"{}\"", nearby.device_name()));
+                }
+                None if self.daemon.as_ref().is_some_and(DaemonClient::is_remote) => {
+                    ui.label("Not available while the background daemon runs the transfers.");
+                }
+                None => {
+                    ui.label("Searching the local network...");
+                }
+            }
+
+            let mut accepted = None;
+            self.incoming_offers.retain(|(from, ticket)| {
+                let mut keep = true;
+                ui.horizontal(|ui| {
+                    ui.label(format!("{} offers files.", from));
+                    if ui.button("Accept").clicked() {
+                        accepted = Some(ticket.clone());
+                        keep = false;
+                    }
+                    if ui.button("Decline").clicked() {
+                        keep = false;
+                    }
+                });
+                keep
+            });
+            if let Some(ticket) = accepted {
+                self.ticket_input = ticket;
+                self.start_receive();
+            }
+
+            if self.nearby_peers.is_empty() {
+                ui.label("No devices found.");
+            }
+            // Offer the most recent ticket-based transfer to the chosen device.
+            let latest_ticket = self
+                .transfers
+                .iter()
+                .rev()
+                .find(|t| t.kind == TransferKind::Send(ShareMode::Ticket))
+                .and_then(|t| t.ticket.clone());
+            for peer in &self.nearby_peers {
+                ui.horizontal(|ui| {
+                    ui.label(&peer.name);
+                    let can_send = latest_ticket.is_some() && self.nearby.is_some();
+                    if ui
+                        .add_enabled(can_send, Button::new("Send"))
+                        .on_disabled_hover_text("Start a ticket transfer first")
+                        .clicked()
+                    {
+                        if let (Some(nearby), Some(ticket)) =
+                            (self.nearby.clone(), latest_ticket.clone())
+                        {
+                            let peer = peer.clone();
+                            self.status_message = format!("Offering files to {}...", peer.name);
+                            self.tokio_rt.spawn(async move {
+                                if let Err(e) = nearby.offer(peer.id, ticket).await {
+                                    println!("Failed to offer ticket to {}: {}", peer.name, e);
+                                }
+                            });
+                        }
+                    }
+                });
             }
 
             ui.separator();
@@ -390,53 +1415,1093 @@ impl App for MyApp {
             } else {
                 ui.label(&self.status_message);
             }
+            let mut hide_qr = false;
+            if let Some((text, matrix)) = &self.qr_code {
+                ui.with_layout(Layout::top_down(Align::Center), |ui| {
+                    paint_qr(ui, matrix, 220.0);
+                    hide_qr = ui.small_button("Hide QR code").clicked();
+                })
+                .response
+                .on_hover_text(text);
+            }
+            if hide_qr {
+                self.qr_code = None;
+            }
 
             ui.separator();
             ui.with_layout(Layout::top_down(Align::Center), |ui| {
                 ui.strong(RichText::new("Active background transfers").size(30.0));
             });
 
-            if self.active_sends.is_empty() {
+            match &self.daemon {
+                Some(daemon) if daemon.is_remote() => {
+                    ui.label("Running in the background daemon; transfers continue after closing.");
+                }
+                Some(_) => {
+                    ui.label("No background daemon; transfers stop when the app closes.");
+                }
+                None => {
+                    ui.label("Starting...");
+                }
+            }
+            let active: Vec<&TransferInfo> = self
+                .transfers
+                .iter()
+                .filter(|t| t.share.is_none())
+                .collect();
+            if active.is_empty() {
                 ui.label("Empty.");
             }
 
-            let mut changed = false;
-            self.active_sends.retain(|(ticket, _handle, _send_type)| {
-                let mut keep = true;
+            let mut stopped = None;
+            let mut code_requested = None;
+            let mut qr_requested = None;
+            let mut limited = None;
+            for transfer in active {
                 ui.horizontal(|ui| {
+                    let ticket = transfer.ticket.as_deref().unwrap_or_default();
                     let display_ticket = if ticket.len() > 60 {
                         format!("{}...", &ticket[..60])
                     } else {
                         ticket.to_string()
                     };
-                    ui.label(&display_ticket);
+                    match transfer.kind {
+                        TransferKind::Receive => {
+                            ui.label(format!("Downloading {}", display_ticket));
+                        }
+                        TransferKind::Send(mode) => {
+                            ui.label(&display_ticket);
+                            if transfer.watch {
+                                ui.label("(watching)");
+                            }
+                            if ui.button("QR").clicked() {
+                                qr_requested = Some(ticket.to_string());
+                            }
+                            if mode == ShareMode::Ticket {
+                                match self.short_codes.get(ticket) {
+                                    Some(code) => {
+                                        if ui.button(code).on_hover_text("Click to copy").clicked()
+                                        {
+                                            ctx.copy_text(code.clone());
+                                        }
+                                    }
+                                    None => {
+                                        if ui
+                                            .add_enabled(
+                                                self.short_code_rx.is_none(),
+                                                Button::new("Short code"),
+                                            )
+                                            .clicked()
+                                        {
+                                            code_requested = Some(ticket.to_string());
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        TransferKind::Bridge => {
+                            ui.label(&display_ticket).on_hover_text(format!(
+                                "{}, fetched from the sender on demand",
+                                transfer.path.display()
+                            ));
+                            if ui.button("QR").clicked() {
+                                qr_requested = Some(ticket.to_string());
+                            }
+                        }
+                        TransferKind::Inbox => {
+                            ui.label(&display_ticket)
+                                .on_hover_text(format!("Uploads go to {}", transfer.path.display()));
+                            if ui.button("QR").clicked() {
+                                qr_requested = Some(ticket.to_string());
+                            }
+                        }
+                    }
+                    if let Some(progress) = &transfer.progress {
+                        ui.label(describe_progress(progress));
+                        for source in &transfer.sources {
+                            ui.label(describe_source(source));
+                        }
+                    } else if let Some(summary) = &transfer.summary {
+                        ui.label(format!("Last upload: {}", describe_summary(summary)));
+                    }
+                    let input = self.limit_inputs.entry(transfer.id).or_default();
+                    if let Some(input) = limit_field(ui, input, transfer.limit) {
+                        limited = Some((transfer.id, input));
+                    }
                     if ui.button("Stop").clicked() {
                         self.status_message = "Background transmission stopped.".to_string();
-                        keep = false;
-                        changed = true;
+                        stopped = Some((transfer.id, ticket.to_string()));
                     }
                 });
-                keep
-            });
+            }
 
-            if changed {
+            if let Some((id, input)) = limited {
+                self.set_transfer_limit(id, &input);
+            }
+            if let Some((id, ticket)) = stopped {
+                self.short_codes.remove(&ticket);
+                self.limit_inputs.remove(&id);
+                self.stop_transfer(id);
+                self.update_web_send_status();
+            }
+            if let Some(ticket) = code_requested {
+                self.request_short_code(ticket);
+            }
+            if let Some(ticket) = qr_requested {
+                self.show_qr_for(&ticket);
+            }
+
+            ui.separator();
+            ui.with_layout(Layout::top_down(Align::Center), |ui| {
+                ui.strong(RichText::new("My shares").size(30.0));
+            });
+            let shares: Vec<(u64, &ShareInfo, Option<u64>)> = self
+                .transfers
+                .iter()
+                .filter_map(|t| t.share.as_ref().map(|share| (t.id, share, t.limit)))
+                .collect();
+            if shares.is_empty() {
+                ui.label("Empty.");
+            }
+            let mut removed = None;
+            let mut qr_requested = None;
+            let mut limited = None;
+            for (id, share, limit) in shares {
+                ui.horizontal(|ui| {
+                    let mode = match share.mode {
+                        ShareMode::Ticket => "ticket",
+                        ShareMode::Web => "web",
+                        ShareMode::EncryptedWeb => "encrypted web",
+                    };
+                    ui.label(format!(
+                        "{} ({}, {})",
+                        share.name,
+                        mode,
+                        format_expiry(share.expires)
+                    ))
+                    .on_hover_text(share.path.display().to_string());
+                    if share.link_changed {
+                        ui.colored_label(Color32::YELLOW, "New link")
+                            .on_hover_text("Web links change when the app restarts. The old link no longer works.");
+                    }
+                    if let Some(ticket) = &share.ticket {
+                        if ui.button("Copy").clicked() {
+                            ctx.copy_text(ticket.clone());
+                        }
+                        if ui.button("QR").clicked() {
+                            qr_requested = Some(ticket.clone());
+                        }
+                    }
+                    let input = self.limit_inputs.entry(id).or_default();
+                    if let Some(input) = limit_field(ui, input, limit) {
+                        limited = Some((id, input));
+                    }
+                    if ui.button("Remove").clicked() {
+                        removed = Some(id);
+                    }
+                });
+            }
+            if let Some((id, input)) = limited {
+                self.set_transfer_limit(id, &input);
+            }
+            if let Some(id) = removed {
+                self.limit_inputs.remove(&id);
+                self.stop_transfer(id);
                 self.update_web_send_status();
+                self.status_message = "Share removed.".to_string();
+            }
+            if let Some(ticket) = qr_requested {
+                self.show_qr_for(&ticket);
             }
 
+            ui.separator();
+            let history_open = ui
+                .collapsing("History", |ui| {
+                    if self.history.is_empty() {
+                        ui.label("No finished transfers yet.");
+                    }
+                    let mut retried = None;
+                    let mut shared_again = None;
+                    for entry in self.history.iter().rev() {
+                        ui.horizontal(|ui| {
+                            ui.label(describe_history_entry(entry))
+                                .on_hover_text(entry.path.display().to_string());
+                            if ui.button("Open folder").clicked() {
+                                let folder = match entry.kind {
+                                    HistoryKind::Send if !entry.path.is_dir() => {
+                                        entry.path.parent().unwrap_or(&entry.path)
+                                    }
+                                    _ => &entry.path,
+                                };
+                                if let Err(e) = open_folder(folder) {
+                                    self.status_message = format!("Error: {}", e);
+                                }
+                            }
+                            if let Some(hash) = entry.hash {
+                                if ui.button("Copy hash").clicked() {
+                                    ctx.copy_text(hash.to_string());
+                                }
+                            }
+                            match entry.kind {
+                                HistoryKind::Send => {
+                                    if ui
+                                        .add_enabled(
+                                            entry.path.exists(),
+                                            Button::new("Share again"),
+                                        )
+                                        .on_disabled_hover_text("The path no longer exists")
+                                        .clicked()
+                                    {
+                                        shared_again = Some(entry.path.clone());
+                                    }
+                                }
+                                HistoryKind::Receive => {
+                                    if entry.is_failed()
+                                        && entry.ticket.is_some()
+                                        && ui.button("Retry").clicked()
+                                    {
+                                        retried = Some(entry.clone());
+                                    }
+                                }
+                            }
+                        });
+                    }
+                    if ui.button("Clear history").clicked() {
+                        match clear_history() {
+                            Ok(()) => self.history.clear(),
+                            Err(e) => self.status_message = format!("Error: {}", e),
+                        }
+                    }
+                    if let Some(path) = shared_again {
+                        self.path_to_send = Some(path);
+                        self.status_message = "Path chosen again, ready to send.".to_string();
+                    }
+                    if let Some(entry) = retried {
+                        self.retry_receive(&entry);
+                    }
+                })
+                .body_returned
+                .is_some();
+            // The history is only reloaded while it is shown.
+            let history_stale = self
+                .last_history_refresh
+                .is_none_or(|refreshed| refreshed.elapsed() > Duration::from_secs(2));
+            if history_open && self.history_rx.is_none() && history_stale {
+                self.refresh_history();
+            }
+
+            let diagnostics_open = ui
+                .collapsing("Diagnostics", |ui| match &self.diagnostics {
+                    Some(Ok(diagnostics)) => {
+                        for line in describe_diagnostics(diagnostics) {
+                            ui.label(line);
+                        }
+                        if ui.button("Copy node id").clicked() {
+                            ctx.copy_text(diagnostics.node_id.to_string());
+                        }
+                    }
+                    Some(Err(e)) => {
+                        ui.label(format!("Error: {}", e));
+                    }
+                    None => {
+                        ui.label("Checking the network...");
+                    }
+                })
+                .body_returned
+                .is_some();
+            // The report is only refreshed while it is shown.
+            let diagnostics_stale = self
+                .last_diagnostics_refresh
+                .is_none_or(|refreshed| refreshed.elapsed() > Duration::from_secs(2));
+            if diagnostics_open && self.diagnostics_rx.is_none() && diagnostics_stale {
+                self.refresh_diagnostics();
+            }
+
+            ui.collapsing("Settings", |ui| {
+                ui.label("Relay: \"default\", \"disabled\" or comma-separated relay URLs. Applying restarts the node and stops its transfers.");
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.relay_input);
+                    if ui.button("Apply").clicked() {
+                        match self.relay_input.parse::<RelaySetting>() {
+                            Ok(relay) => self.apply_relay(relay),
+                            Err(e) => self.status_message = format!("Error: {:#}", e),
+                        }
+                    }
+                });
+                ui.label("Rendezvous server for short codes (empty: local network only)");
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.rendezvous_input);
+                    if ui.button("Apply").clicked() {
+                        let input = self.rendezvous_input.trim();
+                        if input.is_empty() {
+                            self.rendezvous = None;
+                            self.status_message = "Rendezvous server removed.".to_string();
+                        } else {
+                            match input.parse::<Url>() {
+                                Ok(url) => {
+                                    self.status_message =
+                                        format!("Rendezvous server set to {}.", url);
+                                    self.rendezvous = Some(url);
+                                }
+                                Err(e) => self.status_message = format!("Error: {}", e),
+                            }
+                        }
+                    }
+                });
+                ui.label("Bandwidth limits per second for all transfers, e.g. \"2 MB\" (empty: unlimited)");
+                ui.horizontal(|ui| {
+                    ui.label("Upload");
+                    ui.add(egui::TextEdit::singleline(&mut self.upload_limit_input).desired_width(80.0));
+                    ui.label("Download");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.download_limit_input).desired_width(80.0),
+                    );
+                    if ui.button("Apply").clicked() {
+                        self.apply_global_limits();
+                    }
+                });
+            });
+
             ctx.request_repaint_after(std::time::Duration::from_millis(100));
         });
     }
+
+    // Stops the transfers of an embedded daemon before the window closes.
+    // A background daemon keeps running.
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.nearby = None;
+        match self.daemon.take() {
+            Some(daemon) if !daemon.is_remote() => {
+                if let Err(e) = self.tokio_rt.block_on(daemon.shutdown()) {
+                    println!("Failed to stop transfers: {}", e);
+                }
+            }
+            _ => self.tokio_rt.block_on(p2p_client::shutdown()),
+        }
+    }
+}
+
+// Summarizes what a sync receive changes, e.g. "2 new, 1 changed, 5 unchanged".
+fn describe_sync_plan(plan: &SyncPlan, delete: bool) -> String {
+    let mut summary = format!(
+        "{} new, {} changed, {} unchanged",
+        plan.added.len(),
+        plan.changed.len(),
+        plan.unchanged
+    );
+    if !plan.removed.is_empty() {
+        let action = if delete { "to delete" } else { "kept" };
+        summary.push_str(&format!(", {} {}", plan.removed.len(), action));
+    }
+    summary.push_str(&format!(
+        " ({} to download)",
+        bytesize::ByteSize(plan.transfer_size)
+    ));
+    summary
+}
+
+// Shows a small text field for the limit of a running transfer.
+// Returns the entered text once Enter is pressed.
+fn limit_field(ui: &mut Ui, input: &mut String, current: Option<u64>) -> Option<String> {
+    let response = ui
+        .add(
+            egui::TextEdit::singleline(input)
+                .hint_text(format_rate(current))
+                .desired_width(80.0),
+        )
+        .on_hover_text(
+            "Limit of this transfer per second, e.g. \"2 MB\" or \"off\". Press Enter to apply.",
+        );
+    let submitted = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+    submitted.then(|| std::mem::take(input))
+}
+
+// Describes a rate limit, e.g. "2.0 MB/s" or "unlimited".
+fn format_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => format!("{}/s", bytesize::ByteSize(rate).display().si()),
+        None => "unlimited".to_string(),
+    }
+}
+
+// Text for a limit field; empty if unlimited.
+fn format_limit_input(rate: Option<u64>) -> String {
+    rate.map(|rate| bytesize::ByteSize(rate).display().si().to_string())
+        .unwrap_or_default()
+}
+
+// Describes how fast a transfer moves, e.g. "2.1 MB/s, 40s left, direct".
+// Until the speed has been measured, only the path is shown.
+fn describe_speed(speed: u64, eta: Option<u64>, path: PathKind) -> String {
+    let mut text = String::new();
+    if speed > 0 {
+        text.push_str(&format!("{}/s, ", bytesize::ByteSize(speed).display().si()));
+    }
+    if let Some(eta) = eta {
+        text.push_str(&format!("{} left, ", format_duration(eta)));
+    }
+    text.push_str(&path.to_string());
+    text
+}
+
+// Describes a running transfer, e.g. "12.0 MB of 80.0 MB, 2.1 MB/s, 40s left, direct".
+fn describe_progress(progress: &TransferProgress) -> String {
+    let mut text = bytesize::ByteSize(progress.bytes)
+        .display()
+        .si()
+        .to_string();
+    if let Some(total) = progress.total {
+        text.push_str(&format!(" of {}", bytesize::ByteSize(total).display().si()));
+    }
+    format!(
+        "{}, {}",
+        text,
+        describe_speed(progress.speed, progress.eta, progress.path)
+    )
+}
+
+// Describes what one peer of a download contributed, e.g. "from 4b1c2d3e4f: 2.0 MB, 3 files,
+// direct".
+fn describe_source(source: &SourceProgress) -> String {
+    let mut text = format!(
+        "from {}: {}, {} files, {}",
+        source.peer.fmt_short(),
+        bytesize::ByteSize(source.bytes).display().si(),
+        source.files,
+        source.path
+    );
+    if let Some(error) = &source.error {
+        text.push_str(&format!(", failed: {}", error));
+    }
+    text
+}
+
+// Describes a finished transfer, e.g. "80.0 MB in 12s (6.7 MB/s, direct)".
+fn describe_summary(summary: &TransferSummary) -> String {
+    if summary.bytes == 0 {
+        return "nothing transferred".to_string();
+    }
+    format!(
+        "{} in {} ({}/s, {})",
+        bytesize::ByteSize(summary.bytes).display().si(),
+        format_duration(summary.duration.as_secs()),
+        bytesize::ByteSize(summary.average_speed()).display().si(),
+        summary.path
+    )
+}
+
+// Formats seconds as e.g. "40s", "3m 05s" or "2h 10m".
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, secs) => format!("{}s", secs),
+        (0, minutes, secs) => format!("{}m {:02}s", minutes, secs),
+        (hours, minutes, _) => format!("{}h {:02}m", hours, minutes),
+    }
+}
+
+// Short form of a peer's node id.
+fn format_peer(peer: Option<EndpointId>) -> String {
+    peer.map(|peer| peer.fmt_short().to_string())
+        .unwrap_or_else(|| "unknown peer".to_string())
+}
+
+// Describes a connectivity report, one line per fact.
+fn describe_diagnostics(diagnostics: &Diagnostics) -> Vec<String> {
+    let join = |addrs: &[SocketAddr]| {
+        if addrs.is_empty() {
+            "none".to_string()
+        } else {
+            addrs
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    };
+    let public: Vec<SocketAddr> = diagnostics
+        .public_ipv4
+        .into_iter()
+        .chain(diagnostics.public_ipv6)
+        .collect();
+    let mut lines = vec![
+        format!("Node id: {}", diagnostics.node_id),
+        format!("Listening on: {}", join(&diagnostics.bound_sockets)),
+        format!("Direct addresses: {}", join(&diagnostics.direct_addresses)),
+        format!("Public addresses: {}", join(&public)),
+        format!("NAT: {}", diagnostics.nat),
+    ];
+    lines.push(match (&diagnostics.home_relay, diagnostics.relay_latency) {
+        (Some(relay), Some(latency)) => {
+            format!("Home relay: {} ({} ms)", relay, latency.as_millis())
+        }
+        (Some(relay), None) => format!("Home relay: {}", relay),
+        (None, _) => "Home relay: none".to_string(),
+    });
+    if diagnostics.captive_portal == Some(true) {
+        lines.push(
+            "A captive portal seems to intercept web traffic; log in to the network first."
+                .to_string(),
+        );
+    }
+    if diagnostics.connections.is_empty() {
+        lines.push("No transfers are connected to a peer.".to_string());
+    }
+    for connection in &diagnostics.connections {
+        let mut line = format!("Peer {}: {}", connection.peer.fmt_short(), connection.path);
+        if let Some(addr) = connection.remote_addr {
+            line.push_str(&format!(" to {}", addr));
+        }
+        if let Some(relay) = &connection.relay {
+            line.push_str(&format!(" via {}", relay));
+        }
+        if let Some(latency) = connection.latency {
+            line.push_str(&format!(", {} ms", latency.as_millis()));
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// Describes a history entry, e.g. "3m ago: sent /photos to 1a2b3c4d5e: 8.0 MB in 3s (...)".
+fn describe_history_entry(entry: &HistoryEntry) -> String {
+    let what = match entry.kind {
+        HistoryKind::Send if entry.outcome == TransferOutcome::Cancelled => {
+            format!("shared {}", entry.path.display())
+        }
+        HistoryKind::Send => format!(
+            "sent {} to {}",
+            entry.path.display(),
+            format_peer(entry.peer)
+        ),
+        HistoryKind::Receive => format!(
+            "received {} into {} from {}",
+            describe_files(entry),
+            entry.path.display(),
+            format_peer(entry.peer)
+        ),
+    };
+    let outcome = match &entry.outcome {
+        TransferOutcome::Completed(summary) => describe_summary(summary),
+        TransferOutcome::Failed(e) => format!("failed: {}", e),
+        TransferOutcome::Cancelled => "stopped before anyone fetched it".to_string(),
+    };
+    format!("{}: {}: {}", format_age(entry.time), what, outcome)
+}
+
+// Describes the files of a transfer, e.g. "3 files (12.0 MB)".
+fn describe_files(entry: &HistoryEntry) -> String {
+    let size = bytesize::ByteSize(entry.total_size()).display().si();
+    match entry.files.as_slice() {
+        [] => "nothing".to_string(),
+        [file] => format!("{} ({})", file.name, size),
+        files => format!("{} files ({})", files.len(), size),
+    }
+}
+
+// Describes how long ago a Unix timestamp was, e.g. "5m ago".
+fn format_age(time: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let age = now.saturating_sub(time);
+    match (age / 86400, age / 3600, age / 60) {
+        (0, 0, 0) => "just now".to_string(),
+        (0, 0, minutes) => format!("{}m ago", minutes),
+        (0, hours, _) => format!("{}h ago", hours),
+        (days, _, _) => format!("{}d ago", days),
+    }
+}
+
+// Opens a folder in the system's file manager.
+fn open_folder(path: &Path) -> std::io::Result<()> {
+    let opener = if cfg!(target_os = "windows") {
+        "explorer"
+    } else if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    std::process::Command::new(opener).arg(path).spawn()?;
+    Ok(())
+}
+
+// Describes when a share expires, e.g. "expires in 5h 12m".
+fn format_expiry(expires: Option<u64>) -> String {
+    let Some(expires) = expires else {
+        return "never expires".to_string();
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let left = expires.saturating_sub(now);
+    match (left / 86400, left / 3600 % 24, left / 60 % 60) {
+        (0, 0, minutes) => format!("expires in {}m", minutes.max(1)),
+        (0, hours, minutes) => format!("expires in {}h {}m", hours, minutes),
+        (days, hours, _) => format!("expires in {}d {}h", days, hours),
+    }
+}
+
+// Paints a QR code as black modules on a white square, including the quiet zone scanners need.
+fn paint_qr(ui: &mut Ui, matrix: &QrMatrix, size: f32) {
+    let (response, painter) = ui.allocate_painter(Vec2::splat(size), Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0.0, Color32::WHITE);
+    let quiet_zone = 4;
+    let module = size / (matrix.width + quiet_zone * 2) as f32;
+    for y in 0..matrix.width {
+        for x in 0..matrix.width {
+            if matrix.is_dark(x, y) {
+                let min = rect.min
+                    + Vec2::new(
+                        (x + quiet_zone) as f32 * module,
+                        (y + quiet_zone) as f32 * module,
+                    );
+                painter.rect_filled(
+                    Rect::from_min_size(min, Vec2::splat(module)),
+                    0.0,
+                    Color32::BLACK,
+                );
+            }
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     // Initialize the crypto provider for secure connections.
     let _ = CryptoProvider::install_default(rustls::crypto::ring::default_provider());
+    let args = Args::parse();
+    if let Some(Command::RendezvousServer { listen }) = args.command {
+        Runtime::new()?.block_on(run_rendezvous_server(listen))?;
+        return Ok(());
+    }
+    // The CLI flag wins over the environment variable.
+    let relay = match args.relay {
+        Some(relay) => relay,
+        None => RelaySetting::from_env()?,
+    };
+    println!(
+        "Using relay mode: {} (set with --relay or {})",
+        relay, RELAY_ENV_VAR
+    );
+    let rendezvous = match args.rendezvous {
+        Some(url) => Some(url),
+        None => rendezvous_from_env()?,
+    };
+    if let Some(command) = args.command {
+        Runtime::new()?.block_on(run_command(command, relay, rendezvous))?;
+        return Ok(());
+    }
+
     let native_options = eframe::NativeOptions::default();
     // Run the eframe application.
     eframe::run_native(
         "transfers GUI",
         native_options,
-        Box::new(|cc| Ok(Box::new(MyApp::new(cc)))),
+        Box::new(|cc| Ok(Box::new(MyApp::new(cc, relay, rendezvous)))),
     )?;
     Ok(())
 }
+
+// Runs a command line subcommand against the background daemon.
+async fn run_command(
+    command: Command,
+    relay: RelaySetting,
+    rendezvous: Option<Url>,
+) -> anyhow::Result<()> {
+    match command {
+        Command::Daemon => return run_daemon(relay).await,
+        // The history is read from the data dir, so it does not need the daemon.
+        Command::History { clear: true } => {
+            clear_history()?;
+            println!("History cleared.");
+            return Ok(());
+        }
+        Command::History { clear: false } => {
+            let entries = history()?;
+            if entries.is_empty() {
+                println!("No finished transfers.");
+            }
+            for entry in entries {
+                let hash = entry.hash.map(|h| h.to_string()).unwrap_or_default();
+                println!("{}\t{}", describe_history_entry(&entry), hash);
+            }
+            return Ok(());
+        }
+        // Files in use are locked by their process, so this is safe while a daemon runs.
+        Command::Cleanup => {
+            let report = clean_up()?;
+            for path in &report.removed {
+                println!("Removed {}", path.display());
+            }
+            println!(
+                "Removed {} leftovers, reclaiming {}.",
+                report.removed.len(),
+                bytesize::ByteSize(report.bytes).display().si()
+            );
+            return Ok(());
+        }
+        // Without a daemon, a temporary node is started to look at the network.
+        Command::Doctor => {
+            let diagnostics = match DaemonClient::connect().await {
+                Ok(daemon) => daemon.diagnostics().await?,
+                Err(_) => {
+                    println!("No daemon is running, checking with a temporary node.");
+                    let result = diagnose(relay).await;
+                    p2p_client::shutdown().await;
+                    result?
+                }
+            };
+            for line in describe_diagnostics(&diagnostics) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    let daemon = DaemonClient::connect()
+        .await
+        .context("Start the daemon first with `p2p-client daemon`")?;
+    // The daemon keeps the relay mode it was started with.
+    if matches!(
+        command,
+        Command::Send { .. } | Command::Receive { .. } | Command::Bridge { .. }
+    ) {
+        let running = daemon.relay().await?;
+        if running != relay {
+            println!(
+                "The daemon uses relay mode {}. Change it with `p2p-client relay {}`.",
+                running, relay
+            );
+        }
+    }
+    match command {
+        Command::RendezvousServer { .. }
+        | Command::Daemon
+        | Command::History { .. }
+        | Command::Cleanup
+        | Command::Doctor => unreachable!(),
+        Command::StopDaemon => {
+            daemon.shutdown().await?;
+            println!("Daemon stopped.");
+        }
+        Command::Send {
+            path,
+            web,
+            encrypt,
+            keep,
+            name,
+            expires,
+            watch,
+            limit,
+        } => {
+            // The daemon runs in another directory, so relative paths must be resolved here.
+            let path = path.canonicalize().context("Invalid path")?;
+            let mode = match (web, encrypt) {
+                (true, true) => ShareMode::EncryptedWeb,
+                (true, false) => ShareMode::Web,
+                (false, _) => ShareMode::Ticket,
+            };
+            let request = SendRequest {
+                path,
+                mode,
+                keep,
+                name,
+                expires_in: expires,
+                watch,
+                limit: limit.as_deref().map(parse_rate).transpose()?.flatten(),
+            };
+            let mut updates = daemon.send(request).await?;
+            while let Some(update) = updates.recv().await? {
+                match update.status {
+                    SendStatus::Connecting => println!("Preparing..."),
+                    SendStatus::Importing {
+                        total_files,
+                        done_files,
+                        ..
+                    } => println!("Importing {}/{} files...", done_files, total_files),
+                    SendStatus::ReadyToSend { ticket } => {
+                        println!("Transfer {} is ready:\n{}", update.id, ticket);
+                        if !watch {
+                            return Ok(());
+                        }
+                        println!("Watching for changes. Press Ctrl-C to stop following; the share keeps running.");
+                    }
+                    SendStatus::Updated { ticket } => println!("Updated:\n{}", ticket),
+                    SendStatus::Uploading {
+                        peer,
+                        sent,
+                        speed,
+                        path,
+                    } => println!(
+                        "Sending to {}: {} ({})",
+                        format_peer(peer),
+                        bytesize::ByteSize(sent).display().si(),
+                        describe_speed(speed, None, path)
+                    ),
+                    SendStatus::Uploaded { peer, summary } => println!(
+                        "Sent to {}: {}",
+                        format_peer(peer),
+                        describe_summary(&summary)
+                    ),
+                    SendStatus::Done => return Ok(()),
+                    SendStatus::Error(e) => anyhow::bail!(e),
+                }
+            }
+            anyhow::bail!("The daemon stopped the transfer");
+        }
+        Command::Receive {
+            ticket,
+            sources,
+            dir,
+            sync,
+            delete,
+            dry_run,
+            limit,
+            seed,
+            compress,
+        } => {
+            let ticket = if is_short_code(&ticket) {
+                resolve_short_code(&ticket, rendezvous.clone(), None).await?
+            } else {
+                ticket
+            };
+            let mut resolved = Vec::new();
+            for source in sources {
+                resolved.push(if is_short_code(&source) {
+                    resolve_short_code(&source, rendezvous.clone(), None).await?
+                } else {
+                    source
+                });
+            }
+            let dir = match dir {
+                Some(dir) => dir,
+                None => std::env::current_dir()?,
+            };
+            let dir = dir.canonicalize().context("Invalid target directory")?;
+            let sync = sync.then_some(SyncOptions { delete, dry_run });
+            let limit = limit.as_deref().map(parse_rate).transpose()?.flatten();
+            let request = ReceiveRequest {
+                ticket,
+                sources: resolved,
+                target_dir: dir,
+                sync,
+                limit,
+                seed,
+                compress,
+            };
+            let mut updates = daemon.receive(request).await?;
+            while let Some(status) = updates.recv().await? {
+                match status {
+                    ReceiveStatus::Connecting => println!("Connecting..."),
+                    ReceiveStatus::Connected {
+                        total_files,
+                        total_size,
+                    } => println!("Receiving {} files ({} bytes)...", total_files, total_size),
+                    ReceiveStatus::SyncPlan(plan) => {
+                        for name in &plan.added {
+                            println!("+ {}", name);
+                        }
+                        for name in &plan.changed {
+                            println!("~ {}", name);
+                        }
+                        for name in &plan.removed {
+                            println!("- {}", name);
+                        }
+                        println!("{}", describe_sync_plan(&plan, delete));
+                    }
+                    ReceiveStatus::Downloading {
+                        downloaded,
+                        total,
+                        speed,
+                        eta,
+                        path,
+                    } => println!(
+                        "Downloaded {}/{} bytes ({})",
+                        downloaded,
+                        total,
+                        describe_speed(speed, eta, path)
+                    ),
+                    ReceiveStatus::Sources(sources) => {
+                        for source in &sources {
+                            println!("  {}", describe_source(source));
+                        }
+                    }
+                    ReceiveStatus::Exporting {
+                        total_files,
+                        done_files,
+                    } => println!("Exporting {}/{} files...", done_files, total_files),
+                    ReceiveStatus::Done(summary) => {
+                        if !dry_run {
+                            println!("Download complete: {}", describe_summary(&summary));
+                        }
+                        if !seed {
+                            return Ok(());
+                        }
+                    }
+                    ReceiveStatus::Seeding { id, ticket } => {
+                        println!("Sharing the download again as transfer {}:", id);
+                        println!("{}", ticket);
+                        return Ok(());
+                    }
+                    ReceiveStatus::Error(e) => anyhow::bail!(e),
+                }
+            }
+            anyhow::bail!("The daemon stopped the transfer");
+        }
+        Command::Bridge { ticket, limit } => {
+            let ticket = if is_short_code(&ticket) {
+                resolve_short_code(&ticket, rendezvous.clone(), None).await?
+            } else {
+                ticket
+            };
+            let request = BridgeRequest {
+                ticket,
+                limit: limit.as_deref().map(parse_rate).transpose()?.flatten(),
+            };
+            let mut updates = daemon.bridge(request).await?;
+            while let Some(update) = updates.recv().await? {
+                match update.status {
+                    SendStatus::ReadyToSend { ticket } => {
+                        println!("Transfer {} is ready:\n{}", update.id, ticket);
+                        return Ok(());
+                    }
+                    SendStatus::Error(e) => anyhow::bail!(e),
+                    _ => println!("Connecting to the sender..."),
+                }
+            }
+            anyhow::bail!("The daemon stopped the transfer");
+        }
+        Command::Request {
+            dir,
+            max_file_size,
+            max_total_size,
+            password,
+            limit,
+        } => {
+            let dir = match dir {
+                Some(dir) => dir,
+                None => std::env::current_dir()?,
+            };
+            let request = InboxRequest {
+                target_dir: dir.canonicalize().context("Invalid target directory")?,
+                options: InboxOptions {
+                    max_file_size: max_file_size.map(|size| size.as_u64()),
+                    max_total_size: max_total_size.map(|size| size.as_u64()),
+                    password,
+                },
+                limit: limit.as_deref().map(parse_rate).transpose()?.flatten(),
+            };
+            let mut updates = daemon.request_files(request).await?;
+            while let Some(update) = updates.recv().await? {
+                match update.status {
+                    InboxStatus::Connecting => println!("Preparing..."),
+                    InboxStatus::Ready { link } => {
+                        println!("Transfer {} is ready:\n{}", update.id, link);
+                        println!("Waiting for uploads. Press Ctrl-C to stop following; the request keeps running.");
+                    }
+                    InboxStatus::Receiving {
+                        name,
+                        received,
+                        speed,
+                    } => println!(
+                        "Receiving {}: {} ({})",
+                        name,
+                        bytesize::ByteSize(received).display().si(),
+                        describe_speed(speed, None, PathKind::Web)
+                    ),
+                    InboxStatus::Received { name, size, path } => println!(
+                        "Received {} ({}) as {}",
+                        name,
+                        bytesize::ByteSize(size).display().si(),
+                        path.display()
+                    ),
+                    InboxStatus::Rejected(reason) => println!("Refused an upload: {}", reason),
+                    InboxStatus::Error(e) => anyhow::bail!(e),
+                }
+            }
+            anyhow::bail!("The daemon stopped the transfer");
+        }
+        Command::List => {
+            let transfers = daemon.list().await?;
+            if transfers.is_empty() {
+                println!("No transfers.");
+            }
+            for transfer in transfers {
+                let kind = match transfer.kind {
+                    TransferKind::Send(ShareMode::Ticket) => "send",
+                    TransferKind::Send(ShareMode::Web) => "web",
+                    TransferKind::Send(ShareMode::EncryptedWeb) => "encrypted web",
+                    TransferKind::Receive => "receive",
+                    TransferKind::Inbox => "request",
+                    TransferKind::Bridge => "bridge",
+                };
+                let mut name = match &transfer.share {
+                    Some(share) => format!("{} ({})", share.name, format_expiry(share.expires)),
+                    None => transfer.path.display().to_string(),
+                };
+                if transfer.share.as_ref().is_some_and(|share| share.link_changed) {
+                    name.push_str(" (new link since the restart)");
+                }
+                if transfer.watch {
+                    name.push_str(" (watching)");
+                }
+                if let Some(rate) = transfer.limit {
+                    name.push_str(&format!(" (limited to {})", format_rate(Some(rate))));
+                }
+                if let Some(progress) = &transfer.progress {
+                    name.push_str(&format!(" ({})", describe_progress(progress)));
+                } else if let Some(summary) = &transfer.summary {
+                    name.push_str(&format!(" (last upload: {})", describe_summary(summary)));
+                }
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    transfer.id,
+                    kind,
+                    name,
+                    transfer.ticket.unwrap_or_default()
+                );
+            }
+        }
+        Command::Stop { id } => {
+            daemon.stop(id).await?;
+            println!("Transfer {} stopped.", id);
+        }
+        Command::Limit {
+            upload,
+            download,
+            id,
+            rate,
+        } => {
+            if let (Some(id), Some(rate)) = (id, rate) {
+                let rate = parse_rate(&rate)?;
+                daemon.set_transfer_limit(id, rate).await?;
+                match rate {
+                    Some(_) => println!("Transfer {} limited to {}.", id, format_rate(rate)),
+                    None => println!("Transfer {} no longer limited.", id),
+                }
+                return Ok(());
+            }
+            let mut limits = daemon.limits().await?;
+            if upload.is_some() || download.is_some() {
+                if let Some(upload) = upload {
+                    limits.upload = parse_rate(&upload)?;
+                }
+                if let Some(download) = download {
+                    limits.download = parse_rate(&download)?;
+                }
+                daemon.set_limits(limits).await?;
+            }
+            println!("Upload: {}", format_rate(limits.upload));
+            println!("Download: {}", format_rate(limits.download));
+        }
+        Command::Relay { mode } => {
+            if let Some(mode) = mode {
+                daemon.set_relay(mode).await?;
+            }
+            println!("Relay mode: {}", daemon.relay().await?);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,295 @@
+//! Content types and `Content-Disposition` headers of files served over the web. The
+//! type comes from the extension of the file name, or from the first bytes of the file
+//! when the extension is unknown. Browsers show images, PDFs, video, audio and text
+//! inline when asked to; everything else is saved.
+
+use axum::http::{header, HeaderName};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// What files without a known type are sent as.
+const OCTET_STREAM: &str = "application/octet-stream";
+
+const TEXT: &str = "text/plain; charset=utf-8";
+
+/// How many bytes of a file [`content_type`] looks at.
+pub(crate) const SNIFF_LEN: usize = 512;
+
+/// Characters allowed unencoded in the `filename*` parameter by RFC 5987.
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Content types by lowercase file extension.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/x-icon"),
+    ("svg", "image/svg+xml"),
+    ("heic", "image/heic"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mov", "video/quicktime"),
+    ("mkv", "video/x-matroska"),
+    ("avi", "video/x-msvideo"),
+    ("ogv", "video/ogg"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("pdf", "application/pdf"),
+    ("txt", TEXT),
+    ("log", TEXT),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+];
+
+/// The content type of the file `name`, whose first bytes are `head`.
+pub(crate) fn content_type(name: &str, head: &[u8]) -> &'static str {
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    extension
+        .and_then(|extension| {
+            EXTENSIONS
+                .iter()
+                .find(|(known, _)| *known == extension)
+                .map(|(_, content_type)| *content_type)
+        })
+        .or_else(|| sniff(head))
+        .unwrap_or(OCTET_STREAM)
+}
+
+/// Recognizes common formats by their first bytes.
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    let content_type = if at(0, b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if at(0, &[0xff, 0xd8, 0xff]) {
+        "image/jpeg"
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        "image/gif"
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        "image/webp"
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        "audio/wav"
+    } else if at(0, b"RIFF") && at(8, b"AVI ") {
+        "video/x-msvideo"
+    } else if at(4, b"ftyp") {
+        match head.get(8..12) {
+            Some(b"avif") => "image/avif",
+            Some(b"heic" | b"heix" | b"mif1") => "image/heic",
+            Some(b"M4A ") => "audio/mp4",
+            Some(b"qt  ") => "video/quicktime",
+            _ => "video/mp4",
+        }
+    } else if at(0, &[0x1a, 0x45, 0xdf, 0xa3]) {
+        "video/webm"
+    } else if at(0, b"OggS") {
+        "audio/ogg"
+    } else if at(0, b"fLaC") {
+        "audio/flac"
+    } else if at(0, b"ID3") || (head.len() >= 2 && head[0] == 0xff && head[1] & 0xe0 == 0xe0) {
+        "audio/mpeg"
+    } else if at(0, b"%PDF-") {
+        "application/pdf"
+    } else if at(0, b"PK\x03\x04") {
+        "application/zip"
+    } else if at(0, &[0x1f, 0x8b]) {
+        "application/gzip"
+    } else if at(0, &[0x28, 0xb5, 0x2f, 0xfd]) {
+        "application/zstd"
+    } else if is_text(head) {
+        TEXT
+    } else {
+        return None;
+    };
+    Some(content_type)
+}
+
+/// True if `head` is UTF-8 without control characters. The last character may be cut off.
+fn is_text(head: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    !valid.is_empty()
+        && valid
+            .chars()
+            .all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r' | '\x0c'))
+}
+
+/// True if browsers can show files of `content_type` themselves. Pages and SVG images
+/// are left out, as they could run scripts under the address of the link.
+pub(crate) fn is_previewable(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    match essence.split_once('/') {
+        Some(("image", subtype)) => subtype != "svg+xml",
+        Some(("video" | "audio", _)) => true,
+        Some(("text", subtype)) => subtype != "html",
+        _ => matches!(essence, "application/pdf" | "application/json"),
+    }
+}
+
+/// A `Content-Disposition` value for the file `name`. Browsers that know RFC 5987 use the
+/// encoded `filename*`; others get the name with anything but plain ASCII replaced.
+pub(crate) fn content_disposition(name: &str, inline: bool) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        if inline { "inline" } else { "attachment" },
+        fallback,
+        utf8_percent_encode(name, ATTR_CHAR)
+    )
+}
+
+/// The headers describing the download of the file `name`, whose first bytes are `head`.
+/// With `inline`, files the browser can show are shown instead of saved.
+pub(crate) fn download_headers(name: &str, head: &[u8], inline: bool) -> [(HeaderName, String); 3] {
+    let content_type = content_type(name, head);
+    let inline = inline && is_previewable(content_type);
+    [
+        (header::CONTENT_TYPE, content_type.to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            content_disposition(name, inline),
+        ),
+        // Browsers go by the type sent, not by what they guess from the content.
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ]
+}
+
+/// Extracts the file name from a `Content-Disposition` header value, preferring the
+/// encoded `filename*` over the plain `filename`.
+pub(crate) fn disposition_file_name(value: &str) -> Option<String> {
+    let param = |key: &str| {
+        value.split(';').find_map(|part| {
+            let (name, value) = part.trim().split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case(key)
+                .then(|| value.trim().to_string())
+        })
+    };
+    let encoded = param("filename*").and_then(|value| {
+        let (charset, rest) = value.split_once('\'')?;
+        let (_language, encoded) = rest.split_once('\'')?;
+        charset.eq_ignore_ascii_case("UTF-8").then_some(())?;
+        percent_decode_str(encoded)
+            .decode_utf8()
+            .ok()
+            .map(|name| name.into_owned())
+    });
+    encoded
+        .or_else(|| param("filename").map(|name| name.trim_matches('"').to_string()))
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type("Photo.JPG", b""), "image/jpeg");
+        assert_eq!(content_type("notes.txt", b"\x00\x01"), TEXT);
+        assert_eq!(content_type("scan", b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(
+            content_type("clip", b"\x00\x00\x00\x20ftypisom"),
+            "video/mp4"
+        );
+        assert_eq!(content_type("README", "Grüße\n".as_bytes()), TEXT);
+        // Text cut off in the middle of a character is still text.
+        assert_eq!(content_type("README", &"ü".as_bytes()[..1]), OCTET_STREAM);
+        assert_eq!(content_type("README", &"aü".as_bytes()[..2]), TEXT);
+        assert_eq!(content_type("data.bin", b"\x00\x01\x02"), OCTET_STREAM);
+        assert_eq!(content_type("empty", b""), OCTET_STREAM);
+    }
+
+    #[test]
+    fn test_is_previewable() {
+        assert!(is_previewable("image/png"));
+        assert!(is_previewable("video/webm"));
+        assert!(is_previewable("application/pdf"));
+        assert!(is_previewable(TEXT));
+        assert!(!is_previewable("image/svg+xml"));
+        assert!(!is_previewable("text/html; charset=utf-8"));
+        assert!(!is_previewable("application/zip"));
+    }
+
+    #[test]
+    fn test_content_disposition_roundtrip() {
+        for name in ["build.zip", "say \"hi\".txt", "Звіт 2024.pdf", "a;b=c.txt"] {
+            let value = content_disposition(name, false);
+            assert!(value.is_ascii(), "{}", value);
+            assert_eq!(disposition_file_name(&value).as_deref(), Some(name));
+        }
+        assert_eq!(
+            content_disposition("Звіт.pdf", true),
+            "inline; filename=\"____.pdf\"; filename*=UTF-8''%D0%97%D0%B2%D1%96%D1%82.pdf"
+        );
+    }
+
+    #[test]
+    fn test_disposition_file_name() {
+        assert_eq!(
+            disposition_file_name("attachment; filename=\"build.zip\"").as_deref(),
+            Some("build.zip")
+        );
+        assert_eq!(disposition_file_name("attachment"), None);
+    }
+}
@@ -0,0 +1,301 @@
+use super::node::Node;
+use anyhow::Context;
+use iroh::{
+    discovery::{mdns::DiscoveryEvent, UserData},
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+    EndpointId,
+};
+use n0_future::{task::AbortOnDropHandle, StreamExt};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// ALPN used to hand a ticket over to a nearby device.
+pub(crate) const OFFER_ALPN: &[u8] = b"p2p-client/offer/0";
+
+/// ALPN used to ask a nearby device which ticket a short code stands for.
+pub(crate) const CODE_ALPN: &[u8] = b"p2p-client/code/0";
+
+/// Upper bound for a single offer message (device name + ticket).
+const MAX_OFFER_SIZE: usize = 64 * 1024;
+
+/// A device running p2p-client that was found on the local network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NearbyPeer {
+    pub id: EndpointId,
+    pub name: String,
+}
+
+/// Events reported by the nearby devices service to the UI.
+#[derive(Debug, Clone)]
+pub enum NearbyEvent {
+    Discovered(NearbyPeer),
+    Expired(EndpointId),
+    Offer { from: String, ticket: String },
+}
+
+/// What the node answers nearby devices with while the service runs: where offers go
+/// and the tickets of short codes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Nearby {
+    events: Arc<Mutex<Option<mpsc::Sender<NearbyEvent>>>>,
+    codes: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Nearby {
+    /// The protocol handler that accepts tickets offered by nearby devices.
+    pub(crate) fn offers(&self) -> OfferProtocol {
+        OfferProtocol {
+            events: self.events.clone(),
+        }
+    }
+
+    /// The protocol handler that answers short code lookups from nearby devices.
+    pub(crate) fn codes(&self) -> CodeProtocol {
+        CodeProtocol {
+            codes: self.codes.clone(),
+        }
+    }
+}
+
+/// A handle to the running nearby devices service.
+/// When this struct is dropped, the service stops advertising and accepting offers.
+pub struct NearbyHandle {
+    node: Node,
+    device_name: String,
+    events: mpsc::Sender<NearbyEvent>,
+    peers: Arc<Mutex<Vec<NearbyPeer>>>,
+    _discovery: AbortOnDropHandle<()>,
+}
+
+impl NearbyHandle {
+    /// Name under which this device is advertised to others.
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    /// Offers a ticket to a nearby device. The receiving side decides whether to download it.
+    pub async fn offer(&self, peer: EndpointId, ticket: String) -> anyhow::Result<()> {
+        let connection = self
+            .node
+            .endpoint()
+            .connect(peer, OFFER_ALPN)
+            .await
+            .context("Failed to connect to nearby device")?;
+        let mut stream = connection.open_uni().await?;
+        stream
+            .write_all(format!("{}\n{}", self.device_name, ticket).as_bytes())
+            .await?;
+        stream.finish()?;
+        // The receiver closes the connection once it has read the whole offer.
+        connection.closed().await;
+        Ok(())
+    }
+
+    /// Makes a short code resolvable by devices on the local network.
+    pub(crate) fn register_code(&self, code: String, ticket: String) {
+        self.node
+            .nearby()
+            .codes
+            .lock()
+            .unwrap()
+            .insert(code, ticket);
+    }
+
+    /// Asks every nearby device whether it knows the given short code.
+    pub(crate) async fn lookup_code(&self, code: &str) -> Option<String> {
+        let peers = self.peers.lock().unwrap().clone();
+        let queries = peers.into_iter().map(|peer| async move {
+            tokio::time::timeout(Duration::from_secs(5), self.query_code(peer.id, code))
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .flatten()
+        });
+        futures::future::join_all(queries)
+            .await
+            .into_iter()
+            .flatten()
+            .next()
+    }
+
+    async fn query_code(&self, peer: EndpointId, code: &str) -> anyhow::Result<Option<String>> {
+        let connection = self.node.endpoint().connect(peer, CODE_ALPN).await?;
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(code.as_bytes()).await?;
+        send.finish()?;
+        let ticket = recv.read_to_end(MAX_OFFER_SIZE).await?;
+        connection.close(0u32.into(), b"ok");
+        let ticket = String::from_utf8(ticket)?;
+        Ok(Some(ticket).filter(|t| !t.is_empty()))
+    }
+}
+
+impl Drop for NearbyHandle {
+    fn drop(&mut self) {
+        let nearby = self.node.nearby();
+        let mut events = nearby.events.lock().unwrap();
+        // A service started since then keeps running.
+        if !events
+            .as_ref()
+            .is_some_and(|events| events.same_channel(&self.events))
+        {
+            return;
+        }
+        *events = None;
+        nearby.codes.lock().unwrap().clear();
+        self.node.endpoint().set_user_data_for_discovery(None);
+        println!("Nearby devices service stopped.");
+    }
+}
+
+/// Protocol handler that accepts tickets offered by nearby devices.
+#[derive(Debug, Clone)]
+pub(crate) struct OfferProtocol {
+    events: Arc<Mutex<Option<mpsc::Sender<NearbyEvent>>>>,
+}
+
+impl ProtocolHandler for OfferProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let mut stream = connection.accept_uni().await?;
+        let message = stream
+            .read_to_end(MAX_OFFER_SIZE)
+            .await
+            .map_err(AcceptError::from_err)?;
+        connection.close(0u32.into(), b"ok");
+        let message = String::from_utf8_lossy(&message);
+        // Offers are dropped while the service is not running.
+        let events = self.events.lock().unwrap().clone();
+        if let (Some(events), Some((from, ticket))) = (events, message.split_once('\n')) {
+            let _ = events
+                .send(NearbyEvent::Offer {
+                    from: from.to_string(),
+                    ticket: ticket.trim().to_string(),
+                })
+                .await;
+        }
+        Ok(())
+    }
+}
+
+/// Protocol handler that answers short code lookups from nearby devices.
+#[derive(Debug, Clone)]
+pub(crate) struct CodeProtocol {
+    codes: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl ProtocolHandler for CodeProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        let code = recv.read_to_end(256).await.map_err(AcceptError::from_err)?;
+        let code = String::from_utf8_lossy(&code).to_string();
+        let ticket = self.codes.lock().unwrap().get(&code).cloned();
+        send.write_all(ticket.unwrap_or_default().as_bytes())
+            .await
+            .map_err(AcceptError::from_err)?;
+        send.finish()?;
+        // The asking side closes the connection once it has the answer.
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+/// Starts advertising this device on the local network from `node` and listening for offers.
+/// Works without internet access: peers are found via mDNS and connected directly.
+pub(crate) async fn start_nearby_internal(
+    node: Node,
+    device_name: String,
+    events: mpsc::Sender<NearbyEvent>,
+) -> anyhow::Result<NearbyHandle> {
+    let user_data = UserData::from_str(&truncate_name(&device_name))?;
+    node.endpoint().set_user_data_for_discovery(Some(user_data));
+    *node.nearby().events.lock().unwrap() = Some(events.clone());
+
+    let own_id = node.endpoint().id();
+    let peers = Arc::new(Mutex::new(Vec::<NearbyPeer>::new()));
+    let known_peers = peers.clone();
+    let mut discovered = node.mdns().subscribe().await;
+    let discovery_events = events.clone();
+    let discovery = tokio::spawn(async move {
+        while let Some(event) = discovered.next().await {
+            let event = match event {
+                DiscoveryEvent::Discovered { endpoint_info, .. } => {
+                    let id = endpoint_info.endpoint_id;
+                    if id == own_id {
+                        continue;
+                    }
+                    // Only devices running the service advertise a name; other nodes on
+                    // the network, like those of command line transfers, are not listed.
+                    match endpoint_info.data.user_data() {
+                        Some(name) => {
+                            let peer = NearbyPeer {
+                                id,
+                                name: name.as_ref().to_string(),
+                            };
+                            let mut known = known_peers.lock().unwrap();
+                            known.retain(|p| p.id != peer.id);
+                            known.push(peer.clone());
+                            NearbyEvent::Discovered(peer)
+                        }
+                        None if forget(&known_peers, id) => NearbyEvent::Expired(id),
+                        None => continue,
+                    }
+                }
+                DiscoveryEvent::Expired { endpoint_id } => {
+                    if !forget(&known_peers, endpoint_id) {
+                        continue;
+                    }
+                    NearbyEvent::Expired(endpoint_id)
+                }
+            };
+            if discovery_events.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(NearbyHandle {
+        node,
+        device_name,
+        events,
+        peers,
+        _discovery: AbortOnDropHandle::new(discovery),
+    })
+}
+
+/// Removes the peer `id` from `peers`. Returns false if it was not listed.
+fn forget(peers: &Mutex<Vec<NearbyPeer>>, id: EndpointId) -> bool {
+    let mut peers = peers.lock().unwrap();
+    let listed = peers.len();
+    peers.retain(|p| p.id != id);
+    peers.len() < listed
+}
+
+/// Returns a human-readable name for this machine, used when advertising to nearby devices.
+pub fn default_device_name() -> String {
+    std::env::var("P2P_CLIENT_DEVICE_NAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|s| s.trim().to_string())
+        })
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "p2p-client".to_string())
+}
+
+/// Cuts a device name down to what fits into an mDNS record.
+fn truncate_name(name: &str) -> String {
+    let mut end = name.len().min(UserData::MAX_LENGTH);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_string()
+}
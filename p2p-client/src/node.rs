@@ -0,0 +1,238 @@
+use super::compress::{self, Compression};
+use super::config::{data_dir, endpoint_builder, RelaySetting};
+use super::diagnostics::ActivePeers;
+use super::nearby::{self, Nearby};
+use super::pointer::{self, Pointers};
+use super::store::shared_store;
+use super::uploads::Uploads;
+use anyhow::Context;
+use iroh::{discovery::mdns::MdnsDiscovery, protocol::Router, Endpoint, SecretKey, Watcher};
+use iroh_blobs::{api::Store, protocol::ALPN as BlobsAlpn, BlobsProtocol};
+use std::sync::Arc;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+/// The node shared by all transfers of this process. Only one runs at a time, as every node
+/// of the process has the same id.
+static SHARED_NODE: Mutex<Option<Node>> = Mutex::const_new(None);
+
+/// A long-lived iroh node: one endpoint and one router that serve every send and
+/// receive of the process from the persistent blob store.
+///
+/// Cloning is cheap. The node shuts down when the last clone is dropped or when the
+/// shared node is restarted with another relay setting.
+#[derive(Clone)]
+pub(crate) struct Node {
+    inner: Arc<NodeInner>,
+}
+
+struct NodeInner {
+    router: Router,
+    store: Store,
+    pointers: Pointers,
+    uploads: Uploads,
+    active_peers: ActivePeers,
+    mdns: MdnsDiscovery,
+    nearby: Nearby,
+    relay: RelaySetting,
+    tokio_handle: tokio::runtime::Handle,
+}
+
+impl Node {
+    /// Binds a new endpoint serving the persistent blob store.
+    async fn spawn(relay: RelaySetting) -> anyhow::Result<Self> {
+        let store = shared_store().await?;
+
+        let secret_key = load_secret_key().await?;
+        let endpoint = endpoint_builder(&relay)
+            .alpns(vec![
+                BlobsAlpn.to_vec(),
+                pointer::ALPN.to_vec(),
+                compress::ALPN.to_vec(),
+                nearby::OFFER_ALPN.to_vec(),
+                nearby::CODE_ALPN.to_vec(),
+            ])
+            .secret_key(secret_key)
+            .bind()
+            .await?;
+        // Kept at hand, so the nearby devices service can list the peers it finds.
+        let mdns = MdnsDiscovery::builder().build(endpoint.id())?;
+        endpoint.discovery().add(mdns.clone());
+        let uploads = Uploads::default();
+        let active_peers = ActivePeers::default();
+        let events = uploads.event_sender(endpoint.clone(), store.clone(), active_peers.clone());
+        let blobs = BlobsProtocol::new(&store, Some(events));
+        let pointers = Pointers::default();
+        let compression = Compression::new(endpoint.clone(), store.clone(), uploads.clone());
+        let nearby = Nearby::default();
+        let router = Router::builder(endpoint)
+            .accept(BlobsAlpn, blobs)
+            .accept(pointer::ALPN, pointers.clone())
+            .accept(compress::ALPN, compression)
+            .accept(nearby::OFFER_ALPN, nearby.offers())
+            .accept(nearby::CODE_ALPN, nearby.codes())
+            .spawn();
+        println!("Node {} started.", router.endpoint().id().fmt_short());
+
+        Ok(Self {
+            inner: Arc::new(NodeInner {
+                router,
+                store,
+                pointers,
+                uploads,
+                active_peers,
+                mdns,
+                nearby,
+                relay,
+                tokio_handle: tokio::runtime::Handle::current(),
+            }),
+        })
+    }
+
+    pub(crate) fn endpoint(&self) -> &Endpoint {
+        self.inner.router.endpoint()
+    }
+
+    pub(crate) fn store(&self) -> &Store {
+        &self.inner.store
+    }
+
+    /// The mutable share pointers this node serves.
+    pub(crate) fn pointers(&self) -> &Pointers {
+        &self.inner.pointers
+    }
+
+    /// The sends this node serves, with their upload limits and progress.
+    pub(crate) fn uploads(&self) -> &Uploads {
+        &self.inner.uploads
+    }
+
+    /// The peers this node is currently transferring data with.
+    pub(crate) fn active_peers(&self) -> &ActivePeers {
+        &self.inner.active_peers
+    }
+
+    /// The mDNS discovery of the endpoint, which finds peers on the local network.
+    pub(crate) fn mdns(&self) -> &MdnsDiscovery {
+        &self.inner.mdns
+    }
+
+    /// The offers and short codes this node answers nearby devices with.
+    pub(crate) fn nearby(&self) -> &Nearby {
+        &self.inner.nearby
+    }
+
+    /// Waits until the endpoint has something worth putting into a ticket: a home relay,
+    /// or with relays disabled, at least one direct address.
+    /// Without internet access the relay is never reached; LAN peers can still
+    /// connect directly, so a timeout here is not an error. Once the node is online
+    /// this returns immediately.
+    pub(crate) async fn wait_until_reachable(&self) {
+        let endpoint = self.endpoint();
+        let wait = async {
+            if self.inner.relay.uses_relay() {
+                endpoint.online().await;
+            } else {
+                let mut addr = endpoint.watch_addr();
+                while addr.get().ip_addrs().next().is_none() {
+                    if addr.updated().await.is_err() {
+                        break;
+                    }
+                }
+            }
+        };
+        if tokio::time::timeout(std::time::Duration::from_secs(10), wait)
+            .await
+            .is_err()
+        {
+            println!("Relay not reachable, continuing with direct addresses only.");
+        }
+    }
+}
+
+/// The Drop implementation shuts down the router. The store stays open for other nodes.
+impl Drop for NodeInner {
+    fn drop(&mut self) {
+        if self.router.is_shutdown() {
+            return;
+        }
+        let router = self.router.clone();
+        self.tokio_handle.spawn(async move {
+            let _ = router.shutdown().await;
+            println!("Node shut down.");
+        });
+    }
+}
+
+/// Loads the node key from the app data dir, creating it on first use. A stable key
+/// gives the node a stable id, so tickets of persistent shares keep working across restarts.
+async fn load_secret_key() -> anyhow::Result<SecretKey> {
+    let path = data_dir()?.join("node.key");
+    match tokio::fs::read_to_string(&path).await {
+        Ok(text) => {
+            let mut bytes = [0u8; 32];
+            hex::decode_to_slice(text.trim(), &mut bytes)
+                .with_context(|| format!("Invalid node key in {}", path.display()))?;
+            Ok(SecretKey::from_bytes(&bytes))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let secret_key = SecretKey::generate(&mut rand::rng());
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(&path).await?;
+            file.write_all(hex::encode(secret_key.to_bytes()).as_bytes())
+                .await?;
+            Ok(secret_key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the shared node, starting it with `relay` on demand. A running node keeps its
+/// relay setting; [`restart_shared_node`] changes it.
+pub(crate) async fn shared_node(relay: &RelaySetting) -> anyhow::Result<Node> {
+    let mut shared = SHARED_NODE.lock().await;
+    if let Some(node) = shared.as_ref() {
+        anyhow::ensure!(
+            &node.inner.relay == relay,
+            "The node runs with relay mode {}, not {}",
+            node.inner.relay,
+            relay
+        );
+        return Ok(node.clone());
+    }
+    let node = Node::spawn(relay.clone()).await?;
+    *shared = Some(node.clone());
+    Ok(node)
+}
+
+/// Returns the shared node if it is running, without starting it.
+pub(crate) async fn running_node() -> Option<Node> {
+    SHARED_NODE.lock().await.clone()
+}
+
+/// Stops the shared node, aborting the transfers that use it, and starts it again with
+/// `relay`. The old endpoint is closed first, so the id is never announced twice.
+pub(crate) async fn restart_shared_node(relay: &RelaySetting) -> anyhow::Result<Node> {
+    let mut shared = SHARED_NODE.lock().await;
+    if let Some(node) = shared.take() {
+        let _ = node.inner.router.shutdown().await;
+        println!("Node shut down.");
+    }
+    let node = Node::spawn(relay.clone()).await?;
+    *shared = Some(node.clone());
+    Ok(node)
+}
+
+/// Stops the shared node, aborting any transfers that still use it.
+pub(crate) async fn shutdown_shared_node() {
+    let node = SHARED_NODE.lock().await.take();
+    if let Some(node) = node {
+        let _ = node.inner.router.shutdown().await;
+        println!("Node shut down.");
+    }
+}
@@ -1,146 +1,359 @@
 use super::files::{export, import};
+use super::history::{self, FetchFlag, HistoryEntry, HistoryFile, HistoryKind, TransferOutcome};
+use super::limit::RateLimiter;
+use super::node::Node;
+use super::pointer::{self, PointerTicket};
+use super::shares::ShareInfo;
+use super::sources;
 use super::state::{ReceiveStatus, SendHandle, SendStatus};
-use anyhow::{bail, Context};
-use iroh::{Endpoint, RelayMode, SecretKey};
+use super::stats::{PathKind, Throughput, TransferSummary};
+use super::store::ShareTag;
+use super::sync::{self, SyncOptions, SyncPlan};
+use super::watch;
+use anyhow::Context;
+use iroh::{EndpointAddr, EndpointId};
 use iroh_blobs::{
-    api::remote::GetProgressItem,
-    format::collection::Collection,
-    get::request::get_hash_seq_and_sizes,
-    protocol::ALPN as BlobsAlpn,
-    ticket::BlobTicket,
-    BlobFormat, BlobsProtocol,
+    api::TempTag, format::collection::Collection, ticket::BlobTicket, BlobFormat, Hash,
+    HashAndFormat,
 };
-use n0_future::StreamExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use tokio::{runtime::Handle as TokioHandle, sync::mpsc};
 
 /// Core logic for P2P send.
+/// With `watch`, the folder is shared again whenever it changes.
 pub(crate) async fn send_internal(
+    node: Node,
     path: PathBuf,
+    watch: bool,
     progress: mpsc::Sender<SendStatus>,
     tokio_handle: TokioHandle,
 ) -> anyhow::Result<SendHandle> {
     progress.send(SendStatus::Connecting).await?;
 
-    let secret_key = get_or_create_secret()?;
-    let relay_mode = RelayMode::Default;
-    let endpoint = Endpoint::builder()
-        .alpns(vec![BlobsAlpn.to_vec()])
-        .secret_key(secret_key)
-        .relay_mode(relay_mode.clone())
-        .bind()
-        .await?;
-
-    let suffix: [u8; 8] = rand::random();
-    let data_dir = std::env::temp_dir().join(format!("p2p-client-p2p-{}", hex::encode(suffix)));
-    tokio::fs::create_dir_all(&data_dir).await?;
-    let store = iroh_blobs::store::fs::FsStore::load(&data_dir).await?;
-
-    let (temp_tag, _size, _collection) = import(&path, &store, progress.clone()).await?;
+    let path = path.canonicalize()?;
+    let (temp_tag, _size, _collection) = import(&path, node.store(), progress.clone()).await?;
+    let share_tag = ShareTag::session(node.store(), &temp_tag).await?;
 
-    let blobs = BlobsProtocol::new(&store, None);
-    let router = iroh::protocol::Router::builder(endpoint)
-        .accept(BlobsAlpn, blobs)
-        .spawn();
-
-    let ep = router.endpoint();
-    tokio::time::timeout(std::time::Duration::from_secs(10), async move {
-        if !matches!(relay_mode, RelayMode::Disabled) {
-            let _ = ep.online().await;
-        }
-    })
-    .await?;
+    announce(
+        node,
+        temp_tag,
+        share_tag,
+        None,
+        path,
+        watch,
+        progress,
+        tokio_handle,
+    )
+    .await
+}
 
-    let addr = router.endpoint().addr();
-    let ticket = BlobTicket::new(addr, temp_tag.hash(), BlobFormat::HashSeq);
-    progress
-        .send(SendStatus::ReadyToSend {
-            ticket: ticket.to_string(),
-        })
-        .await?;
+/// Hands out a ticket for a collection that is already in the node's store.
+/// A persistent share keeps the ticket it was announced with before.
+/// With `watch`, the folder at `path` is watched and shared again after every change.
+/// The ticket is then a pointer ticket, which keeps resolving to the latest version.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn announce(
+    node: Node,
+    temp_tag: TempTag,
+    share_tag: ShareTag,
+    mut share: Option<ShareInfo>,
+    path: PathBuf,
+    watch: bool,
+    progress: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<SendHandle> {
+    node.wait_until_reachable().await;
 
-    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
-    tokio::spawn(async move {
-        let _ = shutdown_rx.await;
-        println!("Shutting down P2P sender...");
-        let _ = router.shutdown().await;
-        let _ = temp_tag;
+    let pointer = watch.then(|| {
+        // A persistent share keeps its pointer name, so its ticket survives restarts.
+        let name = match share.as_ref() {
+            Some(share) => share.id.clone(),
+            None => hex::encode(rand::random::<[u8; 8]>()),
+        };
+        Arc::new(
+            node.pointers()
+                .publish(node.endpoint(), name, temp_tag.hash()),
+        )
     });
+    let ticket = match (&pointer, share.as_ref().and_then(|s| s.ticket.clone())) {
+        (Some(pointer), _) => pointer.ticket(node.endpoint().addr()).to_string(),
+        (None, Some(ticket)) => ticket,
+        (None, None) => {
+            let addr = node.endpoint().addr();
+            BlobTicket::new(addr, temp_tag.hash(), BlobFormat::HashSeq).to_string()
+        }
+    };
+    if let Some(share) = share.as_mut() {
+        share.ticket = Some(ticket.clone());
+    }
+    progress.send(SendStatus::ReadyToSend { ticket }).await?;
 
+    let upload_limit = RateLimiter::default();
+    let fetched = FetchFlag::default();
+    let upload_registration = Arc::new(node.uploads().register(
+        temp_tag.hash(),
+        path.clone(),
+        upload_limit.clone(),
+        progress.clone(),
+        fetched.clone(),
+    ));
+    let share_tag = Arc::new(share_tag);
+    let watcher = match &pointer {
+        Some(pointer) => Some(watch::spawn(
+            node.clone(),
+            path.clone(),
+            temp_tag.hash(),
+            share_tag.clone(),
+            pointer.clone(),
+            upload_registration.clone(),
+            share.clone(),
+            progress,
+        )?),
+        None => None,
+    };
     Ok(SendHandle {
-        data_dir,
-        shutdown_tx: Some(shutdown_tx),
+        shutdown_tx: None,
         _ngrok_tunnel: None,
+        _temp_tag: temp_tag,
+        _share_tag: share_tag,
+        _watcher: watcher,
+        _pointer: pointer,
+        upload_limit,
+        _upload_registration: Some(upload_registration),
+        _node: node,
+        share,
+        path,
+        fetched,
         tokio_handle,
     })
 }
 
-/// Core logic for receiving files.
+/// Shares the collection `hash`, downloaded into `path`, from this node under a new ticket,
+/// so that others can fetch it from here instead of from the original sender.
+pub(crate) async fn seed(
+    node: Node,
+    hash: Hash,
+    path: PathBuf,
+    progress: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<SendHandle> {
+    let db = node.store();
+    let temp_tag = db.tags().temp_tag(HashAndFormat::hash_seq(hash)).await?;
+    let local = db.remote().local(HashAndFormat::hash_seq(hash)).await?;
+    anyhow::ensure!(
+        local.is_complete(),
+        "The download is no longer in the store"
+    );
+    let share_tag = ShareTag::session(db, &temp_tag).await?;
+    announce(
+        node,
+        temp_tag,
+        share_tag,
+        None,
+        path,
+        false,
+        progress,
+        tokio_handle,
+    )
+    .await
+}
+
+/// Core logic for receiving files into `target_dir`. `sources` are more tickets or node
+/// ids of peers that share the same content; the files are fetched from all of them at
+/// once, compressed if `compress` is set and the peers support it. Returns what was
+/// received, for the history.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn receive_logic(
+    node: Node,
     ticket_str: &str,
-    data_dir: &Path,
+    sources: &[String],
+    target_dir: &Path,
+    sync: Option<SyncOptions>,
+    compress: bool,
+    download_limit: RateLimiter,
     progress: mpsc::Sender<ReceiveStatus>,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<HistoryEntry> {
     progress.send(ReceiveStatus::Connecting).await?;
 
-    let ticket = BlobTicket::from_str(ticket_str).context("Invalid ticket format")?;
-    let addr = ticket.addr().clone();
-    let secret_key = get_or_create_secret()?;
-    let endpoint = Endpoint::builder()
-        .alpns(vec![])
-        .secret_key(secret_key)
-        .relay_mode(RelayMode::Default)
-        .bind()
-        .await?;
-
-    let db = iroh_blobs::store::fs::FsStore::load(data_dir).await?;
+    let ticket = resolve_ticket(&node, ticket_str).await?;
+    let mut providers = vec![ticket.addr().clone()];
+    for source in sources {
+        providers.push(sources::source_addr(&node, source, ticket.hash()).await?);
+    }
+    if let Some(options) = sync {
+        return sync_logic(
+            node,
+            ticket,
+            providers,
+            target_dir,
+            options,
+            compress,
+            download_limit,
+            progress,
+        )
+        .await;
+    }
+    let peer = ticket.addr().id;
+    let db = node.store();
 
     let hash_and_format = ticket.hash_and_format();
+    // Keeps downloaded blobs from being garbage-collected before they are exported.
+    let _temp_tag = db.tags().temp_tag(hash_and_format).await?;
     let local = db.remote().local(hash_and_format).await?;
+    let mut throughput = Throughput::new();
+    let mut received = 0;
     if !local.is_complete() {
-        let connection = endpoint.connect(addr, BlobsAlpn).await?;
-        let (_hash_seq, sizes) =
-            get_hash_seq_and_sizes(&connection, &hash_and_format.hash, 1024 * 1024 * 32, None)
-                .await
-                .map_err(|e| anyhow::anyhow!(e.to_string()))?;
-        let total_size = sizes.iter().copied().sum::<u64>();
-        let payload_size = sizes.iter().skip(1).copied().sum::<u64>();
-        let total_files = (sizes.len().saturating_sub(1)) as u64;
+        let sources = sources::connect(&node, providers, compress).await?;
+        let (hash_seq, sizes) = sources::fetch_hash_seq(db, &sources, hash_and_format.hash).await?;
         progress
             .send(ReceiveStatus::Connected {
-                total_files,
-                total_size: payload_size,
+                total_files: (sizes.len().saturating_sub(1)) as u64,
+                total_size: sizes.iter().skip(1).copied().sum(),
             })
             .await?;
-        let get = db.remote().execute_get(connection, local.missing());
-        let mut stream = get.stream();
-        while let Some(item) = stream.next().await {
-            match item {
-                GetProgressItem::Progress(offset) => {
-                    progress
-                        .send(ReceiveStatus::Downloading {
-                            downloaded: local.local_bytes() + offset,
-                            total: total_size,
-                        })
-                        .await?;
-                }
-                GetProgressItem::Done(_) => break,
-                GetProgressItem::Error(cause) => bail!(cause.to_string()),
-            }
-        }
+        let files: Vec<(Hash, u64)> = hash_seq.iter().zip(sizes).skip(1).collect();
+        received = sources::fetch(
+            &node,
+            &sources,
+            hash_and_format.hash,
+            &files,
+            &download_limit,
+            &mut throughput,
+            &progress,
+        )
+        .await?;
+    }
+
+    let collection = Collection::load(hash_and_format.hash, db).await?;
+    let files = history::collection_files(db, &collection).await;
+    export(db, collection, target_dir, progress.clone()).await?;
+
+    let summary = throughput.summary(received, PathKind::of(node.endpoint(), peer));
+    progress.send(ReceiveStatus::Done(summary)).await?;
+    Ok(history_entry(
+        target_dir,
+        peer,
+        hash_and_format.hash,
+        files,
+        summary,
+    ))
+}
+
+/// Reads a ticket. A pointer ticket is resolved to the version it points to now.
+pub(crate) async fn resolve_ticket(node: &Node, ticket_str: &str) -> anyhow::Result<BlobTicket> {
+    if PointerTicket::is_pointer_ticket(ticket_str) {
+        let pointer = PointerTicket::from_str(ticket_str)?;
+        pointer::resolve(node.endpoint(), &pointer).await
+    } else {
+        BlobTicket::from_str(ticket_str).context("Invalid ticket format")
     }
+}
+
+/// Brings `target_dir` up to date with the collection of `ticket`, downloading only the
+/// files that are new or differ from the local ones from all `providers`.
+#[allow(clippy::too_many_arguments)]
+async fn sync_logic(
+    node: Node,
+    ticket: BlobTicket,
+    providers: Vec<EndpointAddr>,
+    target_dir: &Path,
+    options: SyncOptions,
+    compress: bool,
+    download_limit: RateLimiter,
+    progress: mpsc::Sender<ReceiveStatus>,
+) -> anyhow::Result<HistoryEntry> {
+    anyhow::ensure!(
+        ticket.format() == BlobFormat::HashSeq,
+        "Only tickets of files and folders can be synced"
+    );
+    let db = node.store();
+    let hash = ticket.hash();
+    let peer = ticket.addr().id;
+    let mut throughput = Throughput::new();
+    // Keeps downloaded blobs from being garbage-collected before they are exported.
+    let _temp_tag = db.tags().temp_tag(ticket.hash_and_format()).await?;
+
+    let sources = sources::connect(&node, providers, compress).await?;
+    // The files themselves are fetched once the plan is known.
+    let (_hash_seq, sizes) = sources::fetch_hash_seq(db, &sources, hash).await?;
+    progress
+        .send(ReceiveStatus::Connected {
+            total_files: (sizes.len().saturating_sub(1)) as u64,
+            total_size: sizes.iter().skip(1).copied().sum(),
+        })
+        .await?;
+    let collection = Collection::load(hash, db).await?;
 
-    let collection = Collection::load(hash_and_format.hash, db.as_ref()).await?;
-    export(&db, collection, progress.clone()).await?;
+    let remote: Vec<(String, Hash, u64)> = collection
+        .iter()
+        .zip(sizes.iter().skip(1))
+        .map(|((name, hash), size)| (name.clone(), *hash, *size))
+        .collect();
+    let names = remote.iter().map(|(name, _, _)| name.clone()).collect();
+    let local = sync::local_hashes(target_dir, names).await?;
+    let plan = SyncPlan::new(&remote, &local);
+    progress.send(ReceiveStatus::SyncPlan(plan.clone())).await?;
+    let files: Vec<HistoryFile> = remote
+        .iter()
+        .map(|(name, hash, size)| HistoryFile {
+            name: name.clone(),
+            size: *size,
+            hash: *hash,
+        })
+        .collect();
+    if options.dry_run {
+        let summary = throughput.summary(0, PathKind::of(node.endpoint(), peer));
+        progress.send(ReceiveStatus::Done(summary)).await?;
+        return Ok(history_entry(target_dir, peer, hash, files, summary));
+    }
 
-    db.shutdown().await?;
+    let wanted: Vec<(Hash, u64)> = remote
+        .iter()
+        .filter(|(name, _, _)| plan.to_write().any(|n| n == name))
+        .map(|(_, hash, size)| (*hash, *size))
+        .collect();
+    let received = sources::fetch(
+        &node,
+        &sources,
+        hash,
+        &wanted,
+        &download_limit,
+        &mut throughput,
+        &progress,
+    )
+    .await?;
 
-    progress.send(ReceiveStatus::Done).await?;
-    Ok(())
+    sync::apply(
+        db,
+        &collection,
+        target_dir,
+        &plan,
+        options,
+        progress.clone(),
+    )
+    .await?;
+    let summary = throughput.summary(received, PathKind::of(node.endpoint(), peer));
+    progress.send(ReceiveStatus::Done(summary)).await?;
+    Ok(history_entry(target_dir, peer, hash, files, summary))
 }
 
-/// Generates a new random secret key for an Iroh endpoint
-fn get_or_create_secret() -> anyhow::Result<SecretKey> {
-    Ok(SecretKey::generate(&mut rand::rng()))
-}
\ No newline at end of file
+/// Describes a finished receive of the collection `hash` from `peer` for the history.
+fn history_entry(
+    target_dir: &Path,
+    peer: EndpointId,
+    hash: Hash,
+    files: Vec<HistoryFile>,
+    summary: TransferSummary,
+) -> HistoryEntry {
+    let mut entry = HistoryEntry::new(
+        HistoryKind::Receive,
+        target_dir.to_path_buf(),
+        TransferOutcome::Completed(summary),
+    );
+    entry.peer = Some(peer);
+    entry.hash = Some(hash);
+    entry.files = files;
+    entry
+}
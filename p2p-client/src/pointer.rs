@@ -0,0 +1,282 @@
+//! Mutable share pointers: a stable ticket that always resolves to the latest version of
+//! a watched share. The sender signs every version with its node key and serves the
+//! current record over [`ALPN`]; receivers resolve the pointer before fetching.
+
+use super::config::data_dir;
+use anyhow::{ensure, Context};
+use data_encoding::BASE32_NOPAD;
+use iroh::{
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+    Endpoint, EndpointAddr, PublicKey, SecretKey, Signature,
+};
+use iroh_blobs::{ticket::BlobTicket, BlobFormat, Hash};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The protocol receivers use to ask a sender for the current version of a pointer.
+pub(crate) const ALPN: &[u8] = b"p2p-client/pointer/0";
+
+/// Prefix that tells pointer tickets apart from blob tickets.
+const TICKET_PREFIX: &str = "pointer";
+
+/// Longest pointer name a sender accepts in a request.
+const MAX_NAME_LEN: usize = 256;
+
+/// Serializes the pointers this process has resolved, see [`check_version`].
+static SEEN_LOCK: Mutex<()> = Mutex::new(());
+
+/// A ticket for a pointer: the sender's address and the name of the pointer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PointerTicket {
+    addr: EndpointAddr,
+    name: String,
+}
+
+impl PointerTicket {
+    /// Returns true if `text` looks like a pointer ticket rather than a blob ticket.
+    pub(crate) fn is_pointer_ticket(text: &str) -> bool {
+        text.starts_with(TICKET_PREFIX)
+    }
+
+    /// The node that serves the pointer.
+    pub(crate) fn sender(&self) -> PublicKey {
+        self.addr.id
+    }
+}
+
+impl fmt::Display for PointerTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data = serde_json::to_vec(self).map_err(|_| fmt::Error)?;
+        let encoded = BASE32_NOPAD.encode(&data).to_ascii_lowercase();
+        write!(f, "{}{}", TICKET_PREFIX, encoded)
+    }
+}
+
+impl FromStr for PointerTicket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .strip_prefix(TICKET_PREFIX)
+            .context("Not a pointer ticket")?;
+        let data = BASE32_NOPAD
+            .decode(encoded.to_ascii_uppercase().as_bytes())
+            .context("Invalid pointer ticket")?;
+        serde_json::from_slice(&data).context("Invalid pointer ticket")
+    }
+}
+
+/// One version of a pointer, signed by the node that serves it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PointerRecord {
+    name: String,
+    /// Grows with every update, so receivers can refuse to go back to an older version.
+    version: u64,
+    hash: Hash,
+    signature: Signature,
+}
+
+impl PointerRecord {
+    fn sign(secret_key: &SecretKey, name: String, version: u64, hash: Hash) -> Self {
+        let signature = secret_key.sign(&signed_bytes(&name, version, &hash));
+        Self {
+            name,
+            version,
+            hash,
+            signature,
+        }
+    }
+
+    fn verify(&self, key: &PublicKey) -> anyhow::Result<()> {
+        key.verify(
+            &signed_bytes(&self.name, self.version, &self.hash),
+            &self.signature,
+        )
+        .context("The pointer is not signed by the sender")
+    }
+}
+
+/// The message a pointer record signs. The name is length-prefixed so that no two
+/// records sign the same bytes.
+fn signed_bytes(name: &str, version: u64, hash: &Hash) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ALPN.len() + 8 + name.len() + 8 + 32);
+    bytes.extend_from_slice(ALPN);
+    bytes.extend_from_slice(&(name.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(hash.as_bytes());
+    bytes
+}
+
+/// The pointers a node serves, by name.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pointers {
+    records: Arc<Mutex<HashMap<String, PointerRecord>>>,
+}
+
+impl Pointers {
+    /// Starts serving `name`, pointing at `hash`. Dropping the returned handle stops it.
+    pub(crate) fn publish(&self, endpoint: &Endpoint, name: String, hash: Hash) -> Pointer {
+        let pointer = Pointer {
+            pointers: self.clone(),
+            secret_key: endpoint.secret_key().clone(),
+            name,
+        };
+        pointer.update(hash);
+        pointer
+    }
+
+    fn get(&self, name: &str) -> Option<PointerRecord> {
+        self.records.lock().unwrap().get(name).cloned()
+    }
+}
+
+impl ProtocolHandler for Pointers {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        let name = recv
+            .read_to_end(MAX_NAME_LEN)
+            .await
+            .map_err(AcceptError::from_err)?;
+        let record = self.get(&String::from_utf8_lossy(&name));
+        let response = serde_json::to_vec(&record).map_err(AcceptError::from_err)?;
+        send.write_all(&response)
+            .await
+            .map_err(AcceptError::from_err)?;
+        send.finish()?;
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+/// A pointer this node serves. Stops being served when dropped.
+#[derive(Debug)]
+pub(crate) struct Pointer {
+    pointers: Pointers,
+    secret_key: SecretKey,
+    name: String,
+}
+
+impl Pointer {
+    /// Points at a new version. The version is the current time in milliseconds, so it
+    /// keeps growing across restarts of a persistent share.
+    pub(crate) fn update(&self, hash: Hash) {
+        let mut records = self.pointers.records.lock().unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let version = match records.get(&self.name) {
+            Some(previous) => now.max(previous.version + 1),
+            None => now,
+        };
+        let record = PointerRecord::sign(&self.secret_key, self.name.clone(), version, hash);
+        records.insert(self.name.clone(), record);
+    }
+
+    /// Returns a ticket for this pointer, reachable at `addr`.
+    pub(crate) fn ticket(&self, addr: EndpointAddr) -> PointerTicket {
+        PointerTicket {
+            addr,
+            name: self.name.clone(),
+        }
+    }
+}
+
+impl Drop for Pointer {
+    fn drop(&mut self) {
+        self.pointers.records.lock().unwrap().remove(&self.name);
+    }
+}
+
+/// Asks the sender of `ticket` which collection the pointer currently refers to and
+/// returns a blob ticket for it.
+pub(crate) async fn resolve(
+    endpoint: &Endpoint,
+    ticket: &PointerTicket,
+) -> anyhow::Result<BlobTicket> {
+    let connection = endpoint.connect(ticket.addr.clone(), ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(ticket.name.as_bytes()).await?;
+    send.finish()?;
+    let response = recv.read_to_end(64 * 1024).await?;
+    connection.close(0u32.into(), b"done");
+
+    let record: Option<PointerRecord> = serde_json::from_slice(&response)?;
+    let record = record.context("The sender no longer shares this content")?;
+    ensure!(
+        record.name == ticket.name,
+        "The sender answered with another pointer"
+    );
+    record.verify(&ticket.addr.id)?;
+    check_version(&ticket.addr.id, &record)?;
+    Ok(BlobTicket::new(
+        ticket.addr.clone(),
+        record.hash,
+        BlobFormat::HashSeq,
+    ))
+}
+
+/// Remembers the newest version seen of every pointer and refuses older ones, so a
+/// replayed record cannot roll a receiver back to outdated content.
+fn check_version(sender: &PublicKey, record: &PointerRecord) -> anyhow::Result<()> {
+    let _lock = SEEN_LOCK.lock().unwrap();
+    let path = data_dir()?.join("pointers.json");
+    let mut seen: BTreeMap<String, u64> = match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let key = format!("{}/{}", sender, record.name);
+    let newest = seen.entry(key).or_default();
+    ensure!(
+        record.version >= *newest,
+        "The sender offered an older version of this content than before"
+    );
+    *newest = record.version;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&seen)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pointer_record_signature() {
+        let secret_key = SecretKey::generate(&mut rand::rng());
+        let record = PointerRecord::sign(&secret_key, "docs".into(), 7, Hash::new(b"v1"));
+        assert!(record.verify(&secret_key.public()).is_ok());
+
+        let mut tampered = record.clone();
+        tampered.hash = Hash::new(b"v2");
+        assert!(tampered.verify(&secret_key.public()).is_err());
+
+        let other = SecretKey::generate(&mut rand::rng());
+        assert!(record.verify(&other.public()).is_err());
+    }
+
+    #[test]
+    fn test_pointer_ticket_roundtrip() {
+        let secret_key = SecretKey::generate(&mut rand::rng());
+        let ticket = PointerTicket {
+            addr: EndpointAddr::new(secret_key.public()),
+            name: "docs".into(),
+        };
+        let text = ticket.to_string();
+        assert!(PointerTicket::is_pointer_ticket(&text));
+        assert_eq!(text.parse::<PointerTicket>().unwrap(), ticket);
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::Context;
+use image::GrayImage;
+use qrcode::{Color, QrCode};
+use std::path::Path;
+
+/// A QR code as a square grid of modules, ready to be painted by the UI.
+#[derive(Debug, Clone)]
+pub struct QrMatrix {
+    /// Number of modules per side.
+    pub width: usize,
+    /// Row-major module colors, `true` for dark.
+    pub modules: Vec<bool>,
+}
+
+impl QrMatrix {
+    /// Returns true if the module at (x, y) is dark.
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.width + x]
+    }
+}
+
+/// Encodes a ticket or web link as a QR code.
+pub fn encode_qr(data: &str) -> anyhow::Result<QrMatrix> {
+    let code = QrCode::new(data.as_bytes()).context("Ticket is too long for a QR code")?;
+    Ok(QrMatrix {
+        width: code.width(),
+        modules: code
+            .to_colors()
+            .into_iter()
+            .map(|c| c == Color::Dark)
+            .collect(),
+    })
+}
+
+/// Decodes the first QR code found in an image file.
+pub fn decode_qr_file(path: &Path) -> anyhow::Result<String> {
+    let image =
+        image::open(path).with_context(|| format!("Failed to open image {}", path.display()))?;
+    decode_qr_image(&image.to_luma8())
+}
+
+/// Decodes the first QR code found in raw RGBA pixels (e.g. an image from the clipboard).
+pub fn decode_qr_rgba(width: usize, height: usize, rgba: &[u8]) -> anyhow::Result<String> {
+    anyhow::ensure!(
+        rgba.len() >= width * height * 4,
+        "Image data is smaller than {}x{} pixels",
+        width,
+        height
+    );
+    let gray = GrayImage::from_fn(width as u32, height as u32, |x, y| {
+        let i = (y as usize * width + x as usize) * 4;
+        let [r, g, b] = [rgba[i] as u32, rgba[i + 1] as u32, rgba[i + 2] as u32];
+        image::Luma([((r * 299 + g * 587 + b * 114) / 1000) as u8])
+    });
+    decode_qr_image(&gray)
+}
+
+fn decode_qr_image(image: &GrayImage) -> anyhow::Result<String> {
+    let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(
+        image.width() as usize,
+        image.height() as usize,
+        |x, y| image.get_pixel(x as u32, y as u32).0[0],
+    );
+    let grids = prepared.detect_grids();
+    grids
+        .iter()
+        .find_map(|grid| grid.decode().ok())
+        .map(|(_, content)| content.trim().to_string())
+        .context("No QR code found in the image")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Paints a matrix into a grayscale image with a quiet zone, like a screenshot would contain.
+    fn render(matrix: &QrMatrix, scale: usize) -> GrayImage {
+        let border = 4;
+        let size = (matrix.width + border * 2) * scale;
+        GrayImage::from_fn(size as u32, size as u32, |x, y| {
+            let (mx, my) = (x as usize / scale, y as usize / scale);
+            let dark = mx >= border
+                && my >= border
+                && mx < matrix.width + border
+                && my < matrix.width + border
+                && matrix.is_dark(mx - border, my - border);
+            image::Luma([if dark { 0 } else { 255 }])
+        })
+    }
+
+    #[test]
+    fn test_qr_roundtrip() {
+        let ticket = "blobacvjlsyxq3kv2n5hvn2jjnsfm3pu7c6vnh5gz6s3r6b5x4m4hwxiqajdnb2hi4dthixs65ltmuys2mjoojswyylzfzxdaltjojxwqltjojxwqltmnfxgwlrpaiaakdh3jxaqaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        let matrix = encode_qr(ticket).unwrap();
+        let image = render(&matrix, 4);
+        assert_eq!(decode_qr_image(&image).unwrap(), ticket);
+    }
+
+    #[test]
+    fn test_decode_rejects_blank_image() {
+        let image = GrayImage::from_pixel(64, 64, image::Luma([255]));
+        assert!(decode_qr_image(&image).is_err());
+    }
+}
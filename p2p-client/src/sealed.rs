@@ -0,0 +1,282 @@
+//! End-to-end encryption of web links. The file is served as AES-256-GCM records and the
+//! key only travels in the fragment of the link, which browsers never send to the server.
+//! Neither the tunnel provider nor anyone else in between can read the file; the page the
+//! link opens decrypts it in the browser, and the receive command decrypts it the same way.
+//!
+//! Every record holds [`RECORD_SIZE`] bytes of the file, the last one the rest. Record `n`
+//! is sealed with the nonce `n` and tells in its additional data whether it is the last,
+//! so records cannot be reordered, and a download that was cut short does not open.
+
+use anyhow::Context;
+use data_encoding::BASE64URL_NOPAD;
+use futures::Stream;
+use iroh_blobs::Hash;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Bytes of the file in every record but the last.
+pub(crate) const RECORD_SIZE: u64 = 64 * 1024;
+
+/// The authentication tag added to every record.
+const TAG_LEN: u64 = 16;
+
+/// The nonce of the metadata, which no record of the file reaches.
+const META_COUNTER: u64 = u64::MAX;
+
+/// The additional data of each kind of record. The page in `sealed.html` uses the same.
+const DATA: u8 = 0;
+const LAST: u8 = 1;
+const META: u8 = 2;
+
+/// The page that decrypts the file in the browser.
+pub(crate) const PAGE: &str = include_str!("sealed.html");
+
+/// What the page shows before the download, sealed so the server cannot tell either.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SealedMeta {
+    pub name: String,
+    pub size: u64,
+    pub hash: Hash,
+}
+
+/// The key of one encrypted web link.
+#[derive(Clone)]
+pub(crate) struct SealKey([u8; 32]);
+
+impl SealKey {
+    pub(crate) fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    /// Encodes the key for the fragment of the link.
+    pub(crate) fn to_fragment(&self) -> String {
+        BASE64URL_NOPAD.encode(&self.0)
+    }
+
+    pub(crate) fn from_fragment(fragment: &str) -> anyhow::Result<Self> {
+        let bytes = BASE64URL_NOPAD
+            .decode(fragment.as_bytes())
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .context("The key in the web link is incomplete")?;
+        Ok(Self(bytes))
+    }
+
+    fn key(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.0).expect("the key has 32 bytes"))
+    }
+
+    fn seal(&self, counter: u64, kind: u8, mut record: Vec<u8>) -> Vec<u8> {
+        self.key()
+            .seal_in_place_append_tag(nonce(counter), Aad::from([kind]), &mut record)
+            .expect("records are far below the size limit of AES-GCM");
+        record
+    }
+
+    fn open(&self, counter: u64, kind: u8, mut record: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let len = self
+            .key()
+            .open_in_place(nonce(counter), Aad::from([kind]), &mut record)
+            .map_err(|_| anyhow::anyhow!("The download was tampered with or the key is wrong"))?
+            .len();
+        record.truncate(len);
+        Ok(record)
+    }
+
+    pub(crate) fn seal_meta(&self, meta: &SealedMeta) -> Vec<u8> {
+        let json = serde_json::to_vec(meta).expect("the metadata is plain JSON");
+        self.seal(META_COUNTER, META, json)
+    }
+
+    pub(crate) fn open_meta(&self, sealed: Vec<u8>) -> anyhow::Result<SealedMeta> {
+        let json = self.open(META_COUNTER, META, sealed)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// The 96 bit nonce of record `counter`: four zero bytes and the counter, big endian.
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Records of a file of `size` bytes. An empty file still has one, so its end is sealed.
+fn record_count(size: u64) -> u64 {
+    size.div_ceil(RECORD_SIZE).max(1)
+}
+
+/// The plaintext length of record `counter` of a file of `size` bytes.
+fn record_len(size: u64, counter: u64) -> u64 {
+    if counter + 1 == record_count(size) {
+        size - counter * RECORD_SIZE
+    } else {
+        RECORD_SIZE
+    }
+}
+
+/// The size of a file of `size` bytes once sealed.
+pub(crate) fn sealed_size(size: u64) -> u64 {
+    size + record_count(size) * TAG_LEN
+}
+
+/// Reads the `size` bytes of a file from `reader` and seals them record by record.
+pub(crate) fn seal_stream<R>(
+    key: SealKey,
+    reader: R,
+    size: u64,
+) -> impl Stream<Item = std::io::Result<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    futures::stream::unfold((reader, 0), move |(mut reader, counter)| {
+        let key = key.clone();
+        async move {
+            if counter == record_count(size) {
+                return None;
+            }
+            let mut record = vec![0; record_len(size, counter) as usize];
+            let record = match reader.read_exact(&mut record).await {
+                Ok(_) => {
+                    let kind = if counter + 1 == record_count(size) {
+                        LAST
+                    } else {
+                        DATA
+                    };
+                    Ok(key.seal(counter, kind, record))
+                }
+                Err(e) => Err(e),
+            };
+            Some((record, (reader, counter + 1)))
+        }
+    })
+}
+
+/// Decrypts a sealed file of `size` bytes as it is downloaded.
+pub(crate) struct Opener {
+    key: SealKey,
+    size: u64,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl Opener {
+    pub(crate) fn new(key: SealKey, size: u64) -> Self {
+        Self {
+            key,
+            size,
+            counter: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Takes the next downloaded bytes and returns the plaintext of the records they complete.
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.buffer.extend_from_slice(bytes);
+        let mut plaintext = Vec::new();
+        let records = record_count(self.size);
+        while self.counter < records {
+            let len = (record_len(self.size, self.counter) + TAG_LEN) as usize;
+            if self.buffer.len() < len {
+                break;
+            }
+            let rest = self.buffer.split_off(len);
+            let record = std::mem::replace(&mut self.buffer, rest);
+            let kind = if self.counter + 1 == records {
+                LAST
+            } else {
+                DATA
+            };
+            plaintext.extend(self.key.open(self.counter, kind, record)?);
+            self.counter += 1;
+        }
+        anyhow::ensure!(
+            self.counter < records || self.buffer.is_empty(),
+            "The download is longer than the file"
+        );
+        Ok(plaintext)
+    }
+
+    /// Fails unless the whole file was opened.
+    pub(crate) fn finish(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.counter == record_count(self.size),
+            "The download ended early"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    async fn seal_all(key: &SealKey, data: &[u8]) -> Vec<u8> {
+        let records: Vec<Vec<u8>> = seal_stream(key.clone(), data, data.len() as u64)
+            .try_collect()
+            .await
+            .unwrap();
+        records.concat()
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let key = SealKey::generate();
+        for size in [0, 1, RECORD_SIZE, RECORD_SIZE * 2 + 5] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let sealed = seal_all(&key, &data).await;
+            assert_eq!(sealed.len() as u64, sealed_size(size));
+
+            // Downloads arrive in pieces that do not line up with the records.
+            let mut opener = Opener::new(key.clone(), size);
+            let mut opened = Vec::new();
+            for piece in sealed.chunks(1000) {
+                opened.extend(opener.push(piece).unwrap());
+            }
+            opener.finish().unwrap();
+            assert_eq!(opened, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tampering_and_truncation_fail() {
+        let key = SealKey::generate();
+        let data = vec![7; (RECORD_SIZE * 2) as usize];
+        let sealed = seal_all(&key, &data).await;
+
+        let mut tampered = sealed.clone();
+        tampered[10] ^= 1;
+        assert!(Opener::new(key.clone(), data.len() as u64)
+            .push(&tampered)
+            .is_err());
+
+        // Dropping the last record and claiming a smaller file fails, as the record before
+        // was not sealed as the last one.
+        let first = (RECORD_SIZE + TAG_LEN) as usize;
+        assert!(Opener::new(key.clone(), RECORD_SIZE)
+            .push(&sealed[..first])
+            .is_err());
+
+        let mut opener = Opener::new(key.clone(), data.len() as u64);
+        opener.push(&sealed[..first]).unwrap();
+        assert!(opener.finish().is_err());
+
+        let other = SealKey::generate();
+        assert!(Opener::new(other, data.len() as u64).push(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_meta_and_key_roundtrip() {
+        let key = SealKey::generate();
+        let key = SealKey::from_fragment(&key.to_fragment()).unwrap();
+        let meta = SealedMeta {
+            name: "report.pdf".to_string(),
+            size: 42,
+            hash: Hash::new(b"report"),
+        };
+        assert_eq!(key.open_meta(key.seal_meta(&meta)).unwrap(), meta);
+        assert!(SealKey::from_fragment("c2hvcnQ").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use super::config::data_dir;
+use super::files::import;
+use super::node::Node;
+use super::state::{SendHandle, SendStatus};
+use super::store::{delete_share_tag, ShareTag};
+use super::{p2p, web};
+use iroh_blobs::{
+    api::{Store, TempTag},
+    format::collection::Collection,
+    Hash, HashAndFormat,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{runtime::Handle as TokioHandle, sync::mpsc};
+
+/// Serializes read-modify-write cycles on the shares file within this process.
+static SHARES_LOCK: Mutex<()> = Mutex::new(());
+
+/// How a persistent share is offered to others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShareMode {
+    /// An iroh ticket for other p2p-client users.
+    Ticket,
+    /// A public web link through ngrok.
+    Web,
+    /// A web link through ngrok to the encrypted file. The key is in the link, so only
+    /// those who have the link can read the file, not the tunnel provider.
+    EncryptedWeb,
+}
+
+/// A share that is recorded in the local database and announced again after a restart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShareInfo {
+    pub id: String,
+    pub name: String,
+    /// The file or folder the share was created from.
+    pub path: PathBuf,
+    /// Hash of the collection holding the shared content.
+    pub hash: Hash,
+    /// Creation time, in seconds since the Unix epoch.
+    pub created: u64,
+    /// Expiry time, in seconds since the Unix epoch. `None` never expires.
+    pub expires: Option<u64>,
+    pub mode: ShareMode,
+    /// The ticket or web link handed out most recently.
+    pub ticket: Option<String>,
+    /// Shares the folder again whenever it changes.
+    #[serde(default)]
+    pub watch: bool,
+    /// True once the web link has changed because the share was announced again after a
+    /// restart. Every ngrok tunnel gets a new address, so the old link no longer works.
+    #[serde(default)]
+    pub link_changed: bool,
+}
+
+impl ShareInfo {
+    pub(crate) fn new(
+        name: String,
+        path: PathBuf,
+        hash: Hash,
+        mode: ShareMode,
+        expires_in: Option<Duration>,
+    ) -> Self {
+        let id: [u8; 8] = rand::random();
+        let created = now();
+        Self {
+            id: hex::encode(id),
+            name,
+            path,
+            hash,
+            created,
+            expires: expires_in.map(|d| created + d.as_secs()),
+            mode,
+            ticket: None,
+            watch: false,
+            link_changed: false,
+        }
+    }
+
+    /// Returns true once the expiry time has passed.
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn shares_file() -> anyhow::Result<PathBuf> {
+    Ok(data_dir()?.join("shares.json"))
+}
+
+fn read_from(path: &Path) -> anyhow::Result<Vec<ShareInfo>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_to(path: &Path, shares: &[ShareInfo]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(shares)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Applies `f` to the recorded shares and writes the result back.
+fn update<T>(f: impl FnOnce(&mut Vec<ShareInfo>) -> T) -> anyhow::Result<T> {
+    let _lock = SHARES_LOCK.lock().unwrap();
+    let path = shares_file()?;
+    let mut shares = read_from(&path)?;
+    let result = f(&mut shares);
+    write_to(&path, &shares)?;
+    Ok(result)
+}
+
+/// Returns all recorded shares, oldest first.
+pub(crate) fn list() -> anyhow::Result<Vec<ShareInfo>> {
+    let _lock = SHARES_LOCK.lock().unwrap();
+    read_from(&shares_file()?)
+}
+
+/// Records a new share or replaces the record with the same id.
+pub(crate) fn save(info: &ShareInfo) -> anyhow::Result<()> {
+    update(|shares| match shares.iter_mut().find(|s| s.id == info.id) {
+        Some(existing) => *existing = info.clone(),
+        None => shares.push(info.clone()),
+    })
+}
+
+/// Deletes the record of a share. Returns false if there was none.
+pub(crate) fn remove(id: &str) -> anyhow::Result<bool> {
+    update(|shares| {
+        let before = shares.len();
+        shares.retain(|s| s.id != id);
+        shares.len() != before
+    })
+}
+
+/// Imports `path` and announces it as a persistent share.
+/// With `watch`, the folder is shared again whenever it changes.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn create_share_internal(
+    node: Node,
+    path: PathBuf,
+    name: String,
+    mode: ShareMode,
+    expires_in: Option<Duration>,
+    watch: bool,
+    progress: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<SendHandle> {
+    anyhow::ensure!(
+        !watch || mode == ShareMode::Ticket,
+        "Only ticket shares can watch a folder"
+    );
+    progress.send(SendStatus::Connecting).await?;
+    let path = path.canonicalize()?;
+    let (temp_tag, _size, collection) = import(&path, node.store(), progress.clone()).await?;
+    let mut info = ShareInfo::new(name, path, temp_tag.hash(), mode, expires_in);
+    info.watch = watch;
+    let id = info.id.clone();
+    let store = node.store().clone();
+    let result = start(node, temp_tag, collection, info, progress, tokio_handle).await;
+    if result.is_err() {
+        delete_share_tag(&store, &id).await.ok();
+    }
+    result
+}
+
+/// Announces all recorded shares again, e.g. after the application was restarted.
+/// Expired shares are removed; shares that fail to start are reported and skipped.
+pub(crate) async fn restore_shares_internal(
+    node: Node,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<Vec<SendHandle>> {
+    let mut handles = Vec::new();
+    for info in list()? {
+        if info.is_expired() {
+            println!("Share \"{}\" has expired.", info.name);
+            remove_share_internal(node.store(), &info.id).await?;
+            continue;
+        }
+        let name = info.name.clone();
+        match restore_one(node.clone(), info, tokio_handle.clone()).await {
+            Ok(handle) => handles.push(handle),
+            Err(e) => println!("Failed to restore share \"{}\": {:#}", name, e),
+        }
+    }
+    Ok(handles)
+}
+
+async fn restore_one(
+    node: Node,
+    info: ShareInfo,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<SendHandle> {
+    let content = HashAndFormat::hash_seq(info.hash);
+    let temp_tag = node.store().tags().temp_tag(content).await?;
+    anyhow::ensure!(
+        node.store().remote().local(content).await?.is_complete(),
+        "content is no longer in the store"
+    );
+    let collection = Collection::load(info.hash, node.store()).await?;
+    // Nobody listens to the progress of a restored share, but the sender must not fail.
+    let (progress, _progress_rx) = mpsc::channel(8);
+    start(node, temp_tag, collection, info, progress, tokio_handle).await
+}
+
+/// Tags the content of a share, announces it and records the ticket it was announced with.
+async fn start(
+    node: Node,
+    temp_tag: TempTag,
+    collection: Collection,
+    info: ShareInfo,
+    progress: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<SendHandle> {
+    let share_tag = ShareTag::persistent(node.store(), &info.id, info.hash).await?;
+    let handle = match info.mode {
+        ShareMode::Ticket => {
+            let (path, watch) = (info.path.clone(), info.watch);
+            p2p::announce(
+                node,
+                temp_tag,
+                share_tag,
+                Some(info),
+                path,
+                watch,
+                progress,
+                tokio_handle,
+            )
+            .await?
+        }
+        ShareMode::Web | ShareMode::EncryptedWeb => {
+            let encrypted = info.mode == ShareMode::EncryptedWeb;
+            let path = info.path.clone();
+            web::serve(
+                node,
+                temp_tag,
+                share_tag,
+                collection,
+                path,
+                Some(info),
+                encrypted,
+                progress,
+                tokio_handle,
+            )
+            .await?
+        }
+    };
+    if let Some(info) = handle.share() {
+        save(info)?;
+    }
+    Ok(handle)
+}
+
+/// Deletes the record of a share and releases its content for garbage collection.
+pub(crate) async fn remove_share_internal(store: &Store, id: &str) -> anyhow::Result<()> {
+    remove(id)?;
+    delete_share_tag(store, id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shares_file_roundtrip() {
+        let dir = std::env::temp_dir().join(format!(
+            "p2p-client-test-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let path = dir.join("shares.json");
+        assert!(read_from(&path).unwrap().is_empty());
+
+        let share = ShareInfo::new(
+            "holiday".to_string(),
+            PathBuf::from("/photos/holiday"),
+            Hash::new(b"holiday"),
+            ShareMode::Ticket,
+            Some(Duration::from_secs(3600)),
+        );
+        assert!(!share.is_expired());
+        write_to(&path, std::slice::from_ref(&share)).unwrap();
+        assert_eq!(read_from(&path).unwrap(), vec![share]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,579 @@
+//! Downloads one collection from several peers that share the same content. Every
+//! peer fetches parts of files from a common queue, so faster peers take on more of the
+//! work, and the parts of a peer that goes away are fetched from the others. Large files
+//! are split by their chunks, so that several peers fetch one file at once.
+//! Peers asked to compress send whole files compressed, see [`compress`], so files are not
+//! split then.
+
+use super::compress;
+use super::diagnostics::ActivePeer;
+use super::limit::{self, RateLimiter};
+use super::node::Node;
+use super::pointer::{self, PointerTicket};
+use super::state::ReceiveStatus;
+use super::stats::{PathKind, SourceProgress, Throughput};
+use anyhow::{bail, Context};
+use bao_tree::ChunkNum;
+use futures::future::join_all;
+use iroh::{endpoint::Connection, EndpointAddr, EndpointId};
+use iroh_blobs::{
+    api::{remote::GetProgressItem, Store},
+    get::request::get_hash_seq_and_sizes,
+    hashseq::HashSeq,
+    protocol::{ChunkRanges, GetRequest, ALPN as BlobsAlpn},
+    ticket::BlobTicket,
+    Hash, HashAndFormat,
+};
+use n0_future::StreamExt;
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinSet,
+};
+
+/// A peer that has the collection, connected.
+pub(crate) struct Source {
+    pub(crate) peer: EndpointId,
+    connection: Connection,
+    /// A second connection that files are fetched compressed over, if asked for and the
+    /// peer knows how.
+    compressed: Option<Connection>,
+    /// Shows the peer in the diagnostics while the download runs.
+    _active: ActivePeer,
+}
+
+/// Finds the peer behind another ticket or node id given for the collection `hash`.
+pub(crate) async fn source_addr(
+    node: &Node,
+    source: &str,
+    hash: Hash,
+) -> anyhow::Result<EndpointAddr> {
+    let source = source.trim();
+    let ticket = if PointerTicket::is_pointer_ticket(source) {
+        let pointer = PointerTicket::from_str(source)?;
+        pointer::resolve(node.endpoint(), &pointer).await?
+    } else if let Ok(ticket) = BlobTicket::from_str(source) {
+        ticket
+    } else {
+        let id = EndpointId::from_str(source)
+            .with_context(|| format!("{} is neither a ticket nor a node id", source))?;
+        return Ok(EndpointAddr::new(id));
+    };
+    anyhow::ensure!(
+        ticket.hash() == hash,
+        "The ticket of {} shares different content",
+        ticket.addr().id.fmt_short()
+    );
+    Ok(ticket.addr().clone())
+}
+
+/// Connects to all `providers` at once. Peers that cannot be reached are skipped, as
+/// long as at least one can. With `compress`, peers are also asked to send files
+/// compressed; those that cannot send them as they are.
+pub(crate) async fn connect(
+    node: &Node,
+    providers: Vec<EndpointAddr>,
+    compress: bool,
+) -> anyhow::Result<Vec<Source>> {
+    let attempts = providers.into_iter().map(|addr| async move {
+        let peer = addr.id;
+        let result = node.endpoint().connect(addr.clone(), BlobsAlpn).await;
+        let compressed = match &result {
+            Ok(_) if compress => match node.endpoint().connect(addr, compress::ALPN).await {
+                Ok(connection) => Some(connection),
+                Err(e) => {
+                    println!("{} cannot compress: {}", peer.fmt_short(), e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        (peer, result, compressed)
+    });
+    let mut sources = Vec::new();
+    let mut error = None;
+    for (peer, result, compressed) in join_all(attempts).await {
+        match result {
+            Ok(connection) => sources.push(Source {
+                peer,
+                connection,
+                compressed,
+                _active: node.active_peers().track(peer),
+            }),
+            Err(e) => {
+                println!("Failed to connect to {}: {}", peer.fmt_short(), e);
+                error.get_or_insert(e);
+            }
+        }
+    }
+    match error {
+        Some(e) if sources.is_empty() => Err(e.into()),
+        _ => Ok(sources),
+    }
+}
+
+/// Downloads the hash sequence of the collection `hash` and its metadata, trying one
+/// source after the other. Returns the hash sequence and the sizes of its blobs.
+pub(crate) async fn fetch_hash_seq(
+    store: &Store,
+    sources: &[Source],
+    hash: Hash,
+) -> anyhow::Result<(HashSeq, Vec<u64>)> {
+    let mut error = None;
+    for source in sources {
+        let result = async {
+            let (hash_seq, sizes) =
+                get_hash_seq_and_sizes(&source.connection, &hash, 1024 * 1024 * 32, None)
+                    .await
+                    .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+            // The names of the files are in the first child.
+            let request = GetRequest::builder()
+                .root(ChunkRanges::all())
+                .child(0, ChunkRanges::all())
+                .build(hash);
+            store
+                .remote()
+                .execute_get(source.connection.clone(), request)
+                .await
+                .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+            anyhow::Ok((hash_seq, sizes.to_vec()))
+        }
+        .await;
+        match result {
+            Ok(result) => return Ok(result),
+            Err(e) => {
+                println!(
+                    "{} did not send the collection: {}",
+                    source.peer.fmt_short(),
+                    e
+                );
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| anyhow::anyhow!("No peer to download from")))
+}
+
+/// Files are split into parts of this many chunks of 1 KiB.
+const PART_CHUNKS: u64 = 16 * 1024;
+
+/// The chunks `ranges` of the file `hash`, fetched by one peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Part {
+    hash: Hash,
+    ranges: ChunkRanges,
+}
+
+impl Part {
+    /// Splits the chunks `missing` of the file `hash` of `size` bytes into parts of at most
+    /// [`PART_CHUNKS`] chunks.
+    fn split(hash: Hash, size: u64, missing: &ChunkRanges) -> Vec<Part> {
+        let chunks = ChunkNum::chunks(size).0;
+        let mut parts = Vec::new();
+        let mut start = 0;
+        while start < chunks {
+            let end = start + PART_CHUNKS;
+            // The last part also asks for what lies beyond, which proves the size.
+            let mut ranges = if end < chunks {
+                ChunkRanges::from(ChunkNum(start)..ChunkNum(end))
+            } else {
+                ChunkRanges::from(ChunkNum(start)..)
+            };
+            ranges.intersection_with(missing);
+            if !ranges.is_empty() {
+                parts.push(Part { hash, ranges });
+            }
+            start = end;
+        }
+        if parts.is_empty() {
+            parts.push(Part {
+                hash,
+                ranges: missing.clone(),
+            });
+        }
+        parts
+    }
+}
+
+/// The parts still to be fetched, shared by the workers of all sources.
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// The parts nobody works on.
+    parts: VecDeque<Part>,
+    /// How many parts are being fetched right now.
+    in_flight: usize,
+    /// How many parts of each file are not done yet.
+    remaining: HashMap<Hash, usize>,
+}
+
+impl Queue {
+    fn new(parts: impl IntoIterator<Item = Part>) -> Self {
+        let parts: VecDeque<Part> = parts.into_iter().collect();
+        let mut remaining = HashMap::new();
+        for part in &parts {
+            *remaining.entry(part.hash).or_default() += 1;
+        }
+        Self {
+            state: Mutex::new(QueueState {
+                parts,
+                in_flight: 0,
+                remaining,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Takes the next part. While others are still fetching parts, waits for one of
+    /// them to fail, so that its part is not left behind. Returns None when all parts
+    /// are done.
+    async fn next(&self) -> Option<Part> {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(part) = state.parts.pop_front() {
+                    state.in_flight += 1;
+                    return Some(part);
+                }
+                if state.in_flight == 0 {
+                    return None;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Marks a part taken with [`Queue::next`] as done, or puts it back if it failed.
+    /// Returns true if that was the last part of its file.
+    fn finish(&self, part: Part, done: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        let mut file_done = false;
+        if done {
+            if let Some(remaining) = state.remaining.get_mut(&part.hash) {
+                *remaining -= 1;
+                file_done = *remaining == 0;
+            }
+        } else {
+            state.parts.push_back(part);
+        }
+        self.changed.notify_waiters();
+        file_done
+    }
+
+    fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().parts.is_empty()
+    }
+}
+
+/// What a worker reports, by the index of its source.
+enum Event {
+    Progress(usize, u64),
+    FileDone(usize),
+    /// A part failed with an error, losing the given number of bytes reported for it
+    /// that did not make it into the store and are fetched again.
+    Failed(usize, String, u64),
+}
+
+/// Downloads the blobs `files` of the collection `collection`, given with their sizes,
+/// from all `sources`, limited by `download_limit`. Progress counts the parts already in
+/// the store as downloaded. Returns how many bytes of the files were fetched.
+/// With several sources that do not compress, large files are split into parts.
+pub(crate) async fn fetch(
+    node: &Node,
+    sources: &[Source],
+    collection: Hash,
+    files: &[(Hash, u64)],
+    download_limit: &RateLimiter,
+    throughput: &mut Throughput,
+    progress: &mpsc::Sender<ReceiveStatus>,
+) -> anyhow::Result<u64> {
+    let store = node.store();
+    let total = files.iter().map(|(_, size)| size).sum::<u64>();
+    let split = sources.len() > 1 && sources.iter().all(|source| source.compressed.is_none());
+    let mut local_bytes = 0;
+    let mut parts = Vec::new();
+    for (hash, size) in files {
+        let local = store.remote().local(HashAndFormat::raw(*hash)).await?;
+        local_bytes += local.local_bytes();
+        if local.is_complete() {
+            continue;
+        }
+        let missing = missing_ranges(&local.missing());
+        if split {
+            parts.extend(Part::split(*hash, *size, &missing));
+        } else {
+            parts.push(Part {
+                hash: *hash,
+                ranges: missing,
+            });
+        }
+    }
+    if parts.is_empty() {
+        return Ok(0);
+    }
+
+    let queue = Arc::new(Queue::new(parts));
+    let (events_tx, mut events) = mpsc::channel(32);
+    let mut workers = JoinSet::new();
+    for (index, source) in sources.iter().enumerate() {
+        workers.spawn(worker(
+            store.clone(),
+            source.connection.clone(),
+            source.compressed.clone(),
+            collection,
+            index,
+            queue.clone(),
+            download_limit.clone(),
+            events_tx.clone(),
+        ));
+    }
+    drop(events_tx);
+
+    let mut stats: Vec<SourceProgress> = sources
+        .iter()
+        .map(|source| SourceProgress {
+            peer: source.peer,
+            bytes: 0,
+            files: 0,
+            path: PathKind::Unknown,
+            error: None,
+        })
+        .collect();
+    let mut received = 0;
+    let mut last_error = None;
+    while let Some(event) = events.recv().await {
+        match event {
+            Event::Progress(index, bytes) => {
+                received += bytes;
+                stats[index].bytes += bytes;
+                let sampled = throughput.update(received);
+                let downloaded = local_bytes + received;
+                progress
+                    .send(ReceiveStatus::Downloading {
+                        downloaded,
+                        total,
+                        speed: throughput.speed(),
+                        eta: throughput.eta(downloaded, total),
+                        path: PathKind::of(node.endpoint(), sources[index].peer),
+                    })
+                    .await?;
+                if sampled && sources.len() > 1 {
+                    for stat in stats.iter_mut() {
+                        stat.path = PathKind::of(node.endpoint(), stat.peer);
+                    }
+                    progress.send(ReceiveStatus::Sources(stats.clone())).await?;
+                }
+            }
+            Event::FileDone(index) => stats[index].files += 1,
+            Event::Failed(index, error, lost) => {
+                received -= lost;
+                stats[index].bytes -= lost;
+                throughput.retract(lost);
+                println!(
+                    "Download from {} failed: {}",
+                    sources[index].peer.fmt_short(),
+                    error
+                );
+                stats[index].error = Some(error.clone());
+                last_error = Some(error);
+                if sources.len() > 1 {
+                    progress.send(ReceiveStatus::Sources(stats.clone())).await?;
+                }
+            }
+        }
+    }
+    while workers.join_next().await.is_some() {}
+    if sources.len() > 1 {
+        progress.send(ReceiveStatus::Sources(stats)).await?;
+    }
+    if !queue.is_empty() {
+        bail!(
+            "No peer left to download from: {}",
+            last_error.unwrap_or_default()
+        );
+    }
+    Ok(received)
+}
+
+/// The chunks of the blob that `request`, a request for what is missing of it, asks for.
+fn missing_ranges(request: &GetRequest) -> ChunkRanges {
+    request.ranges.iter().next().cloned().unwrap_or_default()
+}
+
+/// Fetches parts from the queue over one connection until the queue is empty or the
+/// connection fails. Parts are fetched over `compressed` if given, but for files that are
+/// split or partly in the store already, which only the blobs protocol can fetch.
+#[allow(clippy::too_many_arguments)]
+async fn worker(
+    store: Store,
+    connection: Connection,
+    compressed: Option<Connection>,
+    collection: Hash,
+    index: usize,
+    queue: Arc<Queue>,
+    download_limit: RateLimiter,
+    events: mpsc::Sender<Event>,
+) {
+    while let Some(part) = queue.next().await {
+        let hash = part.hash;
+        let stored = || async {
+            let request = GetRequest::builder().root(part.ranges.clone()).build(hash);
+            let local = store.remote().local_for_request(request).await;
+            local.map(|local| local.local_bytes()).unwrap_or_default()
+        };
+        let stored_before = stored().await;
+        let reported = AtomicU64::new(0);
+        let report = |bytes| {
+            reported.fetch_add(bytes, Ordering::Relaxed);
+            events.send(Event::Progress(index, bytes))
+        };
+        let result = async {
+            let local = store.remote().local(HashAndFormat::raw(hash)).await?;
+            if local.is_complete() {
+                return anyhow::Ok(());
+            }
+            let whole = local.local_bytes() == 0 && part.ranges == ChunkRanges::all();
+            if let Some(compressed) = compressed.as_ref().filter(|_| whole) {
+                return compress::fetch(
+                    &store,
+                    compressed,
+                    collection,
+                    hash,
+                    &download_limit,
+                    |bytes| {
+                        let sent = report(bytes);
+                        async move {
+                            sent.await.ok();
+                        }
+                    },
+                )
+                .await;
+            }
+            // Another peer may have fetched some of the part since it was queued.
+            let mut ranges = part.ranges.clone();
+            ranges.intersection_with(&missing_ranges(&local.missing()));
+            if ranges.is_empty() {
+                return Ok(());
+            }
+            let request = GetRequest::builder().root(ranges).build(hash);
+            let get = store.remote().execute_get(connection.clone(), request);
+            let mut stream = get.stream();
+            let mut received = 0;
+            while let Some(item) = stream.next().await {
+                match item {
+                    GetProgressItem::Progress(offset) => {
+                        // Not polling the stream holds back the download until the limits allow more.
+                        limit::throttle_download(&download_limit, offset - received).await;
+                        report(offset - received).await.ok();
+                        received = offset;
+                    }
+                    GetProgressItem::Done(_) => return Ok(()),
+                    GetProgressItem::Error(cause) => return Err(cause.into()),
+                }
+            }
+            bail!("The download ended early")
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                if queue.finish(part, true) {
+                    events.send(Event::FileDone(index)).await.ok();
+                }
+            }
+            Err(e) => {
+                // Progress counts what was received; what of it is not stored is fetched
+                // again, so it is taken back.
+                let kept = stored().await.saturating_sub(stored_before);
+                let lost = reported.into_inner().saturating_sub(kept);
+                // Another source picks the part up; this one is not asked again.
+                queue.finish(part, false);
+                events
+                    .send(Event::Failed(index, format!("{:#}", e), lost))
+                    .await
+                    .ok();
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whole(hash: Hash) -> Part {
+        Part {
+            hash,
+            ranges: ChunkRanges::all(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_hands_back_failed_parts() {
+        let a = whole(Hash::new(b"a"));
+        let b = whole(Hash::new(b"b"));
+        let queue = Arc::new(Queue::new([a.clone(), b.clone()]));
+        assert_eq!(queue.next().await, Some(a.clone()));
+        assert_eq!(queue.next().await, Some(b.clone()));
+
+        // With both parts taken, a worker waits instead of giving up.
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.next().await }
+        });
+        assert!(queue.finish(a, true));
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        assert!(!queue.finish(b.clone(), false));
+        assert_eq!(waiting.await.unwrap(), Some(b.clone()));
+
+        assert!(queue.finish(b, true));
+        assert_eq!(queue.next().await, None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_split_parts() {
+        let hash = Hash::new(b"file");
+        let size = PART_CHUNKS * 1024 * 2 + 10;
+        let parts = Part::split(hash, size, &ChunkRanges::all());
+        let ranges: Vec<_> = parts.into_iter().map(|part| part.ranges).collect();
+        assert_eq!(
+            ranges,
+            vec![
+                ChunkRanges::from(ChunkNum(0)..ChunkNum(PART_CHUNKS)),
+                ChunkRanges::from(ChunkNum(PART_CHUNKS)..ChunkNum(2 * PART_CHUNKS)),
+                ChunkRanges::from(ChunkNum(2 * PART_CHUNKS)..),
+            ]
+        );
+
+        // Only what is missing is fetched.
+        let missing = ChunkRanges::from(ChunkNum(PART_CHUNKS + 5)..);
+        let parts = Part::split(hash, size, &missing);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts[0].ranges,
+            ChunkRanges::from(ChunkNum(PART_CHUNKS + 5)..ChunkNum(2 * PART_CHUNKS))
+        );
+
+        // Small files stay whole.
+        let parts = Part::split(hash, 100, &ChunkRanges::all());
+        assert_eq!(parts, vec![whole(hash)]);
+    }
+}
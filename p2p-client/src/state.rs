@@ -1,38 +1,112 @@
+use super::history::{self, FetchFlag, HistoryEntry, HistoryKind, TransferOutcome};
+use super::limit::RateLimiter;
+use super::node::Node;
+use super::pointer::Pointer;
+use super::shares::ShareInfo;
+use super::stats::{PathKind, SourceProgress, TransferSummary};
+use super::store::ShareTag;
+use super::sync::SyncPlan;
+use super::uploads::UploadRegistration;
+use iroh::EndpointId;
+use iroh_blobs::api::TempTag;
 use ngrok::forwarder::Forwarder;
+use serde::{Deserialize, Serialize};
+use n0_future::task::AbortOnDropHandle;
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::runtime::Handle as TokioHandle;
 use ngrok::tunnel::TunnelCloser;
 
 /// Defines the states of a send operation for reporting progress to the UI.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SendStatus {
     Connecting,
     Importing { total_files: usize, done_files: usize, total_size: u64, done_size: u64 },
     ReadyToSend { ticket: String },
+    /// A watched folder changed. The ticket stays the same and now resolves to the new version.
+    Updated { ticket: String },
+    /// A receiver is fetching the content; `speed` is in bytes per second.
+    Uploading { peer: Option<EndpointId>, sent: u64, speed: u64, path: PathKind },
+    /// A receiver disconnected after fetching `summary.bytes`.
+    Uploaded { peer: Option<EndpointId>, summary: TransferSummary },
     Done,
     Error(String),
 }
 
 /// Defines the states of a receive operation for reporting progress to the UI.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ReceiveStatus {
     Connecting,
     Connected { total_files: u64, total_size: u64 },
-    Downloading { downloaded: u64, total: u64 },
+    /// What a sync receive is going to change, reported before anything is downloaded.
+    SyncPlan(SyncPlan),
+    /// `speed` is in bytes per second, `eta` in seconds once the speed is known.
+    Downloading { downloaded: u64, total: u64, speed: u64, eta: Option<u64>, path: PathKind },
+    /// What each peer contributed, when downloading from several peers at once.
+    Sources(Vec<SourceProgress>),
     Exporting { total_files: u64, done_files: u64 },
-    Done,
+    Done(TransferSummary),
+    /// The download is shared again from this node as transfer `id`, after `Done`.
+    Seeding { id: u64, ticket: String },
+    Error(String),
+}
+
+/// Defines the states of a file request for reporting progress to the UI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum InboxStatus {
+    Connecting,
+    /// The upload page is online at `link`.
+    Ready { link: String },
+    /// A browser is uploading `name`; `received` counts all bytes of its upload so far.
+    Receiving { name: String, received: u64, speed: u64 },
+    /// An uploaded file was saved to `path`.
+    Received { name: String, size: u64, path: PathBuf },
+    /// An upload was refused, e.g. for a wrong password or a size limit.
+    Rejected(String),
     Error(String),
 }
 
 /// A handle to a running send operation.
 /// When this struct is dropped, it automatically cleans up all associated resources.
 pub struct SendHandle {
-    pub(crate) data_dir: PathBuf,
     pub(crate) shutdown_tx: Option<tokio::sync::oneshot::Sender<()>>,
     pub(crate) _ngrok_tunnel: Option<Forwarder<ngrok::tunnel::HttpTunnel>>,
+    /// Protects the shared collection in the node's store while the send is active.
+    pub(crate) _temp_tag: TempTag,
+    /// Keeps the collection in the persistent store; released for garbage collection on drop.
+    pub(crate) _share_tag: Arc<ShareTag>,
+    /// Re-shares the source folder when it changes, in watch-folder mode.
+    pub(crate) _watcher: Option<AbortOnDropHandle<()>>,
+    /// The stable pointer to the latest version of a watched folder.
+    pub(crate) _pointer: Option<Arc<Pointer>>,
+    /// Limits the upload rate of this send. Can be changed while it runs.
+    pub(crate) upload_limit: RateLimiter,
+    /// Applies `upload_limit` to the content the node serves for this send and reports its uploads.
+    pub(crate) _upload_registration: Option<Arc<UploadRegistration>>,
+    /// Keeps the node serving the send alive until the send ends or the node is restarted.
+    pub(crate) _node: Node,
+    /// The database record, if this is a persistent share.
+    pub(crate) share: Option<ShareInfo>,
+    /// The file or folder that is sent.
+    pub(crate) path: PathBuf,
+    /// Set once anyone fetches the send. A send nobody fetched is recorded in the history
+    /// when it is dropped, unless it is a persistent share, which outlives its handle.
+    pub(crate) fetched: FetchFlag,
     pub(crate) tokio_handle: TokioHandle,
 }
-/// The Drop implementation ensures that background tasks are shut down and temporary files are deleted.
+
+impl SendHandle {
+    /// Returns the persistent share behind this send, if any.
+    pub fn share(&self) -> Option<&ShareInfo> {
+        self.share.as_ref()
+    }
+
+    /// Returns the upload limit of this send, which can be changed while it runs.
+    pub fn upload_limit(&self) -> &RateLimiter {
+        &self.upload_limit
+    }
+}
+/// The Drop implementation ensures that background tasks are shut down and the shared content is released.
 impl Drop for SendHandle {
     fn drop(&mut self) {
         if let Some(tx) = self.shutdown_tx.take() {
@@ -45,10 +119,14 @@ impl Drop for SendHandle {
                 println!("Ngrok tunnel closed.");
             });
         }
-        let data_dir = self.data_dir.clone();
-        std::thread::spawn(move || {
-            let _ = std::fs::remove_dir_all(data_dir);
-        });
+        if self.share.is_none() && !self.fetched.is_set() {
+            let entry = HistoryEntry::new(
+                HistoryKind::Send,
+                self.path.clone(),
+                TransferOutcome::Cancelled,
+            );
+            history::record(&entry);
+        }
         println!("Send operation cancelled and cleaning up.");
     }
 }
\ No newline at end of file
@@ -0,0 +1,216 @@
+//! Transfer statistics: smoothed throughput, time left and the network path a transfer
+//! takes.
+
+use iroh::{endpoint::ConnectionType, Endpoint, EndpointId, Watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// How often the throughput is sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How quickly the smoothed throughput follows changes, in seconds.
+const TIME_CONSTANT: f64 = 2.0;
+
+/// The network path to the other side of a transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathKind {
+    /// A direct UDP connection.
+    Direct,
+    /// Traffic goes through a relay server.
+    Relay,
+    /// A direct path is being tried while the relay is still in use.
+    Mixed,
+    /// A plain HTTP download of a web link.
+    Web,
+    #[default]
+    Unknown,
+}
+
+impl PathKind {
+    /// Returns the path `endpoint` currently uses to talk to `peer`.
+    pub(crate) fn of(endpoint: &Endpoint, peer: EndpointId) -> Self {
+        match endpoint.conn_type(peer).map(|mut watcher| watcher.get()) {
+            Some(ConnectionType::Direct(_)) => PathKind::Direct,
+            Some(ConnectionType::Relay(_)) => PathKind::Relay,
+            Some(ConnectionType::Mixed(..)) => PathKind::Mixed,
+            _ => PathKind::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for PathKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PathKind::Direct => "direct",
+            PathKind::Relay => "relayed",
+            PathKind::Mixed => "mixed",
+            PathKind::Web => "web",
+            PathKind::Unknown => "unknown path",
+        })
+    }
+}
+
+/// How a running transfer is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferProgress {
+    /// Bytes moved so far.
+    pub bytes: u64,
+    /// Bytes to move in total, if known.
+    pub total: Option<u64>,
+    /// Bytes per second.
+    pub speed: u64,
+    /// Seconds left, if known.
+    pub eta: Option<u64>,
+    pub path: PathKind,
+}
+
+/// How much one of several peers contributed to a download.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceProgress {
+    pub peer: EndpointId,
+    /// Bytes received from this peer.
+    pub bytes: u64,
+    /// Files completed by this peer.
+    pub files: u64,
+    pub path: PathKind,
+    /// Why the peer dropped out, if it did. Its files are fetched from the others.
+    pub error: Option<String>,
+}
+
+/// What a finished transfer moved and how long it took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferSummary {
+    /// Bytes that went over the network. Content that was already local is not counted.
+    pub bytes: u64,
+    pub duration: Duration,
+    pub path: PathKind,
+}
+
+impl TransferSummary {
+    /// The average speed in bytes per second.
+    pub fn average_speed(&self) -> u64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+}
+
+/// An exponentially smoothed measurement of how fast a transfer moves.
+#[derive(Debug, Clone)]
+pub(crate) struct Throughput {
+    start: Instant,
+    sampled: Instant,
+    sampled_bytes: u64,
+    speed: Option<f64>,
+}
+
+impl Throughput {
+    pub(crate) fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            sampled: now,
+            sampled_bytes: 0,
+            speed: None,
+        }
+    }
+
+    /// Records that `bytes` have been transferred in total. Returns true if this took a
+    /// new sample, which happens at most every [`SAMPLE_INTERVAL`].
+    pub(crate) fn update(&mut self, bytes: u64) -> bool {
+        self.update_at(bytes, Instant::now())
+    }
+
+    fn update_at(&mut self, bytes: u64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.sampled);
+        if elapsed < SAMPLE_INTERVAL {
+            return false;
+        }
+        let secs = elapsed.as_secs_f64();
+        let current = bytes.saturating_sub(self.sampled_bytes) as f64 / secs;
+        // Samples taken further apart weigh more, so the result does not depend on how
+        // often the transfer reports progress.
+        let weight = 1.0 - (-secs / TIME_CONSTANT).exp();
+        self.speed = Some(match self.speed {
+            Some(speed) => speed + weight * (current - speed),
+            None => current,
+        });
+        self.sampled = now;
+        self.sampled_bytes = bytes;
+        true
+    }
+
+    /// Takes back `bytes` of the total given to [`Throughput::update`] that were lost
+    /// and are transferred again, so that the next sample does not miss them.
+    pub(crate) fn retract(&mut self, bytes: u64) {
+        self.sampled_bytes = self.sampled_bytes.saturating_sub(bytes);
+    }
+
+    /// The smoothed speed in bytes per second.
+    pub(crate) fn speed(&self) -> u64 {
+        self.speed.unwrap_or_default() as u64
+    }
+
+    /// Seconds until `total` bytes are transferred at the current speed, if it is known.
+    pub(crate) fn eta(&self, done: u64, total: u64) -> Option<u64> {
+        match self.speed() {
+            0 => None,
+            speed => Some(total.saturating_sub(done).div_ceil(speed)),
+        }
+    }
+
+    /// Sums up the transfer of `bytes` since this measurement started.
+    pub(crate) fn summary(&self, bytes: u64, path: PathKind) -> TransferSummary {
+        TransferSummary {
+            bytes,
+            duration: self.start.elapsed(),
+            path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput() {
+        let mut throughput = Throughput::new();
+        let start = throughput.start;
+        assert_eq!(throughput.eta(0, 1000), None);
+
+        // Progress reported more often than the sample interval is not sampled.
+        assert!(!throughput.update_at(100, start + Duration::from_millis(100)));
+        assert!(throughput.update_at(1000, start + Duration::from_secs(1)));
+        assert_eq!(throughput.speed(), 1000);
+        assert_eq!(throughput.eta(1000, 10_500), Some(10));
+
+        // A sudden stall only slowly pulls the speed down.
+        assert!(throughput.update_at(1000, start + Duration::from_secs(2)));
+        let speed = throughput.speed();
+        assert!(speed > 500 && speed < 1000, "{}", speed);
+        assert_eq!(throughput.eta(2000, 1000), Some(0));
+
+        // Bytes taken back are not missed by the next sample.
+        throughput.retract(500);
+        assert!(throughput.update_at(1500, start + Duration::from_secs(3)));
+        assert!(throughput.speed() > speed, "{}", throughput.speed());
+    }
+
+    #[test]
+    fn test_average_speed() {
+        let summary = TransferSummary {
+            bytes: 10_000,
+            duration: Duration::from_secs(4),
+            path: PathKind::Direct,
+        };
+        assert_eq!(summary.average_speed(), 2500);
+        assert_eq!(TransferSummary::default().average_speed(), 0);
+    }
+}
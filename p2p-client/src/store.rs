@@ -0,0 +1,347 @@
+use super::cleanup;
+use super::config::{data_dir, gc_interval};
+use anyhow::Context;
+use iroh_blobs::{
+    api::{Store, TempTag},
+    protocol::ChunkRanges,
+    store::fs::{
+        options::{GcConfig, Options, PathOptions, ProtectCb, ProtectOutcome},
+        FsStore,
+    },
+    Hash, HashAndFormat,
+};
+use n0_future::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, Metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::UNIX_EPOCH,
+};
+use tokio::sync::Mutex;
+
+/// Prefix of the tags that keep persistent shares alive. Everything not reachable
+/// from a tag is deleted by the garbage collector.
+const SHARE_TAG_PREFIX: &str = "share/";
+
+/// Prefix of the tags of shares that end with the process.
+const SESSION_TAG_PREFIX: &str = "session/";
+
+/// The blob store shared by all nodes of this process. Opened once, because the
+/// underlying database can not be opened twice.
+static SHARED_STORE: Mutex<Option<Store>> = Mutex::const_new(None);
+
+/// How many data files of released blobs are open at a time while they are overwritten.
+const WIPE_BATCH: usize = 64;
+
+/// Returns the persistent blob store, opening it on first use.
+pub(crate) async fn shared_store() -> anyhow::Result<Store> {
+    let mut shared = SHARED_STORE.lock().await;
+    if let Some(store) = shared.as_ref() {
+        return Ok(store.clone());
+    }
+    let root = data_dir()?.join("store");
+    // Other users of the machine must not read the content kept for shares and downloads.
+    cleanup::create_private_dir(&root)
+        .with_context(|| format!("Failed to create {}", root.display()))?;
+    match cleanup::clean_up() {
+        Ok(report) if !report.removed.is_empty() => println!(
+            "Removed {} leftovers of earlier runs ({}).",
+            report.removed.len(),
+            bytesize::ByteSize(report.bytes).display().si()
+        ),
+        Ok(_) => {}
+        Err(e) => println!("Failed to clean up: {:#}", e),
+    }
+    let mut options = Options::new(&root);
+    // Content is kept in files rather than in the database, so it can be overwritten when
+    // it is deleted.
+    options.inline.max_data_inlined = 0;
+    let opened = Arc::new(OnceLock::new());
+    options.gc = gc_interval()?.map(|interval| GcConfig {
+        interval,
+        add_protected: Some(wipe_released_blobs(opened.clone(), options.path.clone())),
+    });
+    let store: Store = FsStore::load_with_opts(root.join("blobs.db"), options)
+        .await?
+        .into();
+    let _ = opened.set(store.clone());
+    // Session shares do not outlive the process, so tags left behind by a crash are stale.
+    let stale = store.tags().delete_prefix(SESSION_TAG_PREFIX).await?;
+    if stale > 0 {
+        println!("Released {} stale shares.", stale);
+    }
+    println!("Blob store opened at {}.", root.display());
+    *shared = Some(store.clone());
+    Ok(store)
+}
+
+/// Closes the persistent blob store.
+pub(crate) async fn shutdown_shared_store() {
+    let store = SHARED_STORE.lock().await.take();
+    if let Some(store) = store {
+        let _ = store.shutdown().await;
+    }
+}
+
+/// Runs before every garbage collection of the store once it is `opened`: overwrites the
+/// data files of the blobs the collection is about to delete. A crash on the way leaves
+/// the blobs in the store, so the next collection overwrites them again.
+fn wipe_released_blobs(opened: Arc<OnceLock<Store>>, paths: PathOptions) -> ProtectCb {
+    Arc::new(move |_protected| {
+        let store = opened.get().cloned();
+        let paths = paths.clone();
+        Box::pin(async move {
+            let Some(store) = store else {
+                return ProtectOutcome::Continue;
+            };
+            let wiped = tokio::spawn(async move { wipe_released(&store, &paths).await });
+            match wiped.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => println!("Failed to overwrite released blobs: {:#}", e),
+                Err(e) => println!("Failed to overwrite released blobs: {}", e),
+            }
+            ProtectOutcome::Continue
+        })
+    })
+}
+
+/// Overwrites the data files of the blobs no tag keeps, as the garbage collector finds
+/// them, a batch at a time. Files that cannot be overwritten are logged.
+async fn wipe_released(store: &Store, paths: &PathOptions) -> anyhow::Result<()> {
+    let live = live_blobs(store).await?;
+    let released: Vec<Hash> = store
+        .blobs()
+        .list()
+        .hashes()
+        .await?
+        .into_iter()
+        .filter(|hash| !live.contains(hash))
+        .collect();
+    for batch in released.chunks(WIPE_BATCH) {
+        let mut opened = Vec::new();
+        for hash in batch {
+            let path = paths.data_path(hash);
+            match File::options().write(true).open(&path) {
+                Ok(file) => opened.push((*hash, path, file)),
+                // A blob nothing was written to yet has no data file.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => println!("Failed to overwrite {}: {}", path.display(), e),
+            }
+        }
+        // A blob that a transfer started using in the meantime keeps its content. One that
+        // was imported again is in a new file, so the old one opened here is still wiped.
+        let live = live_blobs(store).await?;
+        tokio::task::spawn_blocking(move || {
+            for (hash, path, mut file) in opened {
+                if live.contains(&hash) {
+                    continue;
+                }
+                if let Err(e) = cleanup::overwrite_file(&mut file) {
+                    println!("Failed to overwrite {}: {}", path.display(), e);
+                }
+            }
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// The blobs reachable from a tag or a temp tag.
+async fn live_blobs(store: &Store) -> anyhow::Result<HashSet<Hash>> {
+    let mut roots = HashSet::new();
+    let mut tags = store.tags().list().await?;
+    while let Some(tag) = tags.next().await {
+        roots.insert(tag?.hash_and_format());
+    }
+    let mut temp_tags = store.tags().list_temp_tags().await?;
+    while let Some(temp_tag) = temp_tags.next().await {
+        roots.insert(temp_tag);
+    }
+    let mut live = HashSet::new();
+    for HashAndFormat { hash, format } in roots {
+        if live.insert(hash) && !format.is_raw() {
+            let mut children = store.export_bao(hash, ChunkRanges::all()).hashes();
+            while let Some(child) = children.next().await {
+                live.insert(child?);
+            }
+        }
+    }
+    Ok(live)
+}
+
+/// A named tag that keeps a shared collection in the store. Session tags are deleted
+/// on drop, after which the content is eligible for garbage collection unless another
+/// share uses it. Persistent tags stay until the share is removed.
+pub(crate) struct ShareTag {
+    name: String,
+    store: Store,
+    release_on_drop: bool,
+    tokio_handle: tokio::runtime::Handle,
+}
+
+impl ShareTag {
+    /// Tags the collection behind `temp_tag` until the share is dropped.
+    pub(crate) async fn session(store: &Store, temp_tag: &TempTag) -> anyhow::Result<Self> {
+        let id: [u8; 8] = rand::random();
+        let name = format!("{}{}", SESSION_TAG_PREFIX, hex::encode(id));
+        Self::set(store, name, temp_tag.hash(), true).await
+    }
+
+    /// Tags the collection of the persistent share `id` until [`delete_share_tag`] is called.
+    pub(crate) async fn persistent(store: &Store, id: &str, hash: Hash) -> anyhow::Result<Self> {
+        Self::set(store, share_tag_name(id), hash, false).await
+    }
+
+    /// Points the tag at another collection, e.g. a new version of a watched folder.
+    pub(crate) async fn retarget(&self, hash: Hash) -> anyhow::Result<()> {
+        self.store
+            .tags()
+            .set(&self.name, HashAndFormat::hash_seq(hash))
+            .await?;
+        Ok(())
+    }
+
+    async fn set(
+        store: &Store,
+        name: String,
+        hash: Hash,
+        release_on_drop: bool,
+    ) -> anyhow::Result<Self> {
+        store
+            .tags()
+            .set(&name, HashAndFormat::hash_seq(hash))
+            .await?;
+        Ok(Self {
+            name,
+            store: store.clone(),
+            release_on_drop,
+            tokio_handle: tokio::runtime::Handle::current(),
+        })
+    }
+}
+
+impl Drop for ShareTag {
+    fn drop(&mut self) {
+        if !self.release_on_drop {
+            return;
+        }
+        let name = std::mem::take(&mut self.name);
+        let store = self.store.clone();
+        self.tokio_handle.spawn(async move {
+            let _ = store.tags().delete(name).await;
+        });
+    }
+}
+
+fn share_tag_name(id: &str) -> String {
+    format!("{}{}", SHARE_TAG_PREFIX, id)
+}
+
+/// Releases the content of a removed persistent share for garbage collection.
+pub(crate) async fn delete_share_tag(store: &Store, id: &str) -> anyhow::Result<()> {
+    store.tags().delete(share_tag_name(id)).await?;
+    Ok(())
+}
+
+/// What a file looked like when it was last hashed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    modified_ns: u128,
+    hash: Hash,
+}
+
+impl CacheEntry {
+    fn matches(&self, metadata: &Metadata) -> bool {
+        self.size == metadata.len() && Some(self.modified_ns) == modified_ns(metadata)
+    }
+}
+
+fn modified_ns(metadata: &Metadata) -> Option<u128> {
+    let modified = metadata.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+/// Remembers the hash of every imported file, so sending an unchanged file again
+/// does not read and hash it a second time.
+pub(crate) struct HashCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+impl HashCache {
+    /// Loads the cache from the app data dir. A missing or corrupt cache is simply empty.
+    pub(crate) fn load() -> anyhow::Result<Self> {
+        Ok(Self::load_from(data_dir()?.join("hash-cache.json")))
+    }
+
+    fn load_from(path: PathBuf) -> Self {
+        let entries = std::fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self { path, entries }
+    }
+
+    /// Returns the cached hash if the file has not changed since it was hashed.
+    pub(crate) fn lookup(&self, path: &Path, metadata: &Metadata) -> Option<Hash> {
+        self.entries
+            .get(path)
+            .filter(|entry| entry.matches(metadata))
+            .map(|entry| entry.hash)
+    }
+
+    pub(crate) fn insert(&mut self, path: PathBuf, metadata: &Metadata, hash: Hash) {
+        if let Some(modified_ns) = modified_ns(metadata) {
+            let entry = CacheEntry {
+                size: metadata.len(),
+                modified_ns,
+                hash,
+            };
+            self.entries.insert(path, entry);
+        }
+    }
+
+    /// Writes the cache back, dropping entries for files that no longer exist.
+    pub(crate) fn save(mut self) -> anyhow::Result<()> {
+        self.entries.retain(|path, _| path.exists());
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.entries)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_cache_detects_changes() {
+        let dir = std::env::temp_dir().join(format!(
+            "p2p-client-test-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file.txt");
+        std::fs::write(&file, b"hello").unwrap();
+        let hash = Hash::new(b"hello");
+
+        let mut cache = HashCache::load_from(dir.join("cache.json"));
+        cache.insert(file.clone(), &file.metadata().unwrap(), hash);
+        cache.save().unwrap();
+
+        let cache = HashCache::load_from(dir.join("cache.json"));
+        assert_eq!(cache.lookup(&file, &file.metadata().unwrap()), Some(hash));
+        std::fs::write(&file, b"hello world").unwrap();
+        assert_eq!(cache.lookup(&file, &file.metadata().unwrap()), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+//! Sync receive: brings an existing folder up to date with incoming content instead of
+//! refusing to overwrite it. Files are compared by their BLAKE3 hash, so only new and
+//! changed files have to be downloaded.
+
+use super::cleanup;
+use super::files::{canonicalized_path_to_string, export_blob, get_export_path};
+use super::state::ReceiveStatus;
+use super::store::HashCache;
+use iroh_blobs::{api::Store, format::collection::Collection, Hash};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+/// Suffix of files that are still being written; a crash can leave them behind.
+const TEMP_SUFFIX: &str = ".p2p-client-sync";
+
+/// How a sync receive treats the target folder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncOptions {
+    /// Deletes local files that are no longer part of the incoming content.
+    pub delete: bool,
+    /// Only reports the differences and changes nothing.
+    pub dry_run: bool,
+}
+
+/// The differences between incoming content and the target folder, by file name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncPlan {
+    /// Files that do not exist locally yet.
+    pub added: Vec<String>,
+    /// Files whose local content differs.
+    pub changed: Vec<String>,
+    /// Local files the incoming content does not have. Only deleted with [`SyncOptions::delete`].
+    pub removed: Vec<String>,
+    pub unchanged: u64,
+    /// Bytes of the added and changed files.
+    pub transfer_size: u64,
+}
+
+impl SyncPlan {
+    /// Compares the incoming `(name, hash, size)` entries with the hashes of the local files.
+    pub(crate) fn new(remote: &[(String, Hash, u64)], local: &BTreeMap<String, Hash>) -> Self {
+        let mut plan = Self::default();
+        for (name, hash, size) in remote {
+            match local.get(name) {
+                Some(local_hash) if local_hash == hash => plan.unchanged += 1,
+                Some(_) => {
+                    plan.changed.push(name.clone());
+                    plan.transfer_size += size;
+                }
+                None => {
+                    plan.added.push(name.clone());
+                    plan.transfer_size += size;
+                }
+            }
+        }
+        let remote_names: BTreeSet<&str> =
+            remote.iter().map(|(name, _, _)| name.as_str()).collect();
+        plan.removed = local
+            .keys()
+            .filter(|name| !remote_names.contains(name.as_str()))
+            .cloned()
+            .collect();
+        plan
+    }
+
+    /// Returns true if the folder is already up to date, ignoring files that are only kept locally.
+    pub fn is_up_to_date(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty()
+    }
+
+    /// Names of the files that have to be written.
+    pub(crate) fn to_write(&self) -> impl Iterator<Item = &String> {
+        self.added.iter().chain(&self.changed)
+    }
+}
+
+/// Hashes the local files the incoming `names` are compared with: the files with the same
+/// names, plus every file in the top-level folders the incoming content consists of.
+/// Files outside those folders are never touched.
+pub(crate) async fn local_hashes(
+    root: &Path,
+    names: Vec<String>,
+) -> anyhow::Result<BTreeMap<String, Hash>> {
+    let root = root.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut candidates = BTreeMap::new();
+        let mut folders = BTreeSet::new();
+        for name in &names {
+            candidates.insert(name.clone(), get_export_path(&root, name)?);
+            if let Some((folder, _)) = name.split_once('/') {
+                folders.insert(folder.to_string());
+            }
+        }
+        for folder in folders {
+            for entry in WalkDir::new(root.join(folder))
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|e| e.file_type().is_file())
+                .filter(|e| !e.file_name().to_string_lossy().ends_with(TEMP_SUFFIX))
+            {
+                let path = entry.into_path();
+                let name = canonicalized_path_to_string(path.strip_prefix(&root)?, true)?;
+                candidates.insert(name, path);
+            }
+        }
+
+        let mut cache = HashCache::load()?;
+        let mut hashes = BTreeMap::new();
+        for (name, path) in candidates {
+            let Ok(metadata) = path.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let hash = match cache.lookup(&path, &metadata) {
+                Some(hash) => hash,
+                None => {
+                    let hash = hash_file(&path)?;
+                    cache.insert(path, &metadata, hash);
+                    hash
+                }
+            };
+            hashes.insert(name, hash);
+        }
+        if let Err(e) = cache.save() {
+            println!("Failed to save hash cache: {}", e);
+        }
+        Ok(hashes)
+    })
+    .await?
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Writes the added and changed files of `plan` into `root`, replacing local versions,
+/// and deletes the removed ones if requested.
+pub(crate) async fn apply(
+    db: &Store,
+    collection: &Collection,
+    root: &Path,
+    plan: &SyncPlan,
+    options: SyncOptions,
+    progress: mpsc::Sender<ReceiveStatus>,
+) -> anyhow::Result<()> {
+    let hashes: BTreeMap<&str, Hash> = collection
+        .iter()
+        .map(|(name, hash)| (name.as_str(), *hash))
+        .collect();
+    let total_files = plan.to_write().count() as u64;
+    for (i, name) in plan.to_write().enumerate() {
+        progress
+            .send(ReceiveStatus::Exporting {
+                total_files,
+                done_files: i as u64,
+            })
+            .await?;
+        let hash = hashes
+            .get(name.as_str())
+            .ok_or_else(|| anyhow::anyhow!("{} is not part of the collection", name))?;
+        let target = get_export_path(root, name)?;
+        replace_with_blob(db, *hash, &target).await?;
+    }
+    if options.delete {
+        for name in &plan.removed {
+            let target = get_export_path(root, name)?;
+            tokio::fs::remove_file(&target).await?;
+            remove_empty_parents(root, &target).await;
+        }
+    }
+    Ok(())
+}
+
+/// Exports a blob next to `target` first and then moves it into place, so a failed
+/// transfer never leaves a half-written file behind.
+async fn replace_with_blob(db: &Store, hash: Hash, target: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp = temp_path(target);
+    let _registration = cleanup::register_temp(&tmp)?;
+    if let Err(e) = export_blob(db, hash, tmp.clone()).await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&tmp, target).await?;
+    Ok(())
+}
+
+/// Path of the temporary file a new version of `target` is written to.
+pub(crate) fn temp_path(target: &Path) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    target.with_file_name(format!(".{}{}", name, TEMP_SUFFIX))
+}
+
+async fn remove_empty_parents(root: &Path, file: &Path) {
+    let mut dir = file.parent();
+    while let Some(current) = dir {
+        if current == root || tokio::fs::remove_dir(current).await.is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_plan_diff() {
+        let remote = vec![
+            ("build/app".to_string(), Hash::new(b"app v2"), 6),
+            ("build/lib".to_string(), Hash::new(b"lib"), 3),
+            ("build/new".to_string(), Hash::new(b"new"), 3),
+        ];
+        let local = BTreeMap::from([
+            ("build/app".to_string(), Hash::new(b"app v1")),
+            ("build/lib".to_string(), Hash::new(b"lib")),
+            ("build/old".to_string(), Hash::new(b"old")),
+        ]);
+        let plan = SyncPlan::new(&remote, &local);
+        assert_eq!(plan.added, vec!["build/new"]);
+        assert_eq!(plan.changed, vec!["build/app"]);
+        assert_eq!(plan.removed, vec!["build/old"]);
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(plan.transfer_size, 9);
+        assert!(!plan.is_up_to_date());
+    }
+}
@@ -0,0 +1,434 @@
+//! The sends a node serves: refuses blobs that are not part of one, applies their upload
+//! limits, reports how each receiver is doing and records every finished upload in the
+//! history, based on the events of the blobs protocol.
+
+use super::diagnostics::{ActivePeer, ActivePeers};
+use super::history::{self, FetchFlag, HistoryEntry, HistoryKind, TransferOutcome};
+use super::limit::{throttle_upload, RateLimiter};
+use super::state::SendStatus;
+use super::stats::{PathKind, Throughput};
+use iroh::{Endpoint, EndpointId};
+use iroh_blobs::{
+    api::Store,
+    format::collection::Collection,
+    hashseq::HashSeq,
+    provider::events::{
+        AbortReason, ConnectMode, EventMask, EventSender, ObserveMode, ProviderMessage,
+        RequestMode, ThrottleMode,
+    },
+    Hash,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::mpsc;
+
+/// The sends a node serves.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Uploads {
+    /// Every registered send, by the id of its registration. Sends of the same content
+    /// share a collection, so each keeps an entry of its own.
+    sends: Arc<Mutex<BTreeMap<u64, Served>>>,
+    next_id: Arc<AtomicU64>,
+    /// The upload of each connection whose receiver asked for a send.
+    by_connection: Arc<Mutex<HashMap<u64, Arc<Mutex<Upload>>>>>,
+    /// The blobs each shared collection lists, loaded when first needed.
+    contents: Arc<Mutex<HashMap<Hash, Arc<HashSet<Hash>>>>>,
+}
+
+/// What the node needs to know about a send while serving it.
+#[derive(Debug, Clone)]
+struct Served {
+    /// The collection the send shares.
+    hash: Hash,
+    /// The file or folder the send shares.
+    path: PathBuf,
+    limit: RateLimiter,
+    progress: mpsc::Sender<SendStatus>,
+    fetched: FetchFlag,
+}
+
+/// One receiver fetching a send over one connection.
+#[derive(Debug)]
+struct Upload {
+    peer: Option<EndpointId>,
+    /// The collection the receiver fetches.
+    hash: Hash,
+    path: PathBuf,
+    progress: mpsc::Sender<SendStatus>,
+    throughput: Throughput,
+    sent: u64,
+    /// Shows the receiver in the diagnostics while it is connected.
+    _active: Option<ActivePeer>,
+}
+
+impl Upload {
+    fn path(&self, endpoint: &Endpoint) -> PathKind {
+        self.peer
+            .map(|peer| PathKind::of(endpoint, peer))
+            .unwrap_or_default()
+    }
+
+    /// Counts `bytes` more as sent. Progress is reported at most once per sample, and
+    /// dropped rather than waited for if nobody keeps up with it.
+    fn add(&mut self, bytes: u64, endpoint: &Endpoint) {
+        self.sent += bytes;
+        if self.throughput.update(self.sent) {
+            let status = SendStatus::Uploading {
+                peer: self.peer,
+                sent: self.sent,
+                speed: self.throughput.speed(),
+                path: self.path(endpoint),
+            };
+            self.progress.try_send(status).ok();
+        }
+    }
+
+    /// Reports the finished upload and records it in the history.
+    fn finish(&self, endpoint: &Endpoint, store: &Store) {
+        let summary = self.throughput.summary(self.sent, self.path(endpoint));
+        let status = SendStatus::Uploaded {
+            peer: self.peer,
+            summary,
+        };
+        self.progress.try_send(status).ok();
+
+        let mut entry = HistoryEntry::new(
+            HistoryKind::Send,
+            self.path.clone(),
+            TransferOutcome::Completed(summary),
+        );
+        entry.peer = self.peer;
+        entry.hash = Some(self.hash);
+        let (hash, store) = (self.hash, store.clone());
+        tokio::spawn(async move {
+            if let Ok(collection) = Collection::load(hash, &store).await {
+                entry.files = history::collection_files(&store, &collection).await;
+            }
+            history::record(&entry);
+        });
+    }
+}
+
+impl Uploads {
+    /// Serves `hash`, the shared `path`, with the upload limit `limit` and reports its
+    /// uploads to `progress` until the registration is dropped. `fetched` is set once a
+    /// receiver asks for the send.
+    pub(crate) fn register(
+        &self,
+        hash: Hash,
+        path: PathBuf,
+        limit: RateLimiter,
+        progress: mpsc::Sender<SendStatus>,
+        fetched: FetchFlag,
+    ) -> UploadRegistration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let served = Served {
+            hash,
+            path,
+            limit,
+            progress,
+            fetched,
+        };
+        self.sends.lock().unwrap().insert(id, served);
+        UploadRegistration {
+            uploads: self.clone(),
+            id,
+        }
+    }
+
+    /// The first send registered for `collection` that is still running. Uploads of
+    /// content several sends share count towards it.
+    fn served(&self, collection: Hash) -> Option<Served> {
+        let sends = self.sends.lock().unwrap();
+        sends
+            .values()
+            .find(|served| served.hash == collection)
+            .cloned()
+    }
+
+    /// Marks every send of `collection` as fetched.
+    fn mark_fetched(&self, collection: Hash) {
+        let sends = self.sends.lock().unwrap();
+        for served in sends.values().filter(|served| served.hash == collection) {
+            served.fetched.set();
+        }
+    }
+
+    /// Removes the registration `id`, and what is cached about its collection unless
+    /// another send shares it as well.
+    fn unregister(&self, id: u64) {
+        let mut sends = self.sends.lock().unwrap();
+        let Some(served) = sends.remove(&id) else {
+            return;
+        };
+        if !sends.values().any(|other| other.hash == served.hash) {
+            self.contents.lock().unwrap().remove(&served.hash);
+        }
+    }
+
+    /// Returns the collection of the send that shares `hash`: `hash` itself if it is the
+    /// collection of a send, or the collection that lists it, as found in `store`.
+    async fn collection_of(&self, store: &Store, hash: Hash) -> Option<Hash> {
+        let mut collections: Vec<Hash> = {
+            let sends = self.sends.lock().unwrap();
+            sends.values().map(|served| served.hash).collect()
+        };
+        collections.sort();
+        collections.dedup();
+        if collections.contains(&hash) {
+            return Some(hash);
+        }
+        for collection in collections {
+            if self.contents(store, collection).await.contains(&hash) {
+                return Some(collection);
+            }
+        }
+        None
+    }
+
+    /// True if the send of `collection` shares `hash`.
+    pub(crate) async fn serves(&self, store: &Store, collection: Hash, hash: Hash) -> bool {
+        let registered = self.served(collection).is_some();
+        registered && (hash == collection || self.contents(store, collection).await.contains(&hash))
+    }
+
+    /// The blobs the shared `collection` lists, which are in `store` while it is shared.
+    async fn contents(&self, store: &Store, collection: Hash) -> Arc<HashSet<Hash>> {
+        if let Some(contents) = self.contents.lock().unwrap().get(&collection) {
+            return contents.clone();
+        }
+        let hash_seq = match store.blobs().get_bytes(collection).await {
+            Ok(bytes) => HashSeq::try_from(bytes).ok(),
+            Err(_) => None,
+        };
+        let Some(hash_seq) = hash_seq else {
+            return Default::default();
+        };
+        let contents = Arc::new(hash_seq.iter().collect::<HashSet<_>>());
+        self.contents
+            .lock()
+            .unwrap()
+            .insert(collection, contents.clone());
+        contents
+    }
+
+    /// Returns the event handler of the blobs protocol served by `endpoint`. It refuses
+    /// requests for blobs that no send shares, so the rest of `store` stays private. It
+    /// holds back every chunk until the global limit and the limit of its send allow it,
+    /// and counts the chunk towards the upload of its receiver. Finished uploads are
+    /// recorded with the files they shared, as found in `store`. Receivers are tracked as
+    /// `active_peers` while they fetch a send.
+    pub(crate) fn event_sender(
+        &self,
+        endpoint: Endpoint,
+        store: Store,
+        active_peers: ActivePeers,
+    ) -> EventSender {
+        let mask = EventMask {
+            connected: ConnectMode::Notify,
+            get: RequestMode::Intercept,
+            // Receivers fetch one collection or file at a time.
+            get_many: RequestMode::Disabled,
+            observe: ObserveMode::Intercept,
+            throttle: ThrottleMode::Intercept,
+            ..EventMask::DEFAULT
+        };
+        let (tx, mut rx) = EventSender::channel(32, mask);
+        let this = self.clone();
+        let uploads = self.by_connection.clone();
+        tokio::spawn(async move {
+            // The receiver of each connection.
+            let mut peers = HashMap::new();
+            // The limit of the send each request asks for, by connection and request id.
+            let mut requests = HashMap::new();
+            while let Some(msg) = rx.recv().await {
+                match msg {
+                    ProviderMessage::ClientConnectedNotify(msg) => {
+                        peers.insert(msg.inner.connection_id, msg.inner.endpoint_id);
+                    }
+                    ProviderMessage::GetRequestReceived(msg) => {
+                        let connection_id = msg.inner.connection_id;
+                        let hash = msg.inner.request.hash;
+                        let Some(collection) = this.collection_of(&store, hash).await else {
+                            msg.tx.send(Err(AbortReason::Permission)).await.ok();
+                            continue;
+                        };
+                        this.mark_fetched(collection);
+                        // Receivers ask for the files of a collection one by one as well,
+                        // which count towards the send of the collection.
+                        let served = this.served(collection);
+                        if let Some(served) = served {
+                            let mut uploads = uploads.lock().unwrap();
+                            uploads.entry(connection_id).or_insert_with(|| {
+                                let peer = peers.get(&connection_id).copied().flatten();
+                                Arc::new(Mutex::new(Upload {
+                                    peer,
+                                    hash: collection,
+                                    path: served.path,
+                                    progress: served.progress,
+                                    throughput: Throughput::new(),
+                                    sent: 0,
+                                    _active: peer.map(|peer| active_peers.track(peer)),
+                                }))
+                            });
+                            requests.insert((connection_id, msg.inner.request_id), served.limit);
+                        }
+                        msg.tx.send(Ok(())).await.ok();
+                    }
+                    ProviderMessage::ObserveRequestReceived(msg) => {
+                        let hash = msg.inner.request.hash;
+                        let result = match this.collection_of(&store, hash).await {
+                            Some(_) => Ok(()),
+                            None => Err(AbortReason::Permission),
+                        };
+                        msg.tx.send(result).await.ok();
+                    }
+                    ProviderMessage::ConnectionClosed(msg) => {
+                        let connection_id = msg.inner.connection_id;
+                        requests.retain(|(id, _), _| *id != connection_id);
+                        peers.remove(&connection_id);
+                        let upload = uploads.lock().unwrap().remove(&connection_id);
+                        if let Some(upload) = upload {
+                            upload.lock().unwrap().finish(&endpoint, &store);
+                        }
+                    }
+                    ProviderMessage::Throttle(msg) => {
+                        let connection_id = msg.inner.connection_id;
+                        let limit = requests
+                            .get(&(connection_id, msg.inner.request_id))
+                            .cloned()
+                            .unwrap_or_default();
+                        let upload = uploads.lock().unwrap().get(&connection_id).cloned();
+                        let endpoint = endpoint.clone();
+                        tokio::spawn(async move {
+                            throttle_upload(&limit, msg.inner.size).await;
+                            msg.tx.send(Ok(())).await.ok();
+                            if let Some(upload) = upload {
+                                upload.lock().unwrap().add(msg.inner.size, &endpoint);
+                            }
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        });
+        tx
+    }
+
+    /// The upload of the files `peer` fetches compressed over a second connection, see
+    /// [`crate::compress`]. Counts towards the upload of the send `hash` the peer is
+    /// fetching over the blobs protocol, and is held to its limit.
+    pub(crate) fn compressed(&self, peer: EndpointId, hash: Hash) -> CompressedUpload {
+        let limit = self
+            .served(hash)
+            .map(|served| served.limit)
+            .unwrap_or_default();
+        let upload = self
+            .by_connection
+            .lock()
+            .unwrap()
+            .values()
+            .find(|upload| {
+                let upload = upload.lock().unwrap();
+                upload.peer == Some(peer) && upload.hash == hash
+            })
+            .cloned();
+        CompressedUpload { limit, upload }
+    }
+}
+
+/// Files a receiver fetches compressed, see [`Uploads::compressed`].
+#[derive(Debug)]
+pub(crate) struct CompressedUpload {
+    limit: RateLimiter,
+    upload: Option<Arc<Mutex<Upload>>>,
+}
+
+impl CompressedUpload {
+    /// Waits until the limits allow sending `bytes` more, and counts them as sent.
+    pub(crate) async fn send(&self, bytes: u64, endpoint: &Endpoint) {
+        throttle_upload(&self.limit, bytes).await;
+        if let Some(upload) = &self.upload {
+            upload.lock().unwrap().add(bytes, endpoint);
+        }
+    }
+}
+
+/// Keeps a send registered with the node.
+#[derive(Debug)]
+pub(crate) struct UploadRegistration {
+    uploads: Uploads,
+    id: u64,
+}
+
+impl UploadRegistration {
+    /// Moves the registration to a new version of the shared content.
+    pub(crate) fn retarget(&self, hash: Hash) {
+        let mut sends = self.uploads.sends.lock().unwrap();
+        let Some(served) = sends.get_mut(&self.id) else {
+            return;
+        };
+        let previous = std::mem::replace(&mut served.hash, hash);
+        if !sends.values().any(|other| other.hash == previous) {
+            self.uploads.contents.lock().unwrap().remove(&previous);
+        }
+    }
+}
+
+impl Drop for UploadRegistration {
+    fn drop(&mut self) {
+        self.uploads.unregister(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_blobs::store::mem::MemStore;
+
+    #[tokio::test]
+    async fn test_sends_of_the_same_content_are_kept_apart() {
+        let store = MemStore::new();
+        let uploads = Uploads::default();
+        let hash = Hash::new(b"collection");
+        let (progress, _rx) = mpsc::channel(1);
+        let (first_fetched, second_fetched) = (FetchFlag::default(), FetchFlag::default());
+        let first = uploads.register(
+            hash,
+            PathBuf::from("first"),
+            RateLimiter::default(),
+            progress.clone(),
+            first_fetched.clone(),
+        );
+        let second = uploads.register(
+            hash,
+            PathBuf::from("second"),
+            RateLimiter::default(),
+            progress,
+            second_fetched.clone(),
+        );
+        assert_eq!(uploads.served(hash).unwrap().path, PathBuf::from("first"));
+        // A receiver of the content has fetched both sends.
+        uploads.mark_fetched(hash);
+        assert!(first_fetched.is_set() && second_fetched.is_set());
+
+        drop(first);
+        assert!(uploads.serves(&store, hash, hash).await);
+        assert_eq!(uploads.served(hash).unwrap().path, PathBuf::from("second"));
+
+        second.retarget(Hash::new(b"next"));
+        assert!(!uploads.serves(&store, hash, hash).await);
+        drop(second);
+        assert!(
+            !uploads
+                .serves(&store, Hash::new(b"next"), Hash::new(b"next"))
+                .await
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Watch-folder mode: re-shares a directory whenever its content changes.
+
+use super::files::import;
+use super::node::Node;
+use super::pointer::Pointer;
+use super::shares::{self, ShareInfo};
+use super::state::SendStatus;
+use super::store::ShareTag;
+use super::uploads::UploadRegistration;
+use iroh_blobs::Hash;
+use n0_future::task::AbortOnDropHandle;
+use notify::{RecursiveMode, Watcher};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// How long the folder has to stay quiet before a change is published, so that a
+/// build writing many files results in one update.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Watches `path` and publishes a new collection under `share_tag` and `pointer` after
+/// every change, moving the registration of the send along. Each update is reported as
+/// [`SendStatus::Updated`] and recorded in `share`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn(
+    node: Node,
+    path: PathBuf,
+    hash: Hash,
+    share_tag: Arc<ShareTag>,
+    pointer: Arc<Pointer>,
+    upload_registration: Arc<UploadRegistration>,
+    mut share: Option<ShareInfo>,
+    progress: mpsc::Sender<SendStatus>,
+) -> anyhow::Result<AbortOnDropHandle<()>> {
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|e| !e.kind.is_access()) {
+            let _ = events_tx.send(());
+        }
+    })?;
+    watcher.watch(&path, RecursiveMode::Recursive)?;
+    println!("Watching {} for changes.", path.display());
+
+    let task = tokio::spawn(async move {
+        let _watcher = watcher;
+        let mut current = hash;
+        // The folder may have changed while a restored share was offline.
+        let mut changed = share.is_some();
+        loop {
+            if !changed && events_rx.recv().await.is_none() {
+                break;
+            }
+            changed = false;
+            while let Ok(Some(())) = tokio::time::timeout(SETTLE_TIME, events_rx.recv()).await {}
+            match publish(&node, &path, current, &share_tag).await {
+                Ok(Some(hash)) => {
+                    current = hash;
+                    upload_registration.retarget(hash);
+                    pointer.update(hash);
+                    let ticket = pointer.ticket(node.endpoint().addr()).to_string();
+                    println!("Share of {} updated: {}", path.display(), ticket);
+                    if let Some(share) = share.as_mut() {
+                        share.hash = hash;
+                        share.ticket = Some(ticket.clone());
+                        if let Err(e) = shares::save(share) {
+                            println!("Failed to record the updated share: {}", e);
+                        }
+                    }
+                    // Nobody may be listening anymore; the share keeps updating regardless.
+                    progress.send(SendStatus::Updated { ticket }).await.ok();
+                }
+                Ok(None) => {}
+                Err(e) => println!("Failed to update share of {}: {:#}", path.display(), e),
+            }
+        }
+    });
+    Ok(AbortOnDropHandle::new(task))
+}
+
+/// Imports the folder again. Only changed files are hashed, thanks to the hash cache.
+/// Returns the hash of the new collection, or `None` if the content is still the same.
+async fn publish(
+    node: &Node,
+    path: &Path,
+    current: Hash,
+    share_tag: &ShareTag,
+) -> anyhow::Result<Option<Hash>> {
+    let (import_tx, mut import_rx) = mpsc::channel(16);
+    tokio::spawn(async move { while import_rx.recv().await.is_some() {} });
+    let (temp_tag, _size, _collection) = import(path, node.store(), import_tx).await?;
+    if temp_tag.hash() == current {
+        return Ok(None);
+    }
+    // The share tag protects the new collection from here on.
+    share_tag.retarget(temp_tag.hash()).await?;
+    Ok(Some(temp_tag.hash()))
+}
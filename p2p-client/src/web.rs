@@ -1,56 +1,211 @@
-use super::files::import;
-use super::state::{SendHandle, SendStatus};
-use anyhow::bail;
+use super::cleanup;
+use super::files::{get_export_path, import};
+use super::gallery::Gallery;
+use super::history::{self, FetchFlag, HistoryEntry, HistoryFile, HistoryKind, TransferOutcome};
+use super::limit::{self, RateLimiter};
+use super::mime;
+use super::node::Node;
+use super::sealed::{self, Opener, SealKey, SealedMeta};
+use super::shares::ShareInfo;
+use super::state::{ReceiveStatus, SendHandle, SendStatus};
+use super::stats::{PathKind, Throughput, TransferSummary};
+use super::store::ShareTag;
+use super::sync::{self, SyncOptions, SyncPlan};
+use anyhow::{bail, Context};
 use axum::{
     body::Body,
-    extract::{Path as AxumPath, State},
-    http::{header, StatusCode},
+    extract::{Path as AxumPath, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
     response::IntoResponse,
     routing::get,
     Router,
 };
 
-use iroh_blobs::{api::Store, Hash};
+use iroh_blobs::{
+    api::{proto::BlobStatus, Store, TempTag},
+    format::collection::Collection,
+    Hash,
+};
+use n0_future::StreamExt;
 use ngrok::config::ForwarderBuilder;
-use ngrok;
-use ngrok::tunnel::EndpointInfo;
-use std::{path::PathBuf, sync::Arc};
-use tokio::{runtime::Handle as TokioHandle, sync::mpsc};
+use ngrok::forwarder::Forwarder;
+use ngrok::tunnel::{EndpointInfo, HttpTunnel};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    runtime::Handle as TokioHandle,
+    sync::mpsc,
+};
 use tokio_util::io::ReaderStream;
 use url::Url;
 
 /// Public entry point for starting an HTTP (web link) send operation.
+/// With `encrypted`, the file is end-to-end encrypted, see [`serve`].
 pub(crate) async fn start_http_send_internal(
+    node: Node,
     path: PathBuf,
+    encrypted: bool,
     progress_sender: mpsc::Sender<SendStatus>,
     tokio_handle: TokioHandle,
 ) -> anyhow::Result<SendHandle> {
     progress_sender.send(SendStatus::Connecting).await?;
 
-    let suffix: [u8; 8] = rand::random();
-    let data_dir = std::env::temp_dir().join(format!("p2p-client-http-{}", hex::encode(suffix)));
-    tokio::fs::create_dir_all(&data_dir).await?;
-    let db = iroh_blobs::store::fs::FsStore::load(&data_dir).await?;
-    let (_temp_tag, _size, collection) = import(&path, &db, progress_sender.clone()).await?;
+    let (temp_tag, _size, collection) =
+        import(&path, node.store(), progress_sender.clone()).await?;
+    let share_tag = ShareTag::session(node.store(), &temp_tag).await?;
+
+    serve(
+        node,
+        temp_tag,
+        share_tag,
+        collection,
+        path,
+        None,
+        encrypted,
+        progress_sender,
+        tokio_handle,
+    )
+    .await
+}
+
+/// Serves a collection from the node's store through a fresh ngrok tunnel. A single file
+/// is linked directly; a folder is linked as a gallery page, see [`gallery`].
+/// With `encrypted`, only the sealed file and a page that decrypts it are served, under a
+/// random id instead of the hash. The key is put in the fragment of the link, which
+/// browsers keep to themselves, so the tunnel never sees it.
+/// Downloads are recorded in the history as sends of `path`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn serve(
+    node: Node,
+    temp_tag: TempTag,
+    share_tag: ShareTag,
+    collection: Collection,
+    path: PathBuf,
+    mut share: Option<ShareInfo>,
+    encrypted: bool,
+    progress_sender: mpsc::Sender<SendStatus>,
+    tokio_handle: TokioHandle,
+) -> anyhow::Result<SendHandle> {
+    let (file_name, download_hash) = match collection.iter().next() {
+        Some((name, hash)) => (name.clone(), *hash),
+        None => bail!("Collection is empty"),
+    };
+    let gallery = if collection.len() > 1 {
+        anyhow::ensure!(
+            !encrypted,
+            "Encrypted web links hold a single file. Please select a single file."
+        );
+        Some(Gallery::load(node.store(), temp_tag.hash(), &collection).await?)
+    } else {
+        None
+    };
 
-    let (download_hash, file_name) = if collection.len() == 1 {
-        let (name, hash) = collection.iter().next().ok_or_else(|| anyhow::anyhow!("Collection is empty"))?;
-        (*hash, name.clone())
+    let sealed = if encrypted {
+        let size = match node.store().blobs().status(download_hash).await? {
+            BlobStatus::Complete { size } => size,
+            _ => bail!("The file is no longer in the store"),
+        };
+        let key = SealKey::generate();
+        let meta = SealedMeta {
+            name: file_name.clone(),
+            size,
+            hash: download_hash,
+        };
+        Some(SealedFile {
+            id: hex::encode(rand::random::<[u8; 16]>()),
+            meta: Arc::new(key.seal_meta(&meta)),
+            key,
+            hash: download_hash,
+            size,
+        })
     } else {
-        bail!("Sending directories via web link is not yet supported. Please select a single file.");
+        None
     };
 
+    let upload_limit = RateLimiter::default();
+    let web_history = WebHistory::new(path.clone());
     let app_state = AppState {
-        db: Arc::new(db.into()),
-        file_name,
+        db: Arc::new(node.store().clone()),
+        files: Arc::new(
+            collection
+                .iter()
+                .map(|(name, hash)| (*hash, name.clone()))
+                .collect(),
+        ),
+        gallery: gallery.clone(),
+        upload_limit: upload_limit.clone(),
+        sealed: sealed.clone(),
+        history: web_history.clone(),
     };
 
-    let app = Router::new()
-        .route("/download/{hash}", get(download_handler))
-        .with_state(app_state);
+    let app = if sealed.is_some() {
+        Router::new()
+            .route("/s/{id}", get(sealed_page_handler))
+            .route("/s/{id}/meta", get(sealed_meta_handler))
+            .route("/s/{id}/data", get(sealed_data_handler))
+    } else {
+        Router::new()
+            .route("/download/{hash}", get(download_handler))
+            .route("/g/{id}", get(gallery_page_handler))
+            .route("/g/{id}/files", get(gallery_files_handler))
+            .route("/g/{id}/thumb/{hash}", get(thumbnail_handler))
+    }
+    .with_state(app_state);
 
     progress_sender.send(SendStatus::Connecting).await?;
 
+    let (shutdown_tx, tun) = open_tunnel(app).await?;
+
+    let url = match (&sealed, &gallery) {
+        (Some(sealed), _) => format!("{}/s/{}#{}", tun.url(), sealed.id, sealed.key.to_fragment()),
+        (None, Some(gallery)) => format!("{}/g/{}", tun.url(), gallery.id),
+        (None, None) => format!("{}/download/{}", tun.url(), download_hash),
+    };
+    // The key is not logged.
+    println!(
+        "ngrok tunnel started at: {}",
+        url.split('#').next().unwrap_or_default()
+    );
+
+    if let Some(share) = share.as_mut() {
+        if share.ticket.as_ref().is_some_and(|old| old != &url) {
+            println!("The web link of share \"{}\" has changed.", share.name);
+            share.link_changed = true;
+        }
+        share.ticket = Some(url.clone());
+    }
+    progress_sender
+        .send(SendStatus::ReadyToSend { ticket: url })
+        .await?;
+
+    Ok(SendHandle {
+        shutdown_tx: Some(shutdown_tx),
+        _ngrok_tunnel: Some(tun),
+        _temp_tag: temp_tag,
+        _share_tag: Arc::new(share_tag),
+        _watcher: None,
+        _pointer: None,
+        upload_limit,
+        _upload_registration: None,
+        _node: node,
+        share,
+        path,
+        fetched: web_history.fetched,
+        tokio_handle,
+    })
+}
+
+/// Serves `app` on a local port and makes it public through a fresh ngrok tunnel.
+/// The server shuts down when the returned sender is used or dropped.
+pub(crate) async fn open_tunnel(
+    app: Router,
+) -> anyhow::Result<(tokio::sync::oneshot::Sender<()>, Forwarder<HttpTunnel>)> {
     let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
     let local_addr = listener.local_addr()?;
 
@@ -84,55 +239,631 @@ pub(crate) async fn start_http_send_internal(
         .connect()
         .await?
         .http_endpoint()
-        .listen_and_forward(to_url) 
+        .listen_and_forward(to_url)
         .await?;
 
-    let url = format!("{}/download/{}", tun.url(), download_hash);
-    println!("ngrok tunnel started at: {}", url);
-
-    progress_sender
-        .send(SendStatus::ReadyToSend { ticket: url })
-        .await?;
-
-    Ok(SendHandle {
-        data_dir,
-        shutdown_tx: Some(shutdown_tx),
-        _ngrok_tunnel: Some(tun),
-        tokio_handle,
-    })
+    Ok((shutdown_tx, tun))
 }
 
 /// State for the Axum web server.
 #[derive(Clone)]
 struct AppState {
     db: Arc<Store>,
-    file_name: String,
+    /// The names of the files that can be downloaded, by hash.
+    files: Arc<HashMap<Hash, String>>,
+    /// Set if a folder is served.
+    gallery: Option<Gallery>,
+    upload_limit: RateLimiter,
+    sealed: Option<SealedFile>,
+    history: WebHistory,
+}
+
+/// Records the browser downloads of a web link or bridge in the history.
+#[derive(Debug, Clone)]
+pub(crate) struct WebHistory {
+    /// The file or folder that is shared.
+    path: PathBuf,
+    pub(crate) fetched: FetchFlag,
+}
+
+impl WebHistory {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            fetched: FetchFlag::default(),
+        }
+    }
+
+    /// Starts a download of `file`, `range` of it in `len` bytes. Every download marks the
+    /// send as fetched, but only those of whole files are recorded: players ask for many
+    /// parts of a video while they seek.
+    pub(crate) fn download(
+        &self,
+        file: HistoryFile,
+        range: ByteRange,
+        len: u64,
+    ) -> Option<WebDownload> {
+        self.fetched.set();
+        (range == ByteRange::Full).then(|| WebDownload {
+            path: self.path.clone(),
+            file,
+            len,
+            sent: 0,
+            error: None,
+            throughput: Throughput::new(),
+        })
+    }
+}
+
+/// A browser downloading a whole file. Recorded in the history when the response ends:
+/// as completed if all of it was sent, as failed otherwise.
+pub(crate) struct WebDownload {
+    path: PathBuf,
+    file: HistoryFile,
+    /// The length of the response.
+    len: u64,
+    sent: u64,
+    error: Option<String>,
+    throughput: Throughput,
+}
+
+impl WebDownload {
+    /// Counts a chunk of the response, or the error that ends it.
+    pub(crate) fn add<T: AsRef<[u8]>, E: std::fmt::Display>(&mut self, chunk: &Result<T, E>) {
+        match chunk {
+            Ok(bytes) => self.sent += bytes.as_ref().len() as u64,
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// Records the download as failed with `error`.
+    pub(crate) fn fail(&mut self, error: impl std::fmt::Display) {
+        self.error = Some(error.to_string());
+    }
+}
+
+impl Drop for WebDownload {
+    fn drop(&mut self) {
+        let outcome = match self.error.take() {
+            Some(e) => TransferOutcome::Failed(e),
+            None if self.sent < self.len => {
+                TransferOutcome::Failed("The browser stopped the download".to_string())
+            }
+            None => TransferOutcome::Completed(self.throughput.summary(self.sent, PathKind::Web)),
+        };
+        let mut entry = HistoryEntry::new(HistoryKind::Send, self.path.clone(), outcome);
+        entry.hash = Some(self.file.hash);
+        entry.files = vec![self.file.clone()];
+        history::record(&entry);
+    }
+}
+
+/// The file of an end-to-end encrypted web link.
+#[derive(Clone)]
+struct SealedFile {
+    /// Names the file in the link instead of its hash, which would tell what it is.
+    id: String,
+    key: SealKey,
+    /// The sealed [`SealedMeta`].
+    meta: Arc<Vec<u8>>,
+    hash: Hash,
+    size: u64,
+}
+
+/// Returns the sealed file if `id` names it.
+fn sealed_file(state: &AppState, id: &str) -> Option<SealedFile> {
+    state.sealed.clone().filter(|sealed| sealed.id == id)
+}
+
+/// Axum handler for the page that decrypts an encrypted file in the browser.
+async fn sealed_page_handler(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    if sealed_file(&state, &id).is_none() {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+    (
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::REFERRER_POLICY, "no-referrer"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        sealed::PAGE,
+    )
+        .into_response()
+}
+
+/// Axum handler for the sealed name and size of an encrypted file.
+async fn sealed_meta_handler(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    match sealed_file(&state, &id) {
+        Some(sealed) => (
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            sealed.meta.as_ref().clone(),
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
+
+/// Axum handler for the content of an encrypted file, sealed as it is read from the store.
+async fn sealed_data_handler(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    let Some(sealed) = sealed_file(&state, &id) else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+    let reader = state.db.reader(sealed.hash);
+    let file = HistoryFile {
+        name: state.files.get(&sealed.hash).cloned().unwrap_or_default(),
+        size: sealed.size,
+        hash: sealed.hash,
+    };
+    let len = sealed::sealed_size(sealed.size);
+    let mut download = state.history.download(file, ByteRange::Full, len);
+    let upload_limit = state.upload_limit.clone();
+    let stream = sealed::seal_stream(sealed.key, reader, sealed.size).then(move |record| {
+        if let Some(download) = download.as_mut() {
+            download.add(&record);
+        }
+        let upload_limit = upload_limit.clone();
+        async move {
+            if let Ok(bytes) = &record {
+                limit::throttle_upload(&upload_limit, bytes.len() as u64).await;
+            }
+            record
+        }
+    });
+
+    axum::response::Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, len)
+        .body(Body::from_stream(stream))
+        .unwrap()
+        .into_response()
+}
+
+/// The query of a download link.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct DownloadQuery {
+    /// "1" to show the file in the browser instead of saving it, if the browser can.
+    inline: Option<String>,
+}
+
+impl DownloadQuery {
+    pub(crate) fn inline(&self) -> bool {
+        matches!(self.inline.as_deref(), Some("1" | "true"))
+    }
 }
-/// Axum handler to process a download request.
+
+/// A `Range` request for part of a file, as far as it is answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    /// The whole file, also sent for ranges that are malformed or list several parts.
+    Full,
+    /// The bytes from `start` to `end`, both included.
+    Partial { start: u64, end: u64 },
+    /// The range starts after the end of the file.
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// The part of a file of `size` bytes that is sent: its first byte and its length.
+    pub(crate) fn span(&self, size: u64) -> (u64, u64) {
+        match *self {
+            ByteRange::Full => (0, size),
+            ByteRange::Partial { start, end } => (start, end - start + 1),
+            ByteRange::Unsatisfiable => (0, 0),
+        }
+    }
+
+    /// Answers the request for a file of `size` bytes with `headers` and `body`, which
+    /// holds the part of the file given by [`ByteRange::span`].
+    pub(crate) fn response(
+        &self,
+        size: u64,
+        headers: [(HeaderName, String); 3],
+        body: Body,
+    ) -> axum::response::Response {
+        let (status, content_range) = match *self {
+            ByteRange::Full => (StatusCode::OK, None),
+            ByteRange::Partial { start, end } => (
+                StatusCode::PARTIAL_CONTENT,
+                Some(format!("bytes {}-{}/{}", start, end, size)),
+            ),
+            ByteRange::Unsatisfiable => {
+                let content_range = format!("bytes */{}", size);
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, content_range)],
+                )
+                    .into_response();
+            }
+        };
+        let (_, len) = self.span(size);
+        let mut response = (
+            status,
+            headers,
+            [
+                (header::ACCEPT_RANGES, "bytes".to_string()),
+                (header::CONTENT_LENGTH, len.to_string()),
+            ],
+            body,
+        )
+            .into_response();
+        if let Some(value) = content_range.and_then(|value| HeaderValue::from_str(&value).ok()) {
+            response.headers_mut().insert(header::CONTENT_RANGE, value);
+        }
+        response
+    }
+}
+
+/// The part of a file of `size` bytes a request with `request_headers` asks for.
+pub(crate) fn requested_range(request_headers: &HeaderMap, size: u64) -> ByteRange {
+    byte_range(
+        request_headers
+            .get(header::RANGE)
+            .and_then(|value| value.to_str().ok()),
+        size,
+    )
+}
+
+/// Reads the `Range` header `value` of a request for a file of `size` bytes.
+fn byte_range(value: Option<&str>, size: u64) -> ByteRange {
+    let Some(spec) = value.and_then(|value| value.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = if first.is_empty() {
+        // The last bytes of the file.
+        match last.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), u64::MAX),
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Ok(start), _) if last.is_empty() => (start, u64::MAX),
+            (Ok(start), Ok(end)) if start <= end => (start, end),
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial {
+        start,
+        end: end.min(size - 1),
+    }
+}
+
+/// Axum handler to process a download request. `Range` requests are answered with the
+/// part asked for, which is how players seek in videos without downloading them first.
 async fn download_handler(
     State(state): State<AppState>,
     AxumPath(hash_str): AxumPath<String>,
+    Query(query): Query<DownloadQuery>,
+    request_headers: HeaderMap,
 ) -> impl IntoResponse {
     let hash = match hash_str.parse::<Hash>() {
         Ok(h) => h,
         Err(_) => return (StatusCode::BAD_REQUEST, "Invalid hash format").into_response(),
     };
 
-    if !state.db.has(hash).await.unwrap_or(false) {
+    let Some(file_name) = state.files.get(&hash) else {
         return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+    let size = match state.db.blobs().status(hash).await {
+        Ok(BlobStatus::Complete { size }) => size,
+        _ => return (StatusCode::NOT_FOUND, "Not found").into_response(),
+    };
+
+    // Files with an unknown extension are recognized by their first bytes.
+    let mut head = Vec::new();
+    let mut start = state.db.reader(hash).take(mime::SNIFF_LEN as u64);
+    if let Err(e) = start.read_to_end(&mut head).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
     }
+    let headers = mime::download_headers(file_name, &head, query.inline());
 
-    let reader = state.db.reader(hash);
-    let stream = ReaderStream::new(reader);
-    let body = Body::from_stream(stream);
+    let range = requested_range(&request_headers, size);
+    if range == ByteRange::Unsatisfiable {
+        return range.response(size, headers, Body::empty());
+    }
+    let (offset, len) = range.span(size);
+    let mut reader = state.db.reader(hash);
+    if let Err(e) = reader.seek(SeekFrom::Start(offset)).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    }
+    let file = HistoryFile {
+        name: file_name.clone(),
+        size,
+        hash,
+    };
+    let mut download = state.history.download(file, range, len);
+    let upload_limit = state.upload_limit.clone();
+    let stream = ReaderStream::new(reader.take(len)).then(move |chunk| {
+        if let Some(download) = download.as_mut() {
+            download.add(&chunk);
+        }
+        let upload_limit = upload_limit.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                limit::throttle_upload(&upload_limit, bytes.len() as u64).await;
+            }
+            chunk
+        }
+    });
+    range.response(size, headers, Body::from_stream(stream))
+}
 
-    let disposition = format!("attachment; filename=\"{}\"", state.file_name);
+/// Returns the gallery if `id` names it.
+fn gallery(state: &AppState, id: &str) -> Option<Gallery> {
+    state
+        .gallery
+        .clone()
+        .filter(|gallery| gallery.id.to_string() == id)
+}
 
-    axum::response::Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "application/octet-stream")
-        .header(header::CONTENT_DISPOSITION, disposition)
-        .body(body)
-        .unwrap()
-        .into_response()
-}
\ No newline at end of file
+/// Axum handler for the page of a folder.
+async fn gallery_page_handler(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    match gallery(&state, &id) {
+        Some(gallery) => gallery.page_response(),
+        None => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
+
+/// Axum handler for the list of files of a folder.
+async fn gallery_files_handler(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+) -> impl IntoResponse {
+    match gallery(&state, &id) {
+        Some(gallery) => gallery.files_response(),
+        None => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
+
+/// Axum handler for the thumbnail of an image of a folder.
+async fn thumbnail_handler(
+    State(state): State<AppState>,
+    AxumPath((id, hash_str)): AxumPath<(String, String)>,
+) -> impl IntoResponse {
+    let Some(gallery) = gallery(&state, &id) else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+    let db = state.db.clone();
+    gallery
+        .thumbnail_response(&hash_str, |hash| async move {
+            Ok(db.blobs().get_bytes(hash).await?)
+        })
+        .await
+}
+
+/// Downloads the file behind a web link of another p2p-client into `target_dir`.
+/// The link contains the BLAKE3 hash of the file, so a sync receive skips an unchanged
+/// local copy, and the download is verified before it is moved into place.
+/// Encrypted links carry the hash in their sealed metadata instead.
+pub(crate) async fn receive_web_link(
+    link: &str,
+    target_dir: &Path,
+    sync: Option<SyncOptions>,
+    download_limit: RateLimiter,
+    progress: mpsc::Sender<ReceiveStatus>,
+) -> anyhow::Result<HistoryEntry> {
+    progress.send(ReceiveStatus::Connecting).await?;
+    let mut url: Url = link.parse().context("Invalid web link")?;
+    let (hash, name, size, mut response, mut opener) = match web_link_kind(&url)? {
+        WebLink::Plain(hash) => {
+            let response = reqwest::get(url).await?.error_for_status()?;
+            let name = response
+                .headers()
+                .get(header::CONTENT_DISPOSITION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(mime::disposition_file_name)
+                .context("The web link did not name the file")?;
+            let size = response.content_length().unwrap_or_default();
+            (hash, name, size, response, None)
+        }
+        WebLink::Sealed(key) => {
+            url.set_fragment(None);
+            let base = url.as_str().trim_end_matches('/').to_string();
+            let meta = reqwest::get(format!("{}/meta", base))
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            let meta = key.open_meta(meta.to_vec())?;
+            let response = reqwest::get(format!("{}/data", base))
+                .await?
+                .error_for_status()?;
+            let opener = Opener::new(key, meta.size);
+            (meta.hash, meta.name, meta.size, response, Some(opener))
+        }
+    };
+    anyhow::ensure!(!name.contains('/'), "invalid file name: {}", name);
+    let target = get_export_path(target_dir, &name)?;
+    progress
+        .send(ReceiveStatus::Connected {
+            total_files: 1,
+            total_size: size,
+        })
+        .await?;
+
+    let file = HistoryFile {
+        name: name.clone(),
+        size,
+        hash,
+    };
+    match sync {
+        Some(options) => {
+            let local = sync::local_hashes(target_dir, vec![name.clone()]).await?;
+            let plan = SyncPlan::new(&[(name, hash, size)], &local);
+            progress.send(ReceiveStatus::SyncPlan(plan.clone())).await?;
+            if options.dry_run || plan.is_up_to_date() {
+                let summary = Throughput::new().summary(0, PathKind::Web);
+                progress.send(ReceiveStatus::Done(summary)).await?;
+                return Ok(history_entry(target_dir, file, summary));
+            }
+        }
+        None if target.exists() => bail!(
+            "target {} already exists. Please remove it and try again.",
+            target.display()
+        ),
+        None => {}
+    }
+
+    let tmp = sync::temp_path(&target);
+    let _registration = cleanup::register_temp(&tmp)?;
+    let mut throughput = Throughput::new();
+    let mut downloaded = 0;
+    let result = async {
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        let mut hasher = blake3::Hasher::new();
+        while let Some(chunk) = response.chunk().await? {
+            limit::throttle_download(&download_limit, chunk.len() as u64).await;
+            let chunk = match opener.as_mut() {
+                Some(opener) => opener.push(&chunk)?.into(),
+                None => chunk,
+            };
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+            downloaded += chunk.len() as u64;
+            throughput.update(downloaded);
+            progress
+                .send(ReceiveStatus::Downloading {
+                    downloaded,
+                    total: size,
+                    speed: throughput.speed(),
+                    eta: throughput.eta(downloaded, size),
+                    path: PathKind::Web,
+                })
+                .await?;
+        }
+        file.flush().await?;
+        if let Some(opener) = &opener {
+            opener.finish()?;
+        }
+        anyhow::ensure!(
+            Hash::from(hasher.finalize()) == hash,
+            "The downloaded file does not match the link"
+        );
+        tokio::fs::rename(&tmp, &target).await?;
+        anyhow::Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    result?;
+    let summary = throughput.summary(downloaded, PathKind::Web);
+    progress.send(ReceiveStatus::Done(summary)).await?;
+    Ok(history_entry(target_dir, file, summary))
+}
+
+/// Describes a finished download of `file` from a web link for the history.
+fn history_entry(target_dir: &Path, file: HistoryFile, summary: TransferSummary) -> HistoryEntry {
+    let mut entry = HistoryEntry::new(
+        HistoryKind::Receive,
+        target_dir.to_path_buf(),
+        TransferOutcome::Completed(summary),
+    );
+    entry.hash = Some(file.hash);
+    entry.files = vec![file];
+    entry
+}
+
+/// What a web link points to.
+enum WebLink {
+    /// A file served as is, by its hash.
+    Plain(Hash),
+    /// An end-to-end encrypted file, with the key from the fragment.
+    Sealed(SealKey),
+}
+
+fn web_link_kind(url: &Url) -> anyhow::Result<WebLink> {
+    let mut segments = url.path_segments().context("Not a p2p-client web link")?;
+    match (segments.next(), segments.next()) {
+        (Some("download"), Some(hash)) => {
+            let hash = hash.parse::<Hash>().context("Not a p2p-client web link")?;
+            Ok(WebLink::Plain(hash))
+        }
+        (Some("s"), Some(_)) => {
+            let fragment = url
+                .fragment()
+                .context("The encrypted web link has no key after the #")?;
+            Ok(WebLink::Sealed(SealKey::from_fragment(fragment)?))
+        }
+        (Some("g"), Some(_)) => bail!("Web links to folders can only be opened in a browser"),
+        _ => bail!("Not a p2p-client web link"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_web_link_kind() {
+        let hash = Hash::new(b"file");
+        let plain: Url = format!("https://example.com/download/{}", hash)
+            .parse()
+            .unwrap();
+        assert!(matches!(web_link_kind(&plain), Ok(WebLink::Plain(h)) if h == hash));
+
+        let key = SealKey::generate().to_fragment();
+        let sealed: Url = format!("https://example.com/s/abc#{}", key)
+            .parse()
+            .unwrap();
+        assert!(matches!(web_link_kind(&sealed), Ok(WebLink::Sealed(_))));
+        let keyless: Url = "https://example.com/s/abc".parse().unwrap();
+        assert!(web_link_kind(&keyless).is_err());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let range = |value: &str, size| byte_range(Some(value), size);
+        assert_eq!(byte_range(None, 100), ByteRange::Full);
+        assert_eq!(
+            range("bytes=0-9", 100),
+            ByteRange::Partial { start: 0, end: 9 }
+        );
+        assert_eq!(
+            range("bytes=90-", 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        assert_eq!(
+            range("bytes=50-1000", 100),
+            ByteRange::Partial { start: 50, end: 99 }
+        );
+        assert_eq!(
+            range("bytes=-10", 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        assert_eq!(
+            range("bytes=-1000", 100),
+            ByteRange::Partial { start: 0, end: 99 }
+        );
+        assert_eq!(range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        // Ranges that are not understood are ignored.
+        assert_eq!(range("bytes=9-0", 100), ByteRange::Full);
+        assert_eq!(range("bytes=0-1,5-6", 100), ByteRange::Full);
+        assert_eq!(range("items=0-9", 100), ByteRange::Full);
+    }
+}